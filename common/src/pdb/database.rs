@@ -4,14 +4,14 @@ use anyhow::Result;
 
 use serde::{Deserialize, Serialize};
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Pdb {
     pub symbols: Vec<Symbol>,
     pub source_lines: Vec<String>,
     pub source_map: Vec<SourceMap>,
     pub file_info: Vec<FileInfo>,
 }
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct FileInfo {
     pub name: PathBuf,
     pub file_type: FileType,
@@ -31,6 +31,16 @@ pub enum SymbolType {
     Label,
     Unknown,
 }
+/// Values of `Symbol::storage_class` for a Var: the Jack segment it lives in.
+/// hackem's assembler and VM loader write `STORAGE_STATIC`, with the RAM
+/// address in `address`. The other kinds are for a compiler that wants its
+/// fields, arguments and locals named in the segment view, with the segment
+/// index in `address`; none of the tools in this repo produce them.
+pub const STORAGE_STATIC: i64 = 0;
+pub const STORAGE_FIELD: i64 = 1;
+pub const STORAGE_ARGUMENT: i64 = 2;
+pub const STORAGE_LOCAL: i64 = 3;
+
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct Symbol {
     pub symbol_type: SymbolType,
@@ -44,7 +54,7 @@ pub struct Symbol {
     pub instance_type: String,
     pub file_type: FileType,
 }
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct SourceMap {
     pub file: usize,
     pub line_no: usize,
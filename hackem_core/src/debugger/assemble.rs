@@ -0,0 +1,410 @@
+/*
+
+Hack assembler
+
+Assembles `.asm` source in-process, the same way as the nand2tetris
+assembler: `(LABEL)` pseudo-instructions name the next ROM address,
+predefined symbols (SP, LCL, ARG, THIS, THAT, R0-R15, SCREEN, KBD) are
+built in, and any other `@symbol` is a variable allocated from RAM[16] in
+order of first use.
+
+Besides the machine code, assembly produces a `Pdb` holding the labels as
+`Label` symbols, the variables as static `Var` symbols and an asm source
+map, so breakpoints on labels, `mem` on variables and source stepping all
+work without any external toolchain.
+
+*/
+
+use std::{collections::HashMap, path::Path};
+
+use anyhow::{bail, Result};
+use common::pdb::database::{
+    FileInfo, FileType, Pdb, SourceMap, Symbol, SymbolType, STORAGE_STATIC,
+};
+
+use super::debug_em::HackSystem;
+
+/// First RAM address handed out to variables.
+const FIRST_VARIABLE: u16 = 16;
+
+const COMP: &[(&str, u16)] = &[
+    ("0", 0b0101010),
+    ("1", 0b0111111),
+    ("-1", 0b0111010),
+    ("D", 0b0001100),
+    ("A", 0b0110000),
+    ("!D", 0b0001101),
+    ("!A", 0b0110001),
+    ("-D", 0b0001111),
+    ("-A", 0b0110011),
+    ("D+1", 0b0011111),
+    ("A+1", 0b0110111),
+    ("D-1", 0b0001110),
+    ("A-1", 0b0110010),
+    ("D+A", 0b0000010),
+    ("D-A", 0b0010011),
+    ("A-D", 0b0000111),
+    ("D&A", 0b0000000),
+    ("D|A", 0b0010101),
+    ("M", 0b1110000),
+    ("!M", 0b1110001),
+    ("-M", 0b1110011),
+    ("M+1", 0b1110111),
+    ("M-1", 0b1110010),
+    ("D+M", 0b1000010),
+    ("D-M", 0b1010011),
+    ("M-D", 0b1000111),
+    ("D&M", 0b1000000),
+    ("D|M", 0b1010101),
+];
+
+const JUMP: &[&str] = &["", "JGT", "JEQ", "JGE", "JLT", "JNE", "JLE", "JMP"];
+
+/// Machine code and debug info for an assembled program.
+#[derive(Debug)]
+pub struct Assembly {
+    pub code: Vec<u16>,
+    pub pdb: Pdb,
+}
+
+/// Value of a predefined assembler symbol.
+pub fn predefined_symbol(name: &str) -> Option<u16> {
+    match name {
+        "SP" => Some(0),
+        "LCL" => Some(1),
+        "ARG" => Some(2),
+        "THIS" => Some(3),
+        "THAT" => Some(4),
+        "SCREEN" => Some(0x4000),
+        "KBD" => Some(0x6000),
+        _ => name.strip_prefix('R').and_then(|n| {
+            n.parse::<u16>()
+                .ok()
+                .filter(|&r| r < 16 && r.to_string() == n)
+        }),
+    }
+}
+
+/// Strip the comment and all whitespace from a source line.
+fn clean_line(line: &str) -> String {
+    let code = line.split("//").next().unwrap_or("");
+    code.chars().filter(|c| !c.is_whitespace()).collect()
+}
+
+fn is_symbol(name: &str) -> bool {
+    let mut chars = name.chars();
+    chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || "_.$:".contains(c))
+        && chars.all(|c| c.is_ascii_alphanumeric() || "_.$:".contains(c))
+}
+
+fn encode_comp(comp: &str) -> Option<u16> {
+    let lookup = |c: &str| COMP.iter().find(|(m, _)| *m == c).map(|(_, bits)| *bits);
+    lookup(comp).or_else(|| {
+        // accept commutative operands in either order, e.g. A+D or M&D
+        let op = comp.find(['+', '&', '|']).filter(|&i| i > 0)?;
+        let swapped = format!("{}{}{}", &comp[op + 1..], &comp[op..op + 1], &comp[..op]);
+        lookup(&swapped)
+    })
+}
+
+/// Encode one instruction. Whitespace and a trailing comment are allowed.
+/// `resolve` supplies the value of an `@symbol` that is not predefined.
+pub fn encode_instruction(text: &str, mut resolve: impl FnMut(&str) -> Result<u16>) -> Result<u16> {
+    let inst = clean_line(text);
+    if let Some(operand) = inst.strip_prefix('@') {
+        if operand.starts_with(|c: char| c.is_ascii_digit()) {
+            return match operand.parse::<u16>() {
+                Ok(value) if value < 0x8000 => Ok(value),
+                _ => bail!("constant '{}' out of range (0..32767)", operand),
+            };
+        }
+        if !is_symbol(operand) {
+            bail!("invalid symbol '{}'", operand);
+        }
+        return match predefined_symbol(operand) {
+            Some(value) => Ok(value),
+            None => resolve(operand),
+        };
+    }
+    if inst.is_empty() || inst.starts_with('(') {
+        bail!("'{}' is not an instruction", text.trim());
+    }
+
+    let (dest, rest) = match inst.split_once('=') {
+        Some((dest, rest)) => (dest, rest),
+        None => ("", inst.as_str()),
+    };
+    let (comp, jump) = match rest.split_once(';') {
+        Some((comp, jump)) => (comp, jump),
+        None => (rest, ""),
+    };
+
+    let mut dest_bits = 0u16;
+    for c in dest.chars() {
+        let bit = match c {
+            'A' => 0b100,
+            'D' => 0b010,
+            'M' => 0b001,
+            _ => bail!("invalid destination '{}'", dest),
+        };
+        if dest_bits & bit != 0 {
+            bail!("invalid destination '{}'", dest);
+        }
+        dest_bits |= bit;
+    }
+    if inst.contains('=') && dest.is_empty() {
+        bail!("missing destination before '='");
+    }
+    let Some(comp_bits) = encode_comp(comp) else {
+        bail!("invalid computation '{}'", comp);
+    };
+    let Some(jump_bits) = JUMP
+        .iter()
+        .position(|j| *j == jump)
+        .filter(|_| !(inst.contains(';') && jump.is_empty()))
+    else {
+        bail!("invalid jump '{}'", jump);
+    };
+    Ok(0xE000 | comp_bits << 6 | dest_bits << 3 | jump_bits as u16)
+}
+
+fn symbol(symbol_type: SymbolType, name: &str, address: u16) -> Symbol {
+    Symbol {
+        symbol_type,
+        name: name.to_string(),
+        func_type: 0,
+        var_type: 0,
+        storage_class: STORAGE_STATIC,
+        size: 1,
+        address,
+        instance_type: String::new(),
+        file_type: FileType::Asm,
+    }
+}
+
+/// Assemble `source`, read from `file`. Errors carry the source line number.
+pub fn assemble(source: &str, file: &Path) -> Result<Assembly> {
+    // first pass: label addresses
+    let mut labels: HashMap<String, u16> = HashMap::new();
+    let mut label_order = Vec::new();
+    let mut address = 0usize;
+    for (lineno, line) in source.lines().enumerate() {
+        let inst = clean_line(line);
+        if inst.is_empty() {
+            continue;
+        }
+        if let Some(label) = inst.strip_prefix('(') {
+            let Some(label) = label.strip_suffix(')').filter(|l| is_symbol(l)) else {
+                bail!("line {}: invalid label '{}'", lineno + 1, line.trim());
+            };
+            if predefined_symbol(label).is_some() || labels.contains_key(label) {
+                bail!("line {}: label '{}' already defined", lineno + 1, label);
+            }
+            labels.insert(label.to_string(), address as u16);
+            label_order.push(label.to_string());
+            continue;
+        }
+        address += 1;
+        if address > 0x8000 {
+            bail!("line {}: program does not fit in ROM", lineno + 1);
+        }
+    }
+
+    // second pass: encode, allocating variables as they are first seen
+    let mut pdb = Pdb::new();
+    pdb.file_info.push(FileInfo {
+        name: file.to_path_buf(),
+        file_type: FileType::Asm,
+    });
+    pdb.source_lines = source.lines().map(str::to_string).collect();
+    pdb.symbols = label_order
+        .iter()
+        .map(|l| symbol(SymbolType::Label, l, labels[l]))
+        .collect();
+
+    let mut variables: HashMap<String, u16> = HashMap::new();
+    let mut code = Vec::new();
+    for (lineno, line) in source.lines().enumerate() {
+        let inst = clean_line(line);
+        if inst.is_empty() || inst.starts_with('(') {
+            continue;
+        }
+        let word = encode_instruction(line, |name| {
+            if let Some(&addr) = labels.get(name).or_else(|| variables.get(name)) {
+                return Ok(addr);
+            }
+            let addr = FIRST_VARIABLE + variables.len() as u16;
+            if addr >= 0x4000 {
+                bail!("too many variables");
+            }
+            variables.insert(name.to_string(), addr);
+            pdb.symbols.push(symbol(SymbolType::Var, name, addr));
+            Ok(addr)
+        })
+        .map_err(|e| anyhow::anyhow!("line {}: {}", lineno + 1, e))?;
+        pdb.source_map.push(SourceMap {
+            file: 0,
+            line_no: lineno + 1,
+            col_no: 0,
+            addr: code.len() as u16,
+        });
+        code.push(word);
+    }
+    Ok(Assembly { code, pdb })
+}
+
+impl HackSystem {
+    /// Load a program file: `.asm` source is assembled and replaces the PDB,
+    /// a `.vm` file switches to VM mode, anything else is handed to
+    /// `HackEngine::load_file` with an empty PDB.
+    pub fn load_code(&mut self, file: &Path, text: &str) -> Result<()> {
+        let extension = file
+            .extension()
+            .map(|e| e.to_string_lossy().to_ascii_lowercase());
+        if extension.as_deref() == Some("vm") {
+            return self.load_vm(&[(file.to_path_buf(), text.to_string())]);
+        }
+        self.vm = None;
+        self.native_os.reset_heap();
+        if extension.as_deref() != Some("asm") {
+            self.engine.load_file(text)?;
+            // symbols and native OS intercepts of the last program are stale
+            self.pdb = Pdb::new();
+            return self.load_waw();
+        }
+        let assembly = assemble(text, file)?;
+        self.engine.load_rom(&assembly.code);
+        self.pdb = assembly.pdb;
+        self.load_waw()
+    }
+
+    /// Assemble one instruction into ROM at `address`, the inverse of
+    /// `HackEngine::disassemble_one`. `@symbol` operands are resolved with
+    /// `convert_addr`. Returns the encoded word.
+    pub fn assemble_at(&mut self, address: u16, text: &str) -> Result<u16> {
+        if self.vm_mode() {
+            bail!("Cannot patch ROM in VM mode");
+        }
+        if address as usize >= self.engine.rom.len() {
+            bail!("ROM address 0x{:04X} out of range", address);
+        }
+        let word = encode_instruction(text, |name| Ok(self.convert_addr(name)?.0))?;
+        self.engine.rom[address as usize] = word;
+        Ok(word)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emulator::engine::{HackEngine, StopReason};
+    use std::path::PathBuf;
+    use web_time::Duration;
+
+    const MAX: &str = "\
+// max of R0 and R1
+   @R0
+   D=M              // D = first number
+   @R1
+   D=D-M
+   @OUTPUT_FIRST
+   D;JGT
+   @R1
+   D=M
+   @OUTPUT_D
+   0;JMP
+(OUTPUT_FIRST)
+   @R0
+   D=M
+(OUTPUT_D)
+   @max
+   M=D
+   @R2
+   M=D
+(END)
+   @END
+   0 ; JMP
+";
+
+    #[test]
+    fn test_assemble() {
+        let asm = assemble(MAX, Path::new("Max.asm")).unwrap();
+        assert_eq!(asm.code.len(), 18);
+        assert_eq!(
+            &asm.code[..6],
+            &[0x0000, 0xFC10, 0x0001, 0xF4D0, 0x000A, 0xE301]
+        );
+        assert_eq!(asm.code[12], 16); // @max is the first variable
+        assert_eq!(asm.code[16], 16); // @END
+        assert_eq!(asm.code[17], 0xEA87);
+
+        let names: Vec<_> = asm
+            .pdb
+            .symbols
+            .iter()
+            .map(|s| (s.name.as_str(), s.symbol_type.clone(), s.address))
+            .collect();
+        assert_eq!(
+            names,
+            vec![
+                ("OUTPUT_FIRST", SymbolType::Label, 10),
+                ("OUTPUT_D", SymbolType::Label, 12),
+                ("END", SymbolType::Label, 16),
+                ("max", SymbolType::Var, 16),
+            ]
+        );
+        assert_eq!(asm.pdb.source_map[0].line_no, 2);
+        assert_eq!(asm.pdb.source_map[17].line_no, 22);
+
+        assert_eq!(
+            encode_instruction("AMD=M+D;JNE", |_| Ok(0)).unwrap(),
+            0xF0BD
+        );
+        assert_eq!(encode_instruction("@R15", |_| Ok(0)).unwrap(), 15);
+        for bad in ["@32768", "X=D", "D=Q", "D;JXX", "D=M;", "(LOOP)", "MM=D"] {
+            assert!(encode_instruction(bad, |_| Ok(0)).is_err(), "{}", bad);
+        }
+        let err = assemble("@1\n(L)\n(L)\n", Path::new("x.asm")).unwrap_err();
+        assert_eq!(err.to_string(), "line 3: label 'L' already defined");
+        let err = assemble("@1\nD=Q\n", Path::new("x.asm")).unwrap_err();
+        assert_eq!(err.to_string(), "line 2: invalid computation 'Q'");
+    }
+
+    #[test]
+    fn test_load_asm() {
+        let mut hs = HackSystem::new();
+        hs.load_code(Path::new("Max.asm"), MAX).unwrap();
+        hs.engine.ram[0] = 3;
+        hs.engine.ram[1] = 9;
+        assert_eq!(hs.convert_addr("END").unwrap().0, 16);
+        assert!(hs.source_location(2).unwrap().starts_with("Max.asm:4  @R1"));
+        let stop = hs.execute_instructions(Duration::from_millis(100)).unwrap();
+        assert_eq!(stop, StopReason::HardLoop);
+        assert_eq!(hs.engine.ram[2], 9);
+        assert_eq!(hs.engine.ram[16], 9);
+
+        // patch the jump to OUTPUT_D into a jump to END
+        assert_eq!(hs.assemble_at(8, "@END").unwrap(), 16);
+        assert_eq!(hs.assemble_at(9, " D ; JMP // skip").unwrap(), 0xE307);
+        assert_eq!(HackEngine::disassemble_one(hs.engine.rom[9]), "D;JMP");
+        assert!(hs.assemble_at(9, "@nowhere").is_err());
+        assert!(hs.assemble_at(0x8000, "D=M").is_err());
+        assert_eq!(hs.engine.rom[9], 0xE307);
+    }
+
+    #[test]
+    fn test_load_hack_forgets_symbols() {
+        let mut hs = HackSystem::new();
+        let vm = "function Sys.init 0\npush constant 2\npush constant 3\ncall Math.multiply 2\n";
+        hs.load_vm(&[(PathBuf::from("Sys.vm"), vm.to_string())])
+            .unwrap();
+        assert!(!hs.set_native_os(true).is_empty());
+        hs.load_code(Path::new("Zero.hack"), "0000000000000000\n")
+            .unwrap();
+        assert!(hs.pdb.symbols.is_empty());
+        assert!(hs.waw.is_empty());
+        assert!(hs.engine.intercepts.is_empty());
+    }
+}
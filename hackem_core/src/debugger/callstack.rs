@@ -0,0 +1,200 @@
+/*
+
+Call stack
+
+Reconstructs the Jack VM call stack from the frames the VM translator saves
+on the stack (see stepping.rs for the layout). The innermost frame uses the
+live LCL/ARG/THIS/THAT at RAM[1..4]; each caller's registers and return
+address are read from the five words below the callee's LCL:
+
+    RAM[LCL-5]  return address -> pc of the caller frame
+    RAM[LCL-4]  caller's LCL
+    RAM[LCL-3]  caller's ARG
+    RAM[LCL-2]  caller's THIS
+    RAM[LCL-1]  caller's THAT
+
+The walk stops at the first frame whose LCL does not look like a stack
+address below the callee's, which is where the bootstrap code called
+Sys.init. Frames are named by the PDB Func symbol at or below their pc.
+
+*/
+
+use anyhow::{bail, Result};
+use common::pdb::database::{Symbol, SymbolType};
+
+use super::debug_em::HackSystem;
+use super::stepping::{ARG, LCL, THAT, THIS};
+
+/// Guards against walking a corrupt stack forever.
+const MAX_FRAMES: usize = 1024;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Frame {
+    /// Current PC for the innermost frame, return address for the others.
+    pub pc: u16,
+    pub function: Option<String>,
+    pub lcl: u16,
+    pub arg: u16,
+    pub this: u16,
+    pub that: u16,
+}
+
+impl HackSystem {
+    /// The Func symbol containing `addr`: the closest one at or below it.
+    pub fn function_at(&self, addr: u16) -> Option<&Symbol> {
+        self.pdb
+            .symbols
+            .iter()
+            .filter(|s| s.symbol_type == SymbolType::Func && s.address <= addr)
+            .max_by_key(|s| s.address)
+    }
+
+    /// Addresses of function `name`: from its Func symbol up to the next
+    /// one, or to the end of the program for the last.
+    pub fn function_range(&self, name: &str) -> Result<(u16, u16)> {
+        let Some(start) = self
+            .pdb
+            .symbols
+            .iter()
+            .find(|s| s.symbol_type == SymbolType::Func && s.name == name)
+            .map(|s| s.address)
+        else {
+            bail!("Function '{}' not found", name);
+        };
+        let end = self
+            .pdb
+            .symbols
+            .iter()
+            .filter(|s| s.symbol_type == SymbolType::Func && s.address > start)
+            .map(|s| s.address - 1)
+            .min()
+            .unwrap_or_else(|| {
+                let last = self.engine.rom_words_loaded.saturating_sub(1);
+                last.max(start as usize) as u16
+            });
+        Ok((start, end))
+    }
+
+    fn frame(&self, pc: u16, lcl: u16, arg: u16, this: u16, that: u16) -> Frame {
+        Frame {
+            pc,
+            function: self.function_at(pc).map(|s| s.name.clone()),
+            lcl,
+            arg,
+            this,
+            that,
+        }
+    }
+
+    /// All frames, innermost first.
+    pub fn backtrace(&self) -> Vec<Frame> {
+        let ram = &self.engine.ram;
+        let mut frames = vec![self.frame(self.engine.pc, ram[LCL], ram[ARG], ram[THIS], ram[THAT])];
+        while frames.len() < MAX_FRAMES {
+            let lcl = frames.last().unwrap().lcl;
+            if !(5..0x4000).contains(&lcl) {
+                break;
+            }
+            let saved = lcl as usize - 5;
+            let caller = self.frame(
+                ram[saved],
+                ram[saved + 1],
+                ram[saved + 2],
+                ram[saved + 3],
+                ram[saved + 4],
+            );
+            let done = caller.lcl >= lcl;
+            frames.push(caller);
+            if done {
+                break;
+            }
+        }
+        frames
+    }
+
+    /// The frame chosen with `select_frame`, or the innermost one if the
+    /// stack has since become shallower.
+    pub fn selected_frame(&self) -> Frame {
+        let mut frames = self.backtrace();
+        if self.selected_frame < frames.len() {
+            frames.swap_remove(self.selected_frame)
+        } else {
+            frames.swap_remove(0)
+        }
+    }
+
+    pub fn selected_frame_index(&self) -> usize {
+        self.selected_frame
+    }
+
+    /// Make frame `index` (0 = innermost) the one used by `.lcl`, `.arg`,
+    /// `.this` and `.that` in expressions.
+    pub fn select_frame(&mut self, index: usize) -> Result<Frame> {
+        let mut frames = self.backtrace();
+        if index >= frames.len() {
+            bail!("No frame {} (stack has {})", index, frames.len());
+        }
+        self.selected_frame = index;
+        Ok(frames.swap_remove(index))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::pdb::database::FileType;
+
+    fn func(name: &str, address: u16) -> Symbol {
+        Symbol {
+            symbol_type: SymbolType::Func,
+            name: name.to_string(),
+            func_type: 0,
+            var_type: 0,
+            storage_class: 0,
+            size: 0,
+            address,
+            instance_type: String::new(),
+            file_type: FileType::Jack,
+        }
+    }
+
+    #[test]
+    fn test_backtrace() {
+        let mut hacksys = HackSystem::new();
+        hacksys.pdb.symbols.push(func("Sys.init", 0x10));
+        hacksys.pdb.symbols.push(func("Main.main", 0x40));
+        hacksys.pdb.symbols.push(func("Math.divide", 0x80));
+
+        // Sys.init frame at LCL=261, called by the bootstrap (LCL=0)
+        hacksys.engine.ram[256..261].copy_from_slice(&[0x0005, 0, 0, 0, 0]);
+        // Main.main frame at LCL=270, called from Sys.init
+        hacksys.engine.ram[265..270].copy_from_slice(&[0x0012, 261, 256, 0, 0]);
+        // Math.divide is running
+        hacksys.engine.pc = 0x0085;
+        hacksys.engine.ram[LCL] = 280;
+        hacksys.engine.ram[ARG] = 273;
+        hacksys.engine.ram[275..280].copy_from_slice(&[0x0044, 270, 263, 0x800, 0]);
+
+        let frames = hacksys.backtrace();
+        let names: Vec<_> = frames.iter().map(|f| f.function.as_deref()).collect();
+        assert_eq!(
+            names,
+            [
+                Some("Math.divide"),
+                Some("Main.main"),
+                Some("Sys.init"),
+                None
+            ]
+        );
+        assert_eq!(frames[1].pc, 0x0044);
+        assert_eq!(
+            (frames[1].lcl, frames[1].arg, frames[1].this),
+            (270, 263, 0x800)
+        );
+
+        hacksys.select_frame(1).unwrap();
+        assert_eq!(hacksys.evaluate(".lcl").unwrap(), 270);
+        assert_eq!(hacksys.evaluate("@(.arg)").unwrap(), 0);
+        assert!(hacksys.select_frame(4).is_err());
+    }
+}
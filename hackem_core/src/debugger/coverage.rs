@@ -0,0 +1,228 @@
+/*
+
+Code coverage
+
+`HackEngine::executed` marks every address run since the program was
+loaded. Here those addresses are mapped to source lines through the PDB
+source map: an entry covers the addresses from its own up to the next
+entry of the same source level (Jack/C, VM or assembly), so a program
+compiled from Jack gets coverage for its .jack, .vm and .asm files alike.
+A line counts as executed if any of its addresses ran, and a function if
+its entry (the Func symbol address) ran.
+
+The result is written in the LCOV tracefile format read by genhtml,
+coverage gutters and CI services:
+
+    SF:<source file>
+    FN:<line>,<function>      FNDA:<0|1>,<function>   FNF:  FNH:
+    DA:<line>,<0|1>           LF:  LH:
+    end_of_record
+
+*/
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::path::PathBuf;
+
+use common::pdb::database::{FileType, SymbolType};
+
+use super::debug_em::HackSystem;
+use super::pdbio::source_level;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct FileCoverage {
+    pub path: PathBuf,
+    /// Executed or not, by 1-based line number.
+    pub lines: BTreeMap<usize, bool>,
+    /// Line, name and executed, for each function starting in the file.
+    pub functions: Vec<(usize, String, bool)>,
+}
+
+impl FileCoverage {
+    pub fn lines_hit(&self) -> usize {
+        self.lines.values().filter(|&&hit| hit).count()
+    }
+
+    pub fn functions_hit(&self) -> usize {
+        self.functions.iter().filter(|f| f.2).count()
+    }
+}
+
+impl HackSystem {
+    /// Forget which addresses have run.
+    pub fn clear_coverage(&mut self) {
+        self.engine.executed.fill(false);
+    }
+
+    /// One past the last address of the loaded program.
+    fn program_end(&self) -> usize {
+        match &self.vm {
+            Some(vm) => vm.commands.len(),
+            None => self.engine.rom_words_loaded,
+        }
+    }
+
+    /// Coverage of every source file in the PDB, in PDB order.
+    pub fn coverage(&self) -> Vec<FileCoverage> {
+        let executed = &self.engine.executed;
+        let file_type = |file: usize| {
+            self.pdb
+                .file_info
+                .get(file)
+                .map_or(FileType::Unknown, |f| f.file_type.clone())
+        };
+        let mut files: Vec<FileCoverage> = self
+            .pdb
+            .file_info
+            .iter()
+            .map(|f| FileCoverage {
+                path: f.name.clone(),
+                lines: BTreeMap::new(),
+                functions: Vec::new(),
+            })
+            .collect();
+        let funcs: Vec<(u16, &str)> = self
+            .pdb
+            .symbols
+            .iter()
+            .filter(|s| s.symbol_type == SymbolType::Func)
+            .map(|s| (s.address, s.name.as_str()))
+            .collect();
+
+        for level in 0..=3 {
+            let mut entries: Vec<_> = self
+                .pdb
+                .source_map
+                .iter()
+                .filter(|s| s.file < files.len() && source_level(&file_type(s.file)) == level)
+                .collect();
+            entries.sort_by_key(|s| s.addr);
+            for (i, entry) in entries.iter().enumerate() {
+                let start = entry.addr as usize;
+                let next = entries
+                    .get(i + 1)
+                    .map_or(self.program_end(), |s| s.addr as usize);
+                let end = next.clamp(start + 1, executed.len());
+                let hit = executed[start..end].iter().any(|&e| e);
+                let file = &mut files[entry.file];
+                *file.lines.entry(entry.line_no).or_insert(false) |= hit;
+                for &(address, name) in &funcs {
+                    if (start..end).contains(&(address as usize))
+                        && !file.functions.iter().any(|f| f.1 == name)
+                    {
+                        file.functions.push((
+                            entry.line_no,
+                            name.to_string(),
+                            executed[address as usize],
+                        ));
+                    }
+                }
+            }
+        }
+        files.retain(|f| !f.lines.is_empty());
+        files
+    }
+
+    /// The coverage as an LCOV tracefile.
+    pub fn lcov(&self) -> String {
+        let mut out = String::new();
+        for file in self.coverage() {
+            let _ = writeln!(out, "TN:");
+            let _ = writeln!(out, "SF:{}", file.path.display());
+            for (line, name, _) in &file.functions {
+                let _ = writeln!(out, "FN:{},{}", line, name);
+            }
+            for (_, name, hit) in &file.functions {
+                let _ = writeln!(out, "FNDA:{},{}", *hit as u8, name);
+            }
+            let _ = writeln!(out, "FNF:{}", file.functions.len());
+            let _ = writeln!(out, "FNH:{}", file.functions_hit());
+            for (line, hit) in &file.lines {
+                let _ = writeln!(out, "DA:{},{}", line, *hit as u8);
+            }
+            let _ = writeln!(out, "LF:{}", file.lines.len());
+            let _ = writeln!(out, "LH:{}", file.lines_hit());
+            let _ = writeln!(out, "end_of_record");
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::{Path, PathBuf};
+
+    use web_time::Duration;
+
+    use super::*;
+
+    const ABS: &str = "\
+// R1 = |R0|
+    @R0
+    D=M
+    @DONE
+    D;JGE
+    D=-D
+(DONE)
+    @R1
+    M=D
+(END)
+    @END
+    0;JMP
+";
+
+    #[test]
+    fn test_asm_coverage() {
+        let mut hs = HackSystem::new();
+        hs.load_code(Path::new("Abs.asm"), ABS).unwrap();
+        hs.engine.ram[0] = 5;
+        hs.execute_instructions(Duration::from_secs(1)).unwrap();
+
+        let files = hs.coverage();
+        assert_eq!(files.len(), 1);
+        let lines = &files[0].lines;
+        assert_eq!(lines.len(), 9);
+        assert!(!lines[&6]);
+        assert_eq!(files[0].lines_hit(), 8);
+
+        let lcov = hs.lcov();
+        assert!(lcov.starts_with("TN:\nSF:Abs.asm\n"));
+        assert!(lcov.contains("DA:6,0\n"));
+        assert!(lcov.contains("LF:9\nLH:8\nend_of_record\n"));
+
+        hs.clear_coverage();
+        assert_eq!(hs.coverage()[0].lines_hit(), 0);
+    }
+
+    #[test]
+    fn test_vm_function_coverage() {
+        let sys = "\
+function Sys.init 0
+    call Main.used 0
+    pop temp 0
+label END
+    goto END
+function Main.unused 0
+    push constant 0
+    return
+function Main.used 0
+    push constant 1
+    return
+";
+        let mut hs = HackSystem::new();
+        hs.load_vm(&[(PathBuf::from("Sys.vm"), sys.to_string())])
+            .unwrap();
+        hs.execute_instructions(Duration::from_secs(1)).unwrap();
+
+        let file = &hs.coverage()[0];
+        assert_eq!(
+            file.functions,
+            vec![
+                (1, "Sys.init".to_string(), true),
+                (6, "Main.unused".to_string(), false),
+                (9, "Main.used".to_string(), true),
+            ]
+        );
+        assert_eq!(file.lines_hit(), 8);
+    }
+}
@@ -0,0 +1,360 @@
+use std::{cell::RefCell, collections::BTreeMap};
+
+use common::pdb::database::Pdb;
+
+use super::native_os::NativeOs;
+use super::pdbio::CodeLocation;
+use super::stepping::RunGoal;
+use super::vm::VmProgram;
+use crate::emulator::engine::{HackEngine, StopReason};
+use anyhow::{bail, Result};
+use web_time::{Duration, Instant};
+
+pub struct HackSystem {
+    pub engine: HackEngine,
+    pub pdb: Pdb,
+    pub waw: BTreeMap<u16, CodeLocation>,
+    /// Messages printed by logpoints, waiting for the UI to show them.
+    pub messages: Vec<String>,
+    /// What a step over / finish / until is waiting for.
+    pub(crate) goal: Option<RunGoal>,
+    /// Call stack frame used by the frame registers in expressions.
+    pub(crate) selected_frame: usize,
+    pub(crate) expr_value: RefCell<evalexpr::Value>,
+    /// The loaded VM program when in VM mode; `engine.pc` is then a command index.
+    pub vm: Option<VmProgram>,
+    /// Built-in implementation of the Jack OS, see `native_os`.
+    pub native_os: NativeOs,
+}
+
+impl Default for HackSystem {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl HackSystem {
+    pub fn new() -> HackSystem {
+        HackSystem {
+            engine: HackEngine::new(),
+            pdb: Pdb::new(),
+            expr_value: RefCell::new(evalexpr::Value::Int(0)),
+            waw: BTreeMap::new(),
+            messages: Vec::new(),
+            goal: None,
+            selected_frame: 0,
+            vm: None,
+            native_os: NativeOs::default(),
+        }
+    }
+
+    /// Converts an address string to a numeric address.
+    /// - `$1a2b` or `0x1a2b` → hex
+    /// - plain digits → decimal
+    /// - anything else → symbol lookup from PDB
+    pub fn convert_addr(&self, addr_str: &str) -> Result<(u16, String)> {
+        if let Some(hex) = addr_str.strip_prefix('$').or_else(|| {
+            addr_str
+                .strip_prefix("0x")
+                .or_else(|| addr_str.strip_prefix("0X"))
+        }) {
+            return Ok((u16::from_str_radix(hex, 16)?, String::new()));
+        }
+
+        if addr_str.chars().next().unwrap().is_ascii_digit() {
+            return Ok((addr_str.parse::<u16>()?, String::new()));
+        }
+
+        let syms = self.get_symbols(addr_str)?;
+        match syms.len() {
+            0 => bail!("Symbol '{}' not found", addr_str),
+            1 => Ok((syms[0].address, addr_str.to_string())),
+            _ => bail!("Symbol '{}' is ambiguous", addr_str),
+        }
+    }
+
+    /// Run the engine for up to `run_time`, like
+    /// `HackEngine::execute_instructions`, but keep going past breakpoints
+    /// whose condition evaluates to zero or whose ignore count is not used up,
+    /// and past run-to addresses reached in the wrong frame. Any real stop
+    /// cancels a pending step over / finish / until. Calls to native OS
+    /// functions run here and count as one step.
+    pub fn execute_instructions(&mut self, run_time: Duration) -> Result<StopReason> {
+        let start_time = Instant::now();
+        self.selected_frame = 0;
+        loop {
+            let remaining = run_time.saturating_sub(start_time.elapsed());
+            let mut stop = match &self.vm {
+                Some(vm) => vm.execute(&mut self.engine, remaining)?,
+                None => self.engine.execute_instructions(remaining)?,
+            };
+            if stop == StopReason::Intercept {
+                stop = match self.native_call()? {
+                    Some(halt) => halt,
+                    None if self.engine.run_to.contains(&self.engine.pc) => StopReason::RunTo,
+                    None if remaining == Duration::ZERO => StopReason::RefreshUI,
+                    None => continue,
+                };
+            }
+            let mut stop_here = match stop {
+                StopReason::BreakPoint => self.breakpoint_hit(),
+                StopReason::RunTo => self.goal_reached(),
+                _ => true,
+            };
+            // the engine reports a breakpoint before a watchpoint hit by the
+            // same instruction, and forgets the watchpoint when it resumes
+            if !stop_here
+                && stop == StopReason::BreakPoint
+                && self.engine.triggered_watchpoint.is_some()
+            {
+                stop = StopReason::WatchPoint;
+                stop_here = true;
+            }
+            if stop_here {
+                if stop != StopReason::RefreshUI {
+                    self.clear_goal();
+                }
+                return Ok(stop);
+            }
+            if remaining == Duration::ZERO {
+                return Ok(StopReason::RefreshUI);
+            }
+        }
+    }
+
+    /// Drain the messages printed by logpoints since the last call.
+    pub fn take_messages(&mut self) -> Vec<String> {
+        std::mem::take(&mut self.messages)
+    }
+
+    /// Run backwards like `HackEngine::reverse_continue`, but pass the
+    /// breakpoints a forward run would pass: those whose condition is false
+    /// or whose ignore count is not used up yet. Hit counts are not changed,
+    /// the hits were counted on the way forward.
+    pub fn reverse_continue(&mut self) -> Option<StopReason> {
+        self.selected_frame = 0;
+        loop {
+            let stop = self.engine.reverse_continue()?;
+            if stop != StopReason::BreakPoint || self.breakpoint_stops(false) {
+                return Some(stop);
+            }
+        }
+    }
+
+    /// Decide whether the triggered breakpoint stops execution, counting the
+    /// hit, printing logpoints and removing it if it is temporary. A condition that fails to
+    /// evaluate counts as true, and the error goes to the messages, so it is seen.
+    fn breakpoint_hit(&mut self) -> bool {
+        self.breakpoint_stops(true)
+    }
+
+    /// `breakpoint_hit`, with the hit counted, logged and a temporary
+    /// breakpoint removed only when `count` is set.
+    fn breakpoint_stops(&mut self, count: bool) -> bool {
+        let Some(addr) = self.engine.triggered_breakpoint else {
+            return true;
+        };
+        let condition = self
+            .engine
+            .break_points
+            .get(&addr)
+            .and_then(|bp| bp.condition.clone());
+        if let Some(expr) = condition {
+            match self.evaluate(&expr) {
+                Ok(0) => return false,
+                Ok(_) => {}
+                Err(e) => self
+                    .messages
+                    .push(format!("breakpoint 0x{:04X}: condition error: {}", addr, e)),
+            }
+        }
+        let Some(bp) = self.engine.break_points.get_mut(&addr) else {
+            return true;
+        };
+        if count {
+            bp.hit_count += 1;
+        }
+        // going backwards, stop where the next hit going forwards would
+        let hits = if count {
+            bp.hit_count
+        } else {
+            bp.hit_count + 1
+        };
+        if hits <= bp.ignore_count {
+            return false;
+        }
+        if let Some(format) = bp.log.clone() {
+            if count {
+                let message = self.interpolate(&format);
+                self.messages.push(message);
+            }
+            return false;
+        }
+        if count && bp.temporary {
+            self.engine.remove_breakpoint(addr);
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_conditional_breakpoint() {
+        let mut hacksys = HackSystem::new();
+        // loop: RAM[16] += 1
+        hacksys.engine.rom[0] = 0x0010; // @16
+        hacksys.engine.rom[1] = 0xfdc8; // M=M+1
+        hacksys.engine.rom[2] = 0x0000; // @0
+        hacksys.engine.rom[3] = 0xea87; // 0;JMP
+        hacksys
+            .engine
+            .add_breakpoint_if(1, Some("@(16) == 5".to_string()));
+
+        let stop = hacksys
+            .execute_instructions(Duration::from_secs(10))
+            .unwrap();
+        assert_eq!(stop, StopReason::BreakPoint);
+        assert_eq!(hacksys.engine.ram[16], 5);
+
+        // single stepping past a false condition does not report a hit
+        hacksys.engine.break_points.get_mut(&1).unwrap().condition = Some("0".to_string());
+        for _ in 0..4 {
+            let stop = hacksys.execute_instructions(Duration::ZERO).unwrap();
+            assert_eq!(stop, StopReason::RefreshUI);
+        }
+        assert_eq!(hacksys.engine.ram[16], 6);
+    }
+
+    #[test]
+    fn test_condition_error() {
+        let mut hacksys = HackSystem::new();
+        hacksys.engine.rom[0] = 0x0010; // @16
+        hacksys.engine.rom[1] = 0xfdc8; // M=M+1
+        hacksys.engine.rom[2] = 0x0000; // @0
+        hacksys.engine.rom[3] = 0xea87; // 0;JMP
+        hacksys
+            .engine
+            .add_breakpoint_if(1, Some("@(@(16) + 40000) > 0".to_string()));
+        let stop = hacksys
+            .execute_instructions(Duration::from_secs(10))
+            .unwrap();
+        assert_eq!(stop, StopReason::BreakPoint);
+        let messages = hacksys.take_messages();
+        assert_eq!(messages.len(), 1);
+        assert!(
+            messages[0].starts_with("breakpoint 0x0001: condition error:")
+                && messages[0].contains("@(40001) is outside RAM"),
+            "{}",
+            messages[0]
+        );
+    }
+
+    #[test]
+    fn test_ignore_count_and_temporary() {
+        let mut hacksys = HackSystem::new();
+        hacksys.engine.rom[0] = 0x0010; // @16
+        hacksys.engine.rom[1] = 0xfdc8; // M=M+1
+        hacksys.engine.rom[2] = 0x0000; // @0
+        hacksys.engine.rom[3] = 0xea87; // 0;JMP
+        hacksys.engine.add_breakpoint(1);
+        let bp = hacksys.engine.break_points.get_mut(&1).unwrap();
+        bp.ignore_count = 2;
+        bp.temporary = true;
+
+        let stop = hacksys
+            .execute_instructions(Duration::from_secs(10))
+            .unwrap();
+        assert_eq!(stop, StopReason::BreakPoint);
+        assert_eq!(hacksys.engine.ram[16], 3);
+        assert!(hacksys.engine.break_points.is_empty());
+    }
+
+    #[test]
+    fn test_logpoint() {
+        let mut hacksys = HackSystem::new();
+        hacksys.engine.rom[0] = 0x0010; // @16
+        hacksys.engine.rom[1] = 0xfdc8; // M=M+1
+        hacksys.engine.rom[2] = 0x0000; // @0
+        hacksys.engine.rom[3] = 0xea87; // 0;JMP
+        hacksys.engine.add_breakpoint(1);
+        hacksys.engine.break_points.get_mut(&1).unwrap().log =
+            Some("n={@(16)} {{n*2}}={@(16) * 2}".to_string());
+        hacksys.engine.inst_limit = Some(8);
+
+        let stop = hacksys
+            .execute_instructions(Duration::from_secs(10))
+            .unwrap();
+        assert_eq!(stop, StopReason::InstructionLimit);
+        assert_eq!(hacksys.take_messages(), ["n=1 {n*2}=2", "n=2 {n*2}=4"]);
+        assert!(hacksys.messages.is_empty());
+    }
+
+    #[test]
+    fn test_breakpoint_and_watchpoint() {
+        let mut hacksys = HackSystem::new();
+        hacksys.engine.rom[0] = 0x0010; // @16
+        hacksys.engine.rom[1] = 0xfdc8; // M=M+1
+        hacksys.engine.rom[2] = 0x0000; // @0
+        hacksys.engine.rom[3] = 0xea87; // 0;JMP
+        hacksys
+            .engine
+            .add_breakpoint_if(1, Some("@(16) == 5".to_string()));
+        hacksys.engine.add_watchpoint(16, false, true);
+
+        // the false condition does not hide the write to RAM[16]
+        let stop = hacksys
+            .execute_instructions(Duration::from_secs(10))
+            .unwrap();
+        assert_eq!(stop, StopReason::WatchPoint);
+        assert_eq!(hacksys.engine.ram[16], 1);
+        assert_eq!(hacksys.engine.triggered_watchpoint.as_ref().unwrap().pc, 1);
+    }
+
+    #[test]
+    fn test_reverse_continue_conditions() {
+        let mut hacksys = HackSystem::new();
+        hacksys.engine.rom[0] = 0x0010; // @16
+        hacksys.engine.rom[1] = 0xfdc8; // M=M+1
+        hacksys.engine.rom[2] = 0x0000; // @0
+        hacksys.engine.rom[3] = 0xea87; // 0;JMP
+        hacksys.engine.inst_limit = Some(20);
+        hacksys
+            .execute_instructions(Duration::from_secs(10))
+            .unwrap();
+        assert_eq!(hacksys.engine.ram[16], 5);
+
+        hacksys
+            .engine
+            .add_breakpoint_if(1, Some("@(16) == 2".to_string()));
+        hacksys.engine.add_breakpoint(3);
+        hacksys.engine.break_points.get_mut(&3).unwrap().log = Some("x".to_string());
+        assert_eq!(hacksys.reverse_continue(), Some(StopReason::BreakPoint));
+        assert_eq!(hacksys.engine.ram[16], 2);
+        assert!(hacksys.messages.is_empty());
+        assert_eq!(hacksys.engine.break_points[&1].hit_count, 0);
+
+        // a breakpoint still being ignored going forward is passed
+        hacksys.engine.break_points.get_mut(&1).unwrap().condition = None;
+        hacksys
+            .engine
+            .break_points
+            .get_mut(&1)
+            .unwrap()
+            .ignore_count = 3;
+        assert_eq!(hacksys.reverse_continue(), None);
+    }
+
+    #[test]
+    fn test_condition_syntax() {
+        assert!(HackSystem::check_expression("@(0) > 300").is_ok());
+        assert!(HackSystem::check_expression("(@(0) > 3").is_err());
+        assert_eq!(HackSystem::new().evaluate("2 > 1").unwrap(), 1);
+        // dereferencing outside RAM is an error, not a panic
+        assert!(HackSystem::new().evaluate("@(0-1)").is_err());
+        assert!(HackSystem::new().evaluate("@(40000)").is_err());
+        assert!(HackSystem::new().evaluate("@(@(1) - 6) > 3").is_err());
+    }
+}
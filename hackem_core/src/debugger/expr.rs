@@ -0,0 +1,193 @@
+/*
+
+Evaluates arbitrary address expressions
+
+Any shell command tha expects an address can be given an expression instead.
+An expression starts with '=' and is followed by a valid evalexpr expression.
+It may need to be quoted to avoid shell expansion and clap confusuion.
+
+The regsisters are variables called .pc, .a, .d
+The VM pointers are .sp, .lcl, .arg, .this, .that; all but .sp follow the
+frame selected with the `frame` command or the Call Stack tab
+All symbols are available as variables
+You can deference a pointer using '@(<ptr>)'
+the expr command evaluates an expression and prints the result
+
+so you can do
+
+dis =pc
+mem =.xr+0x20
+mem ptr // no need for expression, symbols just work anyway
+mem =@(ptr) // deference a pointer
+mem '=@(ptr + 0x20)' // do math on a pointer
+mem =@(ptr + (0x20*xr)) // more math
+
+Comparisons evaluate to 1 or 0, so an expression can also be used as a
+breakpoint condition:
+
+break Math.multiply -if '@(0) > 300'
+
+Logpoint messages embed expressions in braces, printed as signed decimal;
+'{{' and '}}' give literal braces:
+
+trace Math.divide "x={@(ARG)} y={@(ARG+1)}"
+
+
+
+*/
+
+use anyhow::{anyhow, bail, Result};
+use evalexpr::{build_operator_tree, eval_with_context, Context, EvalexprResult, Value};
+
+use super::debug_em::HackSystem;
+
+impl Context for HackSystem {
+    fn get_value(&self, key: &str) -> Option<&Value> {
+        let regs = self.engine.get_registers();
+        let val = match key {
+            ".pc" => regs.0 as i64,
+            ".a" => regs.1 as i64,
+            ".d" => regs.2 as i64,
+            ".sp" => self.engine.ram[0] as i64,
+            ".lcl" => self.selected_frame().lcl as i64,
+            ".arg" => self.selected_frame().arg as i64,
+            ".this" => self.selected_frame().this as i64,
+            ".that" => self.selected_frame().that as i64,
+            _ => {
+                let sym = self.convert_addr(key).ok();
+                if let Some(s) = sym {
+                    s.0 as i64
+                } else {
+                    return None;
+                }
+            }
+        };
+        // horrible hack because we have to return
+        // a calculated value refernce from a read only context
+        self.expr_value.replace(Value::Int(val));
+        let p = self.expr_value.as_ptr();
+        Some(unsafe { &*p })
+    }
+    fn call_function(&self, key: &str, arg: &Value) -> EvalexprResult<Value> {
+        match key {
+            "@" => {
+                let arg = arg.as_int()?;
+                if !(0..self.engine.ram.len() as i64).contains(&arg) {
+                    return Err(evalexpr::EvalexprError::CustomMessage(format!(
+                        "@({}) is outside RAM (0..0x7FFF)",
+                        arg
+                    )));
+                }
+                let word = self.engine.ram[arg as usize];
+                Ok(evalexpr::Value::Int(word as i64))
+            }
+            // "@b" => {
+            //     let arg = arg.as_int()?;
+            //     if arg > u16::MAX as i64 {
+            //         return Err(evalexpr::EvalexprError::WrongFunctionArgumentAmount {
+            //             expected: RangeInclusive::new(0, 0xffff),
+            //             actual: arg as usize,
+            //         });
+            //     }
+            //     let byte = Cpu::read_byte(arg as u16);
+            //     Ok(evalexpr::Value::Int(byte as i64))
+            // }
+            _ => Err(evalexpr::EvalexprError::FunctionIdentifierNotFound(
+                key.to_string(),
+            )),
+        }
+    }
+    fn are_builtin_functions_disabled(&self) -> bool {
+        false
+    }
+    fn set_builtin_functions_disabled(&mut self, _disabled: bool) -> EvalexprResult<()> {
+        Err(evalexpr::EvalexprError::CustomMessage(
+            "builtin functions are not supported".to_string(),
+        ))
+    }
+}
+impl HackSystem {
+    pub fn evaluate(&self, expr: &str) -> Result<u16> {
+        match eval_with_context(expr, self).map_err(|e| anyhow!(e))? {
+            Value::Int(v) => Ok(v as u16),
+            Value::Boolean(b) => Ok(b as u16),
+            v => bail!("expression '{}' gave {}, expected a number", expr, v),
+        }
+    }
+
+    /// Parse `expr` without evaluating it, so a bad breakpoint condition is
+    /// reported when it is set rather than when it is first hit.
+    pub fn check_expression(expr: &str) -> Result<()> {
+        build_operator_tree(expr).map_err(|e| anyhow!(e))?;
+        Ok(())
+    }
+
+    /// Expand each `{expr}` in a logpoint format with the expression's value.
+    /// An expression that fails to evaluate is shown as `{error: ...}`.
+    pub fn interpolate(&self, format: &str) -> String {
+        let mut out = String::new();
+        for piece in Self::split_format(format).unwrap_or_default() {
+            match piece {
+                FormatPiece::Text(text) => out.push_str(&text),
+                FormatPiece::Expr(expr) => match self.evaluate(&expr) {
+                    Ok(v) => out.push_str(&(v as i16).to_string()),
+                    Err(e) => out.push_str(&format!("{{error: {}}}", e)),
+                },
+            }
+        }
+        out
+    }
+
+    /// Check that a logpoint format is well formed and its expressions parse.
+    pub fn check_format(format: &str) -> Result<()> {
+        for piece in Self::split_format(format)? {
+            if let FormatPiece::Expr(expr) = piece {
+                Self::check_expression(&expr)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn split_format(format: &str) -> Result<Vec<FormatPiece>> {
+        let mut pieces = Vec::new();
+        let mut text = String::new();
+        let mut chars = format.chars().peekable();
+        while let Some(c) = chars.next() {
+            match c {
+                '{' if chars.peek() == Some(&'{') => {
+                    chars.next();
+                    text.push('{');
+                }
+                '}' if chars.peek() == Some(&'}') => {
+                    chars.next();
+                    text.push('}');
+                }
+                '{' => {
+                    let mut expr = String::new();
+                    loop {
+                        match chars.next() {
+                            Some('}') => break,
+                            Some(c) => expr.push(c),
+                            None => bail!("unclosed '{{' in '{}'", format),
+                        }
+                    }
+                    if !text.is_empty() {
+                        pieces.push(FormatPiece::Text(std::mem::take(&mut text)));
+                    }
+                    pieces.push(FormatPiece::Expr(expr));
+                }
+                '}' => bail!("unmatched '}}' in '{}'", format),
+                c => text.push(c),
+            }
+        }
+        if !text.is_empty() {
+            pieces.push(FormatPiece::Text(text));
+        }
+        Ok(pieces)
+    }
+}
+
+enum FormatPiece {
+    Text(String),
+    Expr(String),
+}
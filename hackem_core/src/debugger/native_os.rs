@@ -0,0 +1,658 @@
+/*
+
+Native OS
+
+High-level emulation of the Jack OS, like the built-in OS of the official
+VM emulator. When enabled, calls to known OS functions are intercepted at
+their entry point (the address of their PDB Func symbol, or the command
+index in VM mode) and run here in Rust against RAM. The caller has already
+pushed the frame, so the arguments are at RAM[ARG..] and the native code
+finishes with the VM `return` sequence, leaving the result on the stack.
+
+This lets programs run before their OS is written and makes the slow
+parts (multiply, divide, drawing) instant. Differences from the Jack OS:
+
+  - the heap is managed here, over RAM[2048..16384], with a size word
+    before each block as in the reference `Memory.alloc`
+  - a String is one heap block: max length, length, then the characters,
+    so `String` must be native whenever `Output.printString` is
+  - `Output` writes text to the output port (the console) instead of
+    drawing glyphs on the screen
+  - `Sys.wait` returns at once and `Sys.halt` / `Sys.error` stop the run
+  - a native call clears the execution history, so reverse stepping
+    stops just after it
+
+In VM mode calls to OS functions that are not loaded go to `builtin`
+commands appended to the program, which only run with the native OS on.
+
+*/
+
+use std::collections::BTreeMap;
+
+use anyhow::{bail, Result};
+use common::pdb::database::SymbolType;
+use serde::{Deserialize, Serialize};
+
+use super::debug_em::HackSystem;
+use super::stepping::{ARG, LCL, SP, THAT, THIS};
+use crate::emulator::{
+    devices::{KEYBOARD, OUTPUT_PORT, SCREEN_START},
+    engine::{HackEngine, StopReason},
+};
+
+const HEAP_START: u16 = 2048;
+const HEAP_END: u16 = 0x4000;
+const NEWLINE: u16 = 128;
+const BACKSPACE: u16 = 129;
+const DOUBLE_QUOTE: u16 = 34;
+
+/// Every natively implemented function and its number of arguments
+/// (including `this` for methods).
+pub const FUNCTIONS: &[(&str, u16)] = &[
+    ("Math.init", 0),
+    ("Math.abs", 1),
+    ("Math.multiply", 2),
+    ("Math.divide", 2),
+    ("Math.min", 2),
+    ("Math.max", 2),
+    ("Math.sqrt", 1),
+    ("Memory.init", 0),
+    ("Memory.peek", 1),
+    ("Memory.poke", 2),
+    ("Memory.alloc", 1),
+    ("Memory.deAlloc", 1),
+    ("Array.new", 1),
+    ("Array.dispose", 1),
+    ("String.new", 1),
+    ("String.dispose", 1),
+    ("String.length", 1),
+    ("String.charAt", 2),
+    ("String.setCharAt", 3),
+    ("String.appendChar", 2),
+    ("String.eraseLastChar", 1),
+    ("String.intValue", 1),
+    ("String.setInt", 2),
+    ("String.backSpace", 0),
+    ("String.doubleQuote", 0),
+    ("String.newLine", 0),
+    ("Output.init", 0),
+    ("Output.moveCursor", 2),
+    ("Output.printChar", 1),
+    ("Output.printString", 1),
+    ("Output.printInt", 1),
+    ("Output.println", 0),
+    ("Output.backSpace", 0),
+    ("Screen.init", 0),
+    ("Screen.clearScreen", 0),
+    ("Screen.setColor", 1),
+    ("Screen.drawPixel", 2),
+    ("Screen.drawLine", 4),
+    ("Screen.drawRectangle", 4),
+    ("Screen.drawCircle", 3),
+    ("Keyboard.init", 0),
+    ("Keyboard.keyPressed", 0),
+    ("Sys.wait", 1),
+    ("Sys.halt", 0),
+    ("Sys.error", 1),
+];
+
+/// Number of arguments of a native function, if there is one called `name`.
+pub fn arity(name: &str) -> Option<u16> {
+    FUNCTIONS.iter().find(|(n, _)| *n == name).map(|(_, a)| *a)
+}
+
+/// `base + offset` as a RAM address, or an error if a bad pointer from
+/// the program would take it outside RAM.
+fn address(name: &str, base: u16, offset: u32) -> Result<u16> {
+    let address = base as u32 + offset;
+    if address >= 0x8000 {
+        bail!("{}: bad pointer 0x{:04X}", name, base);
+    }
+    Ok(address as u16)
+}
+
+enum Native {
+    Return(u16),
+    Halt,
+}
+
+/// Native OS state that the Jack OS would keep in statics.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct NativeOs {
+    pub enabled: bool,
+    /// Screen.setColor: true draws black.
+    color: bool,
+    /// Free heap blocks, start -> length (including the size word).
+    free: BTreeMap<u16, u16>,
+}
+
+impl Default for NativeOs {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            color: true,
+            free: BTreeMap::from([(HEAP_START, HEAP_END - HEAP_START)]),
+        }
+    }
+}
+
+impl NativeOs {
+    /// Forget all heap blocks, as when a new program is loaded.
+    pub(crate) fn reset_heap(&mut self) {
+        self.free = NativeOs::default().free;
+    }
+
+    fn alloc(&mut self, engine: &mut HackEngine, size: u16) -> Result<u16> {
+        let need = size.max(1) + 1;
+        let Some((&start, &len)) = self.free.iter().find(|(_, &len)| len >= need) else {
+            bail!("Memory.alloc: heap overflow allocating {} words", size);
+        };
+        self.free.remove(&start);
+        if len > need {
+            self.free.insert(start + need, len - need);
+        }
+        engine.ram[start as usize] = need - 1;
+        Ok(start + 1)
+    }
+
+    fn de_alloc(&mut self, engine: &HackEngine, block: u16) -> Result<()> {
+        if !(HEAP_START + 1..HEAP_END).contains(&block) {
+            bail!("Memory.deAlloc: 0x{:04X} is not a heap block", block);
+        }
+        let mut start = block - 1;
+        let size = engine.ram[start as usize];
+        if size >= HEAP_END - start {
+            bail!("Memory.deAlloc: 0x{:04X} has a bad size {}", block, size);
+        }
+        let mut len = size + 1;
+        // merge with the free neighbours
+        if let Some(&next) = self.free.get(&(start + len)) {
+            self.free.remove(&(start + len));
+            len += next;
+        }
+        if let Some((&prev, &prev_len)) = self.free.range(..start).next_back() {
+            if prev + prev_len == start {
+                self.free.remove(&prev);
+                start = prev;
+                len += prev_len;
+            }
+        }
+        self.free.insert(start, len);
+        Ok(())
+    }
+
+    fn draw_pixel(&self, engine: &mut HackEngine, x: i16, y: i16) {
+        if !(0..512).contains(&x) || !(0..256).contains(&y) {
+            return;
+        }
+        let address = SCREEN_START as usize + y as usize * 32 + x as usize / 16;
+        let bit = 1 << (x % 16);
+        if self.color {
+            engine.ram[address] |= bit;
+        } else {
+            engine.ram[address] &= !bit;
+        }
+    }
+
+    fn draw_line(&self, engine: &mut HackEngine, x1: i16, y1: i16, x2: i16, y2: i16) {
+        let (dx, dy) = ((x2 - x1).abs(), -(y2 - y1).abs());
+        let (sx, sy) = ((x2 - x1).signum(), (y2 - y1).signum());
+        let (mut x, mut y, mut err) = (x1, y1, dx + dy);
+        loop {
+            self.draw_pixel(engine, x, y);
+            if x == x2 && y == y2 {
+                break;
+            }
+            let e2 = 2 * err;
+            if e2 >= dy {
+                err += dy;
+                x += sx;
+            }
+            if e2 <= dx {
+                err += dx;
+                y += sy;
+            }
+        }
+    }
+
+    fn print(&self, engine: &mut HackEngine, text: &str) -> Result<()> {
+        for b in text.bytes() {
+            engine.set_ram(OUTPUT_PORT, b as u16)?;
+        }
+        Ok(())
+    }
+
+    fn print_char(&self, engine: &mut HackEngine, c: u16) -> Result<()> {
+        let c = match c {
+            NEWLINE => b'\n' as u16,
+            BACKSPACE => 8,
+            c => c,
+        };
+        engine.set_ram(OUTPUT_PORT, c)?;
+        Ok(())
+    }
+
+    fn call(&mut self, engine: &mut HackEngine, name: &str, a: &[u16]) -> Result<Native> {
+        let arg = |i: usize| a[i] as i16;
+        // RAM[a[0] + offset], for the String functions
+        let ram =
+            |engine: &mut HackEngine, offset: u32| engine.get_ram(address(name, a[0], offset)?);
+        let value = match name {
+            "Math.init" | "Output.init" | "Keyboard.init" | "Output.moveCursor" => 0,
+            "Math.abs" => arg(0).wrapping_abs() as u16,
+            "Math.multiply" => arg(0).wrapping_mul(arg(1)) as u16,
+            "Math.divide" => {
+                if arg(1) == 0 {
+                    bail!("Math.divide: division by zero");
+                }
+                arg(0).wrapping_div(arg(1)) as u16
+            }
+            "Math.min" => arg(0).min(arg(1)) as u16,
+            "Math.max" => arg(0).max(arg(1)) as u16,
+            "Math.sqrt" => {
+                if arg(0) < 0 {
+                    bail!("Math.sqrt: negative argument {}", arg(0));
+                }
+                (arg(0) as f64).sqrt() as u16
+            }
+            "Memory.init" => {
+                self.reset_heap();
+                0
+            }
+            "Memory.peek" => engine.get_ram(a[0])?,
+            "Memory.poke" => {
+                engine.set_ram(a[0], a[1])?;
+                0
+            }
+            "Memory.alloc" | "Array.new" => {
+                if arg(0) < 0 {
+                    bail!("{}: negative size {}", name, arg(0));
+                }
+                self.alloc(engine, a[0])?
+            }
+            "Memory.deAlloc" | "Array.dispose" | "String.dispose" => {
+                self.de_alloc(engine, a[0])?;
+                0
+            }
+            "String.new" => {
+                if arg(0) < 0 {
+                    bail!("String.new: negative length {}", arg(0));
+                }
+                let s = self.alloc(engine, a[0] + 2)?;
+                engine.set_ram(s, a[0])?;
+                engine.set_ram(s + 1, 0)?;
+                s
+            }
+            "String.length" => ram(engine, 1)?,
+            "String.charAt" | "String.setCharAt" => {
+                if a[1] >= ram(engine, 1)? {
+                    bail!("{}: index {} out of range", name, arg(1));
+                }
+                let address = address(name, a[0], 2 + a[1] as u32)?;
+                if name == "String.setCharAt" {
+                    engine.set_ram(address, a[2])?;
+                    0
+                } else {
+                    engine.get_ram(address)?
+                }
+            }
+            "String.appendChar" => {
+                let len = ram(engine, 1)?;
+                if len >= ram(engine, 0)? {
+                    bail!("String.appendChar: string is full");
+                }
+                engine.set_ram(address(name, a[0], 2 + len as u32)?, a[1])?;
+                engine.set_ram(address(name, a[0], 1)?, len + 1)?;
+                a[0]
+            }
+            "String.eraseLastChar" => {
+                let len = ram(engine, 1)?;
+                if len == 0 {
+                    bail!("String.eraseLastChar: string is empty");
+                }
+                engine.set_ram(address(name, a[0], 1)?, len - 1)?;
+                0
+            }
+            "String.intValue" => {
+                let len = ram(engine, 1)?;
+                let mut value = 0i16;
+                let mut negative = false;
+                for i in 0..len {
+                    match ram(engine, 2 + i as u32)? {
+                        45 if i == 0 => negative = true,
+                        c @ 48..=57 => value = value.wrapping_mul(10).wrapping_add(c as i16 - 48),
+                        _ => break,
+                    }
+                }
+                (if negative {
+                    value.wrapping_neg()
+                } else {
+                    value
+                }) as u16
+            }
+            "String.setInt" => {
+                let text = arg(1).to_string();
+                if text.len() as u16 > ram(engine, 0)? {
+                    bail!("String.setInt: string too short for {}", text);
+                }
+                for (i, c) in text.bytes().enumerate() {
+                    engine.set_ram(address(name, a[0], 2 + i as u32)?, c as u16)?;
+                }
+                engine.set_ram(address(name, a[0], 1)?, text.len() as u16)?;
+                0
+            }
+            "String.backSpace" => BACKSPACE,
+            "String.doubleQuote" => DOUBLE_QUOTE,
+            "String.newLine" => NEWLINE,
+            "Output.printChar" => {
+                self.print_char(engine, a[0])?;
+                0
+            }
+            "Output.printString" => {
+                let len = ram(engine, 1)?;
+                for i in 0..len {
+                    let c = ram(engine, 2 + i as u32)?;
+                    self.print_char(engine, c)?;
+                }
+                0
+            }
+            "Output.printInt" => {
+                self.print(engine, &arg(0).to_string())?;
+                0
+            }
+            "Output.println" => {
+                self.print_char(engine, NEWLINE)?;
+                0
+            }
+            "Output.backSpace" => {
+                self.print_char(engine, BACKSPACE)?;
+                0
+            }
+            "Screen.init" => {
+                self.color = true;
+                0
+            }
+            "Screen.clearScreen" => {
+                engine.ram[SCREEN_START as usize..KEYBOARD as usize].fill(0);
+                engine.set_screen_dirty(true);
+                0
+            }
+            "Screen.setColor" => {
+                self.color = a[0] != 0;
+                0
+            }
+            "Screen.drawPixel" | "Screen.drawLine" | "Screen.drawRectangle" => {
+                let (x1, y1) = (arg(0), arg(1));
+                let (x2, y2) = if a.len() == 4 {
+                    (arg(2), arg(3))
+                } else {
+                    (x1, y1)
+                };
+                for (x, y) in [(x1, y1), (x2, y2)] {
+                    if !(0..512).contains(&x) || !(0..256).contains(&y) {
+                        bail!("{}: illegal coordinates ({}, {})", name, x, y);
+                    }
+                }
+                if name == "Screen.drawRectangle" {
+                    if x1 > x2 || y1 > y2 {
+                        bail!("{}: illegal rectangle", name);
+                    }
+                    for y in y1..=y2 {
+                        self.draw_line(engine, x1, y, x2, y);
+                    }
+                } else {
+                    self.draw_line(engine, x1, y1, x2, y2);
+                }
+                engine.set_screen_dirty(true);
+                0
+            }
+            "Screen.drawCircle" => {
+                let (x, y, r) = (arg(0), arg(1), arg(2));
+                if !(0..512).contains(&x) || !(0..256).contains(&y) {
+                    bail!("{}: illegal center ({}, {})", name, x, y);
+                }
+                if !(0..=181).contains(&r) {
+                    bail!("Screen.drawCircle: illegal radius {}", r);
+                }
+                for dy in -r..=r {
+                    let dx = ((r as i32 * r as i32 - dy as i32 * dy as i32) as f64).sqrt() as i16;
+                    self.draw_line(engine, x - dx, y + dy, x + dx, y + dy);
+                }
+                engine.set_screen_dirty(true);
+                0
+            }
+            "Keyboard.keyPressed" => engine.get_ram(KEYBOARD)?,
+            "Sys.wait" => 0,
+            "Sys.halt" | "Sys.error" => return Ok(Native::Halt),
+            _ => bail!("{} has no native implementation", name),
+        };
+        Ok(Native::Return(value))
+    }
+}
+
+impl HackSystem {
+    /// Turn the native OS on or off. Returns the functions now intercepted.
+    pub fn set_native_os(&mut self, enabled: bool) -> Vec<String> {
+        self.native_os.enabled = enabled;
+        self.refresh_native_os()
+    }
+
+    /// Point the engine's intercepts at the entry of every native function
+    /// in the PDB. Called whenever the PDB changes.
+    pub fn refresh_native_os(&mut self) -> Vec<String> {
+        self.engine.intercepts.clear();
+        if !self.native_os.enabled {
+            return Vec::new();
+        }
+        let mut names = Vec::new();
+        for symbol in &self.pdb.symbols {
+            if symbol.symbol_type == SymbolType::Func && arity(&symbol.name).is_some() {
+                self.engine.intercepts.insert(symbol.address);
+                names.push(symbol.name.clone());
+            }
+        }
+        names
+    }
+
+    /// Run the native function whose entry is at PC and return to its
+    /// caller. Returns a stop reason if it halts the program.
+    pub(crate) fn native_call(&mut self) -> Result<Option<StopReason>> {
+        let pc = self.engine.pc;
+        let Some((name, arity)) = self
+            .pdb
+            .symbols
+            .iter()
+            .filter(|s| s.symbol_type == SymbolType::Func && s.address == pc)
+            .find_map(|s| Some((s.name.clone(), arity(&s.name)?)))
+        else {
+            bail!("No native function at 0x{:04X}", pc);
+        };
+        let engine = &mut self.engine;
+        // a native call counts as one instruction of the function
+        engine.executed[pc as usize & 0x7fff] = true;
+        if let Some(profiler) = &mut engine.profiler {
+            profiler.record(pc, &engine.ram);
+        }
+        let arg = engine.ram[ARG];
+        // the arguments, and the return value that replaces them
+        if arg as u32 + arity.max(1) as u32 > 0x7fff {
+            bail!("{}: bad arguments (ARG=0x{:04X})", name, arg);
+        }
+        let args: Vec<u16> = (0..arity).map(|i| engine.ram[(arg + i) as usize]).collect();
+        let value = match self.native_os.call(engine, &name, &args)? {
+            Native::Return(value) => value,
+            Native::Halt => {
+                if name == "Sys.error" {
+                    self.messages
+                        .push(format!("Sys.error: error code {}", args[0] as i16));
+                }
+                return Ok(Some(StopReason::SysHalt));
+            }
+        };
+        // the call can write anywhere in RAM and changes the heap, none of
+        // which the history can undo, so reverse stepping stops here
+        engine.history.clear();
+
+        // the VM return sequence
+        let ram = &mut engine.ram;
+        let frame = ram[LCL] as usize;
+        if !(5..0x4000).contains(&frame) {
+            bail!("{}: bad frame (LCL=0x{:04X})", name, frame);
+        }
+        let ret = ram[frame - 5];
+        ram[arg as usize] = value;
+        ram[SP] = arg + 1;
+        ram[THAT] = ram[frame - 1];
+        ram[THIS] = ram[frame - 2];
+        ram[ARG] = ram[frame - 3];
+        ram[LCL] = ram[frame - 4];
+        engine.pc = ret;
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::pdb::database::{FileType, Symbol};
+    use std::path::{Path, PathBuf};
+    use web_time::Duration;
+
+    const MAIN: &str = "\
+function Sys.init 0
+    push constant 6
+    push constant 7
+    call Math.multiply 2
+    pop static 0
+    push constant 3
+    call String.new 1
+    push constant 45
+    call String.appendChar 2
+    push constant 52
+    call String.appendChar 2
+    pop static 1
+    push static 1
+    call String.intValue 1
+    pop static 2
+    push static 1
+    call Output.printString 1
+    pop temp 0
+    push constant 0
+    push constant 0
+    push constant 15
+    push constant 0
+    call Screen.drawLine 4
+    pop temp 0
+    call Sys.halt 0
+    pop temp 0
+";
+
+    #[test]
+    fn test_native_os_vm() {
+        let files = vec![(PathBuf::from("Sys.vm"), MAIN.to_string())];
+        let mut hs = HackSystem::new();
+        hs.load_vm(&files).unwrap();
+
+        // without the native OS, OS calls fail
+        let err = hs
+            .execute_instructions(Duration::from_secs(10))
+            .unwrap_err();
+        assert!(err.to_string().contains("Math.multiply"), "{}", err);
+
+        hs.load_vm(&files).unwrap();
+        let names = hs.set_native_os(true);
+        assert!(names.contains(&"String.appendChar".to_string()));
+        let stop = hs.execute_instructions(Duration::from_secs(10)).unwrap();
+        assert_eq!(stop, StopReason::SysHalt);
+        assert_eq!(hs.engine.ram[16], 42);
+        assert_eq!(hs.engine.ram[18] as i16, -4);
+        assert_eq!(hs.engine.take_output(), "-4");
+        assert_eq!(hs.engine.ram[SCREEN_START as usize], 0xffff);
+    }
+
+    #[test]
+    fn test_native_call_clears_history() {
+        // jump to a native Math.abs at 10 with a frame built by hand,
+        // returning to 4
+        let asm = "@7\nD=A\n@10\n0;JMP\n@R5\nM=D\n(END)\n@END\n0;JMP\n";
+        let mut hs = HackSystem::new();
+        hs.load_code(Path::new("Abs.asm"), asm).unwrap();
+        hs.pdb.symbols.push(Symbol {
+            symbol_type: SymbolType::Func,
+            name: "Math.abs".to_string(),
+            func_type: 0,
+            var_type: 0,
+            storage_class: 0,
+            size: 0,
+            address: 10,
+            instance_type: String::new(),
+            file_type: FileType::Jack,
+        });
+        hs.set_native_os(true);
+        let ram = &mut hs.engine.ram;
+        ram[ARG] = 256;
+        ram[256] = -3i16 as u16;
+        ram[LCL] = 262;
+        ram[SP] = 262;
+        ram[257] = 4;
+        let stop = hs.execute_instructions(Duration::from_secs(10)).unwrap();
+        assert_eq!(stop, StopReason::HardLoop);
+        assert_eq!(hs.engine.ram[5], 7);
+
+        // reverse stepping stops just after the call
+        while hs.engine.reverse_step() {}
+        assert_eq!(hs.engine.pc, 4);
+        assert_eq!(hs.engine.ram[256], 3);
+        assert_eq!(hs.engine.ram[5], 0);
+    }
+
+    #[test]
+    fn test_heap() {
+        let mut os = NativeOs::default();
+        let mut engine = HackEngine::new();
+        let a = os.alloc(&mut engine, 10).unwrap();
+        let b = os.alloc(&mut engine, 5).unwrap();
+        assert_eq!((a, b), (2049, 2060));
+        os.de_alloc(&engine, a).unwrap();
+        os.de_alloc(&engine, b).unwrap();
+        assert_eq!(os.free.len(), 1);
+        assert!(os.alloc(&mut engine, 0x4000).is_err());
+    }
+
+    #[test]
+    fn test_bad_pointers() {
+        let mut os = NativeOs::default();
+        let mut engine = HackEngine::new();
+        // a bad pointer or coordinate is an OS error, not a panic
+        for (name, args) in [
+            ("String.length", vec![0xffff]),
+            ("String.charAt", vec![0x7fff, 0]),
+            ("String.setCharAt", vec![32767, 0, 65]),
+            ("String.appendChar", vec![0xfffe, 65]),
+            ("String.setInt", vec![0x7ffe, 12]),
+            ("Output.printString", vec![0xffff]),
+            ("Screen.drawCircle", vec![32767, 10, 5]),
+            ("Screen.drawCircle", vec![10, 0xffff, 5]),
+        ] {
+            assert!(os.call(&mut engine, name, &args).is_err(), "{}", name);
+        }
+        engine.ram[0x7ffe] = 5;
+        engine.ram[0x7fff] = 5;
+        assert!(os.call(&mut engine, "String.charAt", &[0x7ffe, 3]).is_err());
+
+        // a block with a corrupt size word
+        let a = os.alloc(&mut engine, 10).unwrap();
+        engine.ram[a as usize - 1] = 0xffff;
+        assert!(os.de_alloc(&engine, a).is_err());
+    }
+
+    #[test]
+    fn test_heap_reset_on_load() {
+        let files = vec![(PathBuf::from("Sys.vm"), MAIN.to_string())];
+        let mut hs = HackSystem::new();
+        let engine = &mut hs.engine;
+        hs.native_os.alloc(engine, 100).unwrap();
+        hs.load_vm(&files).unwrap();
+        assert_eq!(hs.native_os.free, NativeOs::default().free);
+    }
+}
@@ -0,0 +1,144 @@
+use std::collections::BTreeMap;
+
+use super::debug_em::HackSystem;
+use anyhow::Result;
+use common::pdb::database::{FileType, Pdb, Symbol};
+#[derive(Debug, Clone)]
+#[allow(dead_code)]
+pub struct CodeLocation {
+    pub module: Option<i32>,
+    pub cfile: Option<usize>,
+    pub cline: usize,
+    pub ctext: Option<String>,
+
+    pub absaddr: u16,
+}
+
+impl HackSystem {
+    pub fn get_symbols(&self, name: &str) -> Result<Vec<Symbol>> {
+        Ok(self
+            .pdb
+            .symbols
+            .iter()
+            .filter(|s| s.name == name)
+            .cloned()
+            .collect())
+    }
+
+    pub fn where_are_we(&self, addr: u16) -> CodeLocation {
+        if let Some(cl) = self.waw.range(..=addr).next_back() {
+            cl.1.clone()
+        } else {
+            CodeLocation {
+                module: None,
+                cfile: None,
+                cline: 0,
+                ctext: None,
+                absaddr: addr,
+            }
+        }
+    }
+
+    /// Build the address -> source line map for the current PDB.
+    pub fn load_waw(&mut self) -> Result<()> {
+        self.waw = source_map(&self.pdb);
+        self.refresh_native_os();
+        Ok(())
+    }
+
+    /// `file:line  text` for the source line containing `addr`, if known.
+    pub fn source_location(&self, addr: u16) -> Option<String> {
+        let cl = self.where_are_we(addr);
+        let file = self.pdb.file_info.get(cl.cfile?)?;
+        let name = file.name.file_name().map_or_else(
+            || file.name.display().to_string(),
+            |n| n.to_string_lossy().into_owned(),
+        );
+        Some(format!(
+            "{}:{}  {}",
+            name,
+            cl.cline,
+            cl.ctext.as_deref().unwrap_or("").trim()
+        ))
+    }
+}
+
+/// The address -> source line map of `pdb`. Only lines of the highest-level
+/// source are used (Jack or C, else VM, else assembly), so a Jack line is
+/// not split up by the VM commands it compiles to. `source_lines` is one
+/// flat list with no per-file offsets, so the line text is only filled in
+/// for a single-file PDB.
+pub(crate) fn source_map(pdb: &Pdb) -> BTreeMap<u16, CodeLocation> {
+    let file_type = |file: usize| {
+        pdb.file_info
+            .get(file)
+            .map_or(FileType::Unknown, |f| f.file_type.clone())
+    };
+    let level = pdb
+        .source_map
+        .iter()
+        .map(|s| source_level(&file_type(s.file)))
+        .max()
+        .unwrap_or(0);
+    let single_file = pdb.file_info.len() <= 1;
+    let mut waw = BTreeMap::new();
+    for s in pdb.source_map.iter() {
+        if source_level(&file_type(s.file)) != level {
+            continue;
+        }
+        let cl = CodeLocation {
+            module: None,
+            cfile: Some(s.file),
+            cline: s.line_no,
+            ctext: s
+                .line_no
+                .checked_sub(1)
+                .filter(|_| single_file)
+                .and_then(|i| pdb.source_lines.get(i))
+                .cloned(),
+            absaddr: s.addr,
+        };
+        waw.insert(s.addr, cl);
+    }
+    waw
+}
+
+/// Ranking of source languages, highest level first when stepping.
+pub(crate) fn source_level(file_type: &FileType) -> u8 {
+    match file_type {
+        FileType::Jack | FileType::C => 3,
+        FileType::Vm => 2,
+        FileType::Asm => 1,
+        FileType::Unknown => 0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::pdb::database::{FileInfo, SourceMap};
+
+    #[test]
+    fn test_line_text() {
+        let mut pdb = Pdb::new();
+        pdb.source_lines = vec!["@1".to_string(), "D=A".to_string()];
+        pdb.file_info.push(FileInfo {
+            name: "A.asm".into(),
+            file_type: FileType::Asm,
+        });
+        pdb.source_map.push(SourceMap {
+            file: 0,
+            line_no: 2,
+            col_no: 0,
+            addr: 1,
+        });
+        assert_eq!(source_map(&pdb)[&1].ctext.as_deref(), Some("D=A"));
+
+        // with two files the flat list can't be indexed by line number
+        pdb.file_info.push(FileInfo {
+            name: "B.asm".into(),
+            file_type: FileType::Asm,
+        });
+        assert_eq!(source_map(&pdb)[&1].ctext, None);
+    }
+}
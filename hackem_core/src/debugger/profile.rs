@@ -0,0 +1,270 @@
+/*
+
+Profile reports
+
+Turns the raw counts of `emulator::profiler` into something readable:
+executions per function (self and total, named from the PDB Func
+symbols), the hottest addresses, and the folded-stack format used by
+flame graph tools (`inferno-flamegraph`, `flamegraph.pl`, speedscope):
+
+    Sys.init;Main.main;Screen.drawRectangle 123456
+
+one line per distinct call stack, outermost function first, followed by
+the number of instructions executed there. Frames without a Func symbol
+(such as the bootstrap code) are shown as `??`.
+
+*/
+
+use std::collections::{BTreeMap, HashMap};
+
+use anyhow::{bail, Result};
+use common::pdb::database::SymbolType;
+
+use super::debug_em::HackSystem;
+use crate::emulator::profiler::Profiler;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct FunctionProfile {
+    pub name: String,
+    /// Instructions executed in the function itself.
+    pub self_count: u64,
+    /// Instructions executed in the function and everything it called.
+    pub total_count: u64,
+}
+
+#[derive(Debug, Default)]
+pub struct ProfileReport {
+    pub total: u64,
+    /// Sorted by self count, highest first.
+    pub functions: Vec<FunctionProfile>,
+    /// Address, executions and containing function, highest first.
+    pub hotspots: Vec<(u16, u64, String)>,
+}
+
+impl std::fmt::Display for ProfileReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let percent = |count: u64| count as f64 * 100.0 / self.total.max(1) as f64;
+        writeln!(f, "{} instructions profiled", self.total)?;
+        writeln!(
+            f,
+            "{:>12} {:>6} {:>12} {:>6}  function",
+            "self", "%", "total", "%"
+        )?;
+        for func in &self.functions {
+            writeln!(
+                f,
+                "{:>12} {:>5.1}% {:>12} {:>5.1}%  {}",
+                func.self_count,
+                percent(func.self_count),
+                func.total_count,
+                percent(func.total_count),
+                func.name
+            )?;
+        }
+        if !self.hotspots.is_empty() {
+            writeln!(f, "Hottest addresses:")?;
+        }
+        for (addr, count, name) in &self.hotspots {
+            writeln!(f, "  0x{:04X} {:>12}  {}", addr, count, name)?;
+        }
+        Ok(())
+    }
+}
+
+/// Names addresses by the closest Func symbol at or below them.
+struct FunctionNames {
+    entries: Vec<(u16, String)>,
+    /// Addresses from here on are outside the program (VM builtins excepted).
+    end: u16,
+}
+
+impl FunctionNames {
+    fn name(&self, addr: u16) -> &str {
+        if addr >= self.end {
+            return "??";
+        }
+        match self.entries.partition_point(|(a, _)| *a <= addr) {
+            0 => "??",
+            i => &self.entries[i - 1].1,
+        }
+    }
+}
+
+impl HackSystem {
+    /// Start or stop counting executions. Stopping keeps the counts.
+    pub fn set_profiling(&mut self, on: bool) {
+        match &mut self.engine.profiler {
+            Some(profiler) => profiler.running = on,
+            None if on => self.engine.profiler = Some(Profiler::new()),
+            None => {}
+        }
+    }
+
+    pub fn profiling(&self) -> bool {
+        self.engine.profiler.as_ref().is_some_and(|p| p.running)
+    }
+
+    /// Throw away the counts, keeping profiling on if it was.
+    pub fn clear_profile(&mut self) {
+        if let Some(profiler) = &mut self.engine.profiler {
+            let running = profiler.running;
+            *profiler = Profiler::new();
+            profiler.running = running;
+        }
+    }
+
+    fn function_names(&self) -> FunctionNames {
+        let mut entries: Vec<(u16, String)> = self
+            .pdb
+            .symbols
+            .iter()
+            .filter(|s| s.symbol_type == SymbolType::Func)
+            .map(|s| (s.address, s.name.clone()))
+            .collect();
+        entries.sort();
+        // the VM bootstrap returns past the last command
+        let end = match &self.vm {
+            Some(vm) => vm.commands.len().min(0x8000) as u16,
+            None => u16::MAX,
+        };
+        FunctionNames { entries, end }
+    }
+
+    /// Executions per function and the `hotspots` most executed addresses.
+    pub fn profile(&self, hotspots: usize) -> ProfileReport {
+        let Some(profiler) = &self.engine.profiler else {
+            return ProfileReport::default();
+        };
+        let names = self.function_names();
+        let mut functions: HashMap<&str, FunctionProfile> = HashMap::new();
+        for (addr, &count) in profiler.hits.iter().enumerate() {
+            if count > 0 {
+                let name = names.name(addr as u16);
+                functions
+                    .entry(name)
+                    .or_insert_with(|| FunctionProfile {
+                        name: name.to_string(),
+                        self_count: 0,
+                        total_count: 0,
+                    })
+                    .self_count += count;
+            }
+        }
+        // a function counts once per stack, however deep it recurses
+        for (&(stack, pc), &count) in &profiler.stack_hits {
+            let mut seen: Vec<&str> = profiler.stacks[stack]
+                .iter()
+                .map(|&ret| names.name(ret))
+                .collect();
+            seen.push(names.name(pc));
+            seen.sort_unstable();
+            seen.dedup();
+            for name in seen {
+                if let Some(f) = functions.get_mut(name) {
+                    f.total_count += count;
+                }
+            }
+        }
+
+        let mut functions: Vec<FunctionProfile> = functions.into_values().collect();
+        functions.sort_by(|a, b| {
+            b.self_count
+                .cmp(&a.self_count)
+                .then_with(|| a.name.cmp(&b.name))
+        });
+        let mut hot: Vec<(u16, u64)> = profiler
+            .hits
+            .iter()
+            .enumerate()
+            .filter(|(_, &count)| count > 0)
+            .map(|(addr, &count)| (addr as u16, count))
+            .collect();
+        hot.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        ProfileReport {
+            total: profiler.total(),
+            functions,
+            hotspots: hot
+                .into_iter()
+                .take(hotspots)
+                .map(|(addr, count)| (addr, count, names.name(addr).to_string()))
+                .collect(),
+        }
+    }
+
+    /// The profile in folded-stack format, one line per call stack.
+    pub fn folded_stacks(&self) -> Result<String> {
+        let Some(profiler) = &self.engine.profiler else {
+            bail!("Nothing profiled; use 'profile on' first");
+        };
+        let names = self.function_names();
+        let mut folded: BTreeMap<String, u64> = BTreeMap::new();
+        for (&(stack, pc), &count) in &profiler.stack_hits {
+            let mut path: Vec<&str> = profiler.stacks[stack]
+                .iter()
+                .map(|&ret| names.name(ret))
+                .collect();
+            path.push(names.name(pc));
+            *folded.entry(path.join(";")).or_insert(0) += count;
+        }
+        Ok(folded
+            .iter()
+            .map(|(path, count)| format!("{} {}\n", path, count))
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use web_time::Duration;
+
+    const SYS: &str = "\
+function Sys.init 0
+    push constant 3
+    call Main.triple 1
+    pop temp 0
+    push constant 4
+    call Main.triple 1
+    pop temp 0
+";
+
+    const MAIN: &str = "\
+function Main.triple 0
+    push argument 0
+    push argument 0
+    push argument 0
+    add
+    add
+    return
+";
+
+    #[test]
+    fn test_profile() {
+        let files = vec![
+            (PathBuf::from("Main.vm"), MAIN.to_string()),
+            (PathBuf::from("Sys.vm"), SYS.to_string()),
+        ];
+        let mut hs = super::HackSystem::new();
+        hs.load_vm(&files).unwrap();
+        hs.set_profiling(true);
+        hs.execute_instructions(Duration::from_secs(10)).unwrap();
+
+        let report = hs.profile(3);
+        assert_eq!(report.total, 21);
+        assert_eq!(report.functions[0].name, "Main.triple");
+        assert_eq!(report.functions[0].self_count, 14);
+        assert_eq!(report.functions[1].name, "Sys.init");
+        assert_eq!(report.functions[1].self_count, 7);
+        assert_eq!(report.functions[1].total_count, 21);
+        assert_eq!(report.hotspots[0].1, 2);
+        assert_eq!(report.hotspots[0].2, "Main.triple");
+
+        let folded = hs.folded_stacks().unwrap();
+        assert_eq!(folded, "??;Sys.init 7\n??;Sys.init;Main.triple 14\n");
+
+        hs.clear_profile();
+        assert!(hs.profiling());
+        assert_eq!(hs.profile(3).total, 0);
+    }
+}
@@ -0,0 +1,289 @@
+/*
+
+VM segments
+
+Decodes the Jack VM memory segments of the selected call stack frame:
+
+    local     RAM[LCL + i]
+    argument  RAM[ARG + i]
+    this      RAM[THIS + i]
+    that      RAM[THAT + i]
+    pointer   RAM[3..=4]
+    temp      RAM[5..=12]
+    static    RAM[16..]
+
+Slots are named from PDB Var symbols whose `storage_class` uses the
+encoding in `common::pdb::database` (STORAGE_*), with the segment index in
+`address` (the RAM address for statics) and the number of words in `size`.
+hackem's own assembler and VM loader only record statics; locals, arguments
+and fields are named only if the PDB's producer follows the same encoding.
+Locals and arguments are named `Function.name.var` and fields `Class.var`,
+so they are matched to the frame by prefix.
+
+Without symbols the segment lengths come from the frame itself: arguments
+run from ARG up to the saved frame at LCL-5, locals from LCL up to the
+stack top (SP, or the callee's ARG for an outer frame). Nothing in memory
+gives the size of the objects `this` and `that` point at, so they show an
+arbitrary 8 slots (`this` more if field symbols cover more).
+
+*/
+
+use common::pdb::database::{
+    Symbol, SymbolType, STORAGE_ARGUMENT, STORAGE_FIELD, STORAGE_LOCAL, STORAGE_STATIC,
+};
+
+use super::callstack::Frame;
+use super::debug_em::HackSystem;
+
+const TEMP: u16 = 5;
+const STATIC: u16 = 16;
+/// Most slots shown for a segment whose length is not known.
+const MAX_SLOTS: u16 = 32;
+/// Slots shown for `this` / `that`, whose object size is unknown. Arbitrary.
+const OBJECT_SLOTS: u16 = 8;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Segment {
+    Local,
+    Argument,
+    This,
+    That,
+    Pointer,
+    Temp,
+    Static,
+}
+
+impl std::fmt::Display for Segment {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            Segment::Local => "local",
+            Segment::Argument => "argument",
+            Segment::This => "this",
+            Segment::That => "that",
+            Segment::Pointer => "pointer",
+            Segment::Temp => "temp",
+            Segment::Static => "static",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SegmentSlot {
+    pub index: u16,
+    pub address: u16,
+    pub value: u16,
+    pub name: Option<String>,
+}
+
+#[derive(Debug, Clone)]
+pub struct SegmentView {
+    pub segment: Segment,
+    /// RAM address of index 0.
+    pub base: u16,
+    pub slots: Vec<SegmentSlot>,
+}
+
+impl HackSystem {
+    /// All VM segments of the selected frame.
+    pub fn vm_segments(&self) -> Vec<SegmentView> {
+        let frames = self.backtrace();
+        let index = self.selected_frame_index().min(frames.len() - 1);
+        let frame = &frames[index];
+        let stack_top = match index {
+            0 => self.engine.ram[0],
+            _ => frames[index - 1].arg,
+        };
+        let function = frame.function.as_deref().unwrap_or("");
+        let class = function.split('.').next().unwrap_or("");
+
+        let locals = self.vars(STORAGE_LOCAL, function);
+        let args = self.vars(STORAGE_ARGUMENT, function);
+        let fields = self.vars(STORAGE_FIELD, class);
+
+        let local_len = Self::span(&locals).unwrap_or(stack_top.saturating_sub(frame.lcl));
+        let arg_len =
+            Self::span(&args).unwrap_or(frame.lcl.saturating_sub(frame.arg.saturating_add(5)));
+        let this_len = Self::span(&fields).unwrap_or(OBJECT_SLOTS);
+
+        let mut statics: Vec<&Symbol> = self
+            .pdb
+            .symbols
+            .iter()
+            .filter(|s| s.symbol_type == SymbolType::Var && s.storage_class == STORAGE_STATIC)
+            .collect();
+        statics.sort_by_key(|s| s.address);
+        let static_len = statics
+            .iter()
+            .map(|s| Self::end(s))
+            .max()
+            .map_or(16, |end| end.saturating_sub(STATIC));
+
+        vec![
+            self.segment(Segment::Local, frame.lcl, local_len, &locals, 0),
+            self.segment(Segment::Argument, frame.arg, arg_len, &args, 0),
+            self.segment(Segment::This, frame.this, this_len, &fields, 0),
+            self.segment(Segment::That, frame.that, OBJECT_SLOTS, &[], 0),
+            self.pointer_segment(frame),
+            self.segment(Segment::Temp, TEMP, 8, &[], 0),
+            self.segment(Segment::Static, STATIC, static_len, &statics, STATIC),
+        ]
+    }
+
+    /// Var symbols of one storage class whose name starts with `owner.`.
+    fn vars(&self, storage_class: i64, owner: &str) -> Vec<&Symbol> {
+        if owner.is_empty() {
+            return Vec::new();
+        }
+        let prefix = format!("{}.", owner);
+        self.pdb
+            .symbols
+            .iter()
+            .filter(|s| {
+                s.symbol_type == SymbolType::Var
+                    && s.storage_class == storage_class
+                    && s.name.starts_with(&prefix)
+                    && !s.name[prefix.len()..].contains('.')
+            })
+            .collect()
+    }
+
+    /// Number of slots covered by a set of symbols, if there are any.
+    fn span(vars: &[&Symbol]) -> Option<u16> {
+        vars.iter().map(|s| Self::end(s)).max()
+    }
+
+    /// One past the last slot of a symbol, saturating for a bad PDB.
+    fn end(s: &Symbol) -> u16 {
+        s.address
+            .saturating_add(s.size.clamp(1, u16::MAX as i64) as u16)
+    }
+
+    /// `origin` is subtracted from symbol addresses to get the slot index
+    /// (statics are recorded by RAM address).
+    fn segment(
+        &self,
+        segment: Segment,
+        base: u16,
+        len: u16,
+        vars: &[&Symbol],
+        origin: u16,
+    ) -> SegmentView {
+        let len = len.min(MAX_SLOTS.max(Self::span(vars).unwrap_or(0)));
+        let slots = (0..len)
+            .map(|index| {
+                let address = base.wrapping_add(index);
+                let name = vars.iter().find_map(|s| {
+                    let start = s.address.wrapping_sub(origin);
+                    let offset = index.wrapping_sub(start);
+                    let short = s.name.rsplit('.').next().unwrap_or(&s.name);
+                    match (offset, s.size) {
+                        (0, size) if size <= 1 => Some(short.to_string()),
+                        (o, size) if (o as i64) < size => Some(format!("{}[{}]", short, o)),
+                        _ => None,
+                    }
+                });
+                SegmentSlot {
+                    index,
+                    address,
+                    value: self.engine.ram.get(address as usize).copied().unwrap_or(0),
+                    name,
+                }
+            })
+            .collect();
+        SegmentView {
+            segment,
+            base,
+            slots,
+        }
+    }
+
+    fn pointer_segment(&self, frame: &Frame) -> SegmentView {
+        let slot = |index: u16, value: u16, name: &str| SegmentSlot {
+            index,
+            address: 3 + index,
+            value,
+            name: Some(name.to_string()),
+        };
+        SegmentView {
+            segment: Segment::Pointer,
+            base: 3,
+            slots: vec![slot(0, frame.this, "this"), slot(1, frame.that, "that")],
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::pdb::database::FileType;
+
+    fn var(name: &str, storage_class: i64, address: u16, size: i64) -> Symbol {
+        Symbol {
+            symbol_type: SymbolType::Var,
+            name: name.to_string(),
+            func_type: 0,
+            var_type: 0,
+            storage_class,
+            size,
+            address,
+            instance_type: String::new(),
+            file_type: FileType::Jack,
+        }
+    }
+
+    #[test]
+    fn test_vm_segments() {
+        let mut hacksys = HackSystem::new();
+        hacksys.pdb.symbols.push(Symbol {
+            symbol_type: SymbolType::Func,
+            ..var("Main.main", 0, 0x10, 0)
+        });
+        hacksys
+            .pdb
+            .symbols
+            .push(var("Main.main.x", STORAGE_LOCAL, 0, 1));
+        hacksys
+            .pdb
+            .symbols
+            .push(var("Main.main.buf", STORAGE_LOCAL, 1, 2));
+        hacksys
+            .pdb
+            .symbols
+            .push(var("Main.count", STORAGE_STATIC, 16, 1));
+        hacksys
+            .pdb
+            .symbols
+            .push(var("Other.main.y", STORAGE_LOCAL, 0, 1));
+
+        hacksys.engine.pc = 0x20;
+        hacksys.engine.ram[0] = 270; // SP
+        hacksys.engine.ram[1] = 261; // LCL
+        hacksys.engine.ram[2] = 256; // ARG: no arguments
+        hacksys.engine.ram[261] = 7;
+        hacksys.engine.ram[16] = 3;
+
+        let segments = hacksys.vm_segments();
+        let local = &segments[0];
+        assert_eq!(local.segment, Segment::Local);
+        let names: Vec<_> = local.slots.iter().map(|s| s.name.as_deref()).collect();
+        assert_eq!(names, [Some("x"), Some("buf[0]"), Some("buf[1]")]);
+        assert_eq!((local.slots[0].address, local.slots[0].value), (261, 7));
+        assert!(segments[1].slots.is_empty());
+        assert_eq!(segments[5].slots[0].address, 5);
+        let statics = &segments[6];
+        assert_eq!(statics.slots.len(), 1);
+        assert_eq!(statics.slots[0].name.as_deref(), Some("count"));
+        assert_eq!(statics.slots[0].value, 3);
+
+        // garbage registers and sizes are shown, not a panic
+        hacksys.engine.ram[1] = 0xfff0;
+        hacksys.engine.ram[2] = 0xfffc;
+        hacksys
+            .pdb
+            .symbols
+            .push(var("Main.main.z", STORAGE_LOCAL, 0xfff0, 0x7fff_ffff));
+        let segments = hacksys.vm_segments();
+        assert!(segments[1].slots.is_empty());
+    }
+}
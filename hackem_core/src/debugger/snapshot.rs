@@ -0,0 +1,207 @@
+/*
+
+Whole machine snapshots
+
+save_state / load_state write and read everything needed to resume a
+session later: CPU registers, RAM, ROM, halt address, breakpoints,
+watchpoints, pending output port bytes, the native OS state and the
+loaded PDB. VM mode is not saved: the VM program lives outside the machine,
+so save it by reloading the .vm files instead.
+
+The file is JSON with a format tag and a version number. Loading checks
+both before touching the machine so an old or foreign file is rejected
+rather than half loaded. Bump SNAPSHOT_VERSION whenever the layout of
+Snapshot changes.
+
+*/
+
+use std::collections::BTreeMap;
+
+use anyhow::{bail, Result};
+use common::pdb::database::Pdb;
+use serde::{Deserialize, Serialize};
+
+use super::{debug_em::HackSystem, native_os::NativeOs, pdbio::source_map};
+use crate::emulator::{
+    devices::OutputPort,
+    engine::{BreakPoint, WatchPoint},
+};
+
+const SNAPSHOT_FORMAT: &str = "hackem-snapshot";
+const SNAPSHOT_VERSION: u32 = 3;
+
+/// Just enough of a snapshot to decide whether the rest can be read.
+#[derive(Deserialize)]
+struct SnapshotHeader {
+    format: String,
+    version: u32,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Snapshot {
+    format: String,
+    version: u32,
+    pc: u16,
+    a: u16,
+    d: u16,
+    ram: Vec<u16>,
+    rom: Vec<u16>,
+    halt_addr: u16,
+    rom_words_loaded: usize,
+    ram_words_loaded: usize,
+    break_points: BTreeMap<u16, BreakPoint>,
+    watch_points: BTreeMap<u16, WatchPoint>,
+    output: Vec<u8>,
+    native_os: NativeOs,
+    pdb: Pdb,
+}
+
+impl HackSystem {
+    /// Serialize the whole machine to a JSON snapshot.
+    pub fn save_state(&self) -> Result<String> {
+        if self.vm_mode() {
+            bail!("snapshots of VM mode are not supported");
+        }
+        let engine = &self.engine;
+        let snapshot = Snapshot {
+            format: SNAPSHOT_FORMAT.to_string(),
+            version: SNAPSHOT_VERSION,
+            pc: engine.pc,
+            a: engine.a,
+            d: engine.d,
+            ram: engine.ram.to_vec(),
+            rom: engine.rom.to_vec(),
+            halt_addr: engine.halt_addr,
+            rom_words_loaded: engine.rom_words_loaded,
+            ram_words_loaded: engine.ram_words_loaded,
+            break_points: engine.break_points.clone(),
+            watch_points: engine.watch_points.clone(),
+            output: engine
+                .devices
+                .get::<OutputPort>()
+                .map(|port| port.buffer.clone())
+                .unwrap_or_default(),
+            native_os: self.native_os.clone(),
+            pdb: self.pdb.clone(),
+        };
+        Ok(serde_json::to_string(&snapshot)?)
+    }
+
+    /// Replace the whole machine with a snapshot made by `save_state`.
+    /// Nothing is changed if the snapshot is rejected.
+    pub fn load_state(&mut self, json: &str) -> Result<()> {
+        let header: SnapshotHeader = serde_json::from_str(json)?;
+        if header.format != SNAPSHOT_FORMAT {
+            bail!("not a hackem snapshot (format '{}')", header.format);
+        }
+        if header.version != SNAPSHOT_VERSION {
+            bail!(
+                "unsupported snapshot version {} (expected {})",
+                header.version,
+                SNAPSHOT_VERSION
+            );
+        }
+        let snapshot: Snapshot = serde_json::from_str(json)?;
+        let engine = &mut self.engine;
+        if snapshot.ram.len() != engine.ram.len() || snapshot.rom.len() != engine.rom.len() {
+            bail!("snapshot memory size does not match");
+        }
+        let waw = source_map(&snapshot.pdb);
+
+        // nothing can fail from here on, so the machine is never half loaded
+        engine.pc = snapshot.pc;
+        engine.a = snapshot.a;
+        engine.d = snapshot.d;
+        engine.ram.copy_from_slice(&snapshot.ram);
+        engine.rom.copy_from_slice(&snapshot.rom);
+        engine.halt_addr = snapshot.halt_addr;
+        engine.rom_words_loaded = snapshot.rom_words_loaded;
+        engine.ram_words_loaded = snapshot.ram_words_loaded;
+        engine.break_points = snapshot.break_points;
+        engine.watch_points = snapshot.watch_points;
+        if let Some(port) = engine.devices.get_mut::<OutputPort>() {
+            port.buffer = snapshot.output;
+        }
+        engine.triggered_watchpoint = None;
+        engine.triggered_breakpoint = None;
+        engine.history.clear();
+        engine.speed = 0.0;
+        engine.set_screen_dirty(true);
+
+        self.vm = None;
+        self.native_os = snapshot.native_os;
+        self.pdb = snapshot.pdb;
+        self.waw = waw;
+        self.refresh_native_os();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_snapshot_round_trip() {
+        let mut hacksys = HackSystem::new();
+        hacksys.engine.pc = 0x0123;
+        hacksys.engine.a = 0x4000;
+        hacksys.engine.d = 0xffff;
+        hacksys.engine.ram[256] = 42;
+        hacksys.engine.ram[0x4000] = 0x8001;
+        hacksys.engine.rom[7] = 0xea87;
+        hacksys.engine.halt_addr = 0x33;
+        hacksys.engine.add_breakpoint(0x10);
+        hacksys.engine.add_watchpoint(0x20, false, true);
+        hacksys.engine.set_ram(0x7fff, b'h' as u16).unwrap();
+        let json = hacksys.save_state().unwrap();
+
+        let mut restored = HackSystem::new();
+        restored.load_state(&json).unwrap();
+        assert_eq!(restored.engine.get_registers(), (0x0123, 0x4000, 0xffff));
+        assert_eq!(restored.engine.ram[256], 42);
+        assert_eq!(restored.engine.ram[0x4000], 0x8001);
+        assert_eq!(restored.engine.rom[7], 0xea87);
+        assert_eq!(restored.engine.halt_addr, 0x33);
+        assert!(restored.engine.break_points.contains_key(&0x10));
+        assert!(restored.engine.watch_points[&0x20].write);
+        assert_eq!(restored.engine.take_output(), "h");
+    }
+
+    #[test]
+    fn test_snapshot_vm_mode() {
+        let files = vec![(
+            std::path::PathBuf::from("Sys.vm"),
+            "function Sys.init 0\npush constant 1\n".to_string(),
+        )];
+        let mut hacksys = HackSystem::new();
+        hacksys.load_vm(&files).unwrap();
+        assert!(hacksys.save_state().is_err());
+
+        // loading a Hack snapshot leaves VM mode
+        let json = HackSystem::new().save_state().unwrap();
+        hacksys.load_state(&json).unwrap();
+        assert!(!hacksys.vm_mode());
+    }
+
+    #[test]
+    fn test_snapshot_rejects_other_versions() {
+        let hacksys = HackSystem::new();
+        let json = hacksys.save_state().unwrap();
+        let old = json.replacen(
+            &format!("\"version\":{}", SNAPSHOT_VERSION),
+            "\"version\":0",
+            1,
+        );
+        let mut restored = HackSystem::new();
+        restored.engine.pc = 5;
+        assert!(restored.load_state(&old).is_err());
+        assert_eq!(restored.engine.pc, 5);
+        assert!(restored.load_state("{\"pc\":1}").is_err());
+
+        // a bad field after the memory does not half load the snapshot
+        let bad = json.replacen("\"pdb\":{", "\"pdb\":{\"symbols\":7,", 1);
+        assert!(restored.load_state(&bad).is_err());
+        assert_eq!(restored.engine.pc, 5);
+    }
+}
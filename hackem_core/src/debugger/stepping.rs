@@ -0,0 +1,333 @@
+/*
+
+Step over, finish and until
+
+These understand the calling convention emitted by the nand2tetris VM
+translator. A `call f n` becomes
+
+    @RET        return address
+    D=A
+    ...         push RET, LCL, ARG, THIS, THAT; ARG = SP-n-5; LCL = SP
+    @f
+    0;JMP
+    (RET)
+
+(a shared call helper that takes the return address in D looks the same
+from the call site), so a call starts with `@X; D=A` where the instruction
+before X is `0;JMP`. So does `push constant X` when a jump happens to end
+at X, so the instructions in between must also either write LCL (the
+inline frame save) or load the address of a PDB `Func` (the callee, passed
+to the helper or jumped to). Inside a function the frame saved below LCL is
+
+    RAM[LCL-5]  return address
+    RAM[LCL-4]  caller's LCL
+    RAM[LCL-3]  caller's ARG
+    RAM[LCL-2]  caller's THIS
+    RAM[LCL-1]  caller's THAT
+
+In VM mode a call is simply a `call` command and returns to the next one.
+
+Each command sets an address in `engine.run_to` and, for calls, the LCL the
+caller will have when it gets back there, so a recursive call returning to
+the same address in a deeper frame does not stop.
+
+Source stepping (`step`, `next`) runs to the start of any line in the
+source map (`waw`) other than the current one. `next` also ignores lines
+reached in deeper frames, i.e. with LCL above the current function's, since
+the stack grows upwards.
+
+*/
+
+use anyhow::{bail, Result};
+use common::pdb::database::SymbolType;
+
+use super::debug_em::HackSystem;
+use super::vm::VmOp;
+
+pub const SP: usize = 0;
+pub const LCL: usize = 1;
+pub const ARG: usize = 2;
+pub const THIS: usize = 3;
+pub const THAT: usize = 4;
+
+const JMP: u16 = 0xea87; // 0;JMP
+const D_EQ_A: u16 = 0xec10; // D=A
+/// Longest call sequence recognised, from `@RET` to the return address.
+const MAX_CALL_LEN: u16 = 64;
+
+#[derive(Default)]
+pub(crate) struct RunGoal {
+    /// LCL must have this value at the run-to address.
+    lcl: Option<u16>,
+    /// LCL must be at most this (not in a function called from here).
+    max_lcl: Option<u16>,
+    /// Source line (file, line) being stepped off.
+    from_line: Option<(Option<usize>, usize)>,
+}
+
+impl HackSystem {
+    /// If the instruction at PC starts a VM call sequence, its return address.
+    pub fn call_return_address(&self) -> Option<u16> {
+        let pc = self.engine.pc;
+        if let Some(vm) = &self.vm {
+            return match vm.commands.get(pc as usize)?.op {
+                VmOp::Call { .. } => Some(pc + 1),
+                _ => None,
+            };
+        }
+        let rom = &self.engine.rom;
+        let ret = *rom.get(pc as usize)?;
+        if ret & 0x8000 != 0 || *rom.get(pc as usize + 1)? != D_EQ_A {
+            return None;
+        }
+        if ret <= pc + 2 || ret - pc > MAX_CALL_LEN || rom[ret as usize - 1] != JMP {
+            return None;
+        }
+        let body = &rom[pc as usize + 2..ret as usize - 1];
+        let writes_lcl = body
+            .windows(2)
+            .any(|w| w[0] == LCL as u16 && w[1] & 0x8008 == 0x8008);
+        // @SP..@THAT are registers here, not functions
+        let loads_func = body.iter().any(|&word| {
+            word > THAT as u16
+                && self
+                    .pdb
+                    .symbols
+                    .iter()
+                    .any(|s| s.symbol_type == SymbolType::Func && s.address == word)
+        });
+        (writes_lcl || loads_func).then_some(ret)
+    }
+
+    /// Start running to the end of the call at PC. Returns false if PC is
+    /// not at a call, in which case nothing is set up.
+    pub fn step_over(&mut self) -> bool {
+        match self.call_return_address() {
+            Some(ret) => {
+                let lcl = self.engine.ram[LCL];
+                self.set_goal(ret, Some(lcl));
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Start running until the current function returns to its caller.
+    pub fn finish(&mut self) -> Result<()> {
+        let lcl = self.engine.ram[LCL];
+        if !(5..0x4000).contains(&lcl) {
+            bail!("Not inside a VM function (LCL=0x{:04X})", lcl);
+        }
+        let ret = self.engine.ram[lcl as usize - 5];
+        let caller_lcl = self.engine.ram[lcl as usize - 4];
+        self.set_goal(ret, Some(caller_lcl));
+        Ok(())
+    }
+
+    /// Start running until PC reaches `address`.
+    pub fn run_until(&mut self, address: u16) {
+        self.set_goal(address, None);
+    }
+
+    /// Start running to the next source line. With `over`, lines in
+    /// functions called from the current one are skipped.
+    pub fn step_line(&mut self, over: bool) -> Result<()> {
+        if self.waw.is_empty() {
+            bail!("No source map loaded");
+        }
+        let pc = self.engine.pc;
+        let from_line = self
+            .waw
+            .range(..=pc)
+            .next_back()
+            .map(|(_, cl)| (cl.cfile, cl.cline));
+        let lcl = self.engine.ram[LCL];
+        self.clear_goal();
+        self.engine.run_to = self.waw.keys().copied().collect();
+        self.goal = Some(RunGoal {
+            max_lcl: if over && lcl != 0 { Some(lcl) } else { None },
+            from_line,
+            ..Default::default()
+        });
+        Ok(())
+    }
+
+    fn set_goal(&mut self, address: u16, lcl: Option<u16>) {
+        self.clear_goal();
+        self.engine.run_to.insert(address);
+        self.goal = Some(RunGoal {
+            lcl,
+            ..Default::default()
+        });
+    }
+
+    /// Cancel a pending step over / finish / until.
+    pub fn clear_goal(&mut self) {
+        self.engine.run_to.clear();
+        self.goal = None;
+    }
+
+    /// Called on `StopReason::RunTo`: true if this is the frame we want.
+    pub(crate) fn goal_reached(&self) -> bool {
+        let Some(goal) = &self.goal else {
+            return true;
+        };
+        let lcl = self.engine.ram[LCL];
+        if goal.lcl.is_some_and(|l| lcl != l) || goal.max_lcl.is_some_and(|l| lcl > l) {
+            return false;
+        }
+        match (goal.from_line, self.waw.get(&self.engine.pc)) {
+            (Some(from), Some(cl)) => (cl.cfile, cl.cline) != from,
+            _ => true,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emulator::engine::StopReason;
+    use common::pdb::database::{FileType, Symbol};
+    use web_time::Duration;
+
+    #[test]
+    fn test_step_over_call() {
+        let mut hacksys = HackSystem::new();
+        hacksys.pdb.symbols.push(Symbol {
+            symbol_type: SymbolType::Func,
+            name: "f".to_string(),
+            func_type: 0,
+            var_type: 0,
+            storage_class: 0,
+            size: 0,
+            address: 10,
+            instance_type: String::new(),
+            file_type: FileType::Jack,
+        });
+        let rom = &mut hacksys.engine.rom;
+        rom[0] = 0x0005; // @RET
+        rom[1] = 0xec10; // D=A
+        rom[2] = 0x000a; // @f
+        rom[3] = 0x000a; // @f
+        rom[4] = 0xea87; // 0;JMP
+        rom[10] = 0xe320; // f: A=D
+        rom[11] = 0xea87; // 0;JMP
+
+        assert_eq!(hacksys.call_return_address(), Some(5));
+        assert!(hacksys.step_over());
+        let stop = hacksys
+            .execute_instructions(Duration::from_secs(10))
+            .unwrap();
+        assert_eq!(stop, StopReason::RunTo);
+        assert_eq!(hacksys.engine.pc, 5);
+        assert!(hacksys.engine.run_to.is_empty());
+        assert!(!hacksys.step_over());
+    }
+
+    #[test]
+    fn test_push_is_not_a_call() {
+        let mut hacksys = HackSystem::new();
+        let rom = &mut hacksys.engine.rom;
+        rom[0] = 0x0007; // @7      push constant 7
+        rom[1] = 0xec10; // D=A
+        rom[2] = 0x0000; // @SP
+        rom[3] = 0xfc20; // A=M
+        rom[4] = 0xe308; // M=D
+        rom[5] = 0x0014; // @20
+        rom[6] = 0xea87; // 0;JMP
+        assert_eq!(hacksys.call_return_address(), None);
+        assert!(!hacksys.step_over());
+
+        // with a write to LCL in between it is a call
+        hacksys.engine.rom[3] = 0x0001; // @LCL
+        assert_eq!(hacksys.call_return_address(), Some(7));
+    }
+
+    #[test]
+    fn test_finish_checks_frame() {
+        let mut hacksys = HackSystem::new();
+        let rom = &mut hacksys.engine.rom;
+        rom[0] = 0x0014; // @20
+        rom[1] = 0xea87; // 0;JMP
+        rom[20] = 0x00fa; // @250
+        rom[21] = 0xec10; // D=A
+        rom[22] = 0x0001; // @LCL
+        rom[23] = 0xe308; // M=D
+        rom[24] = 0x0014; // @20
+        rom[25] = 0xea87; // 0;JMP
+                          // frame of the current function: returns to 20 with LCL=250
+        hacksys.engine.ram[LCL] = 300;
+        hacksys.engine.ram[295] = 20;
+        hacksys.engine.ram[296] = 250;
+
+        hacksys.finish().unwrap();
+        let stop = hacksys
+            .execute_instructions(Duration::from_secs(10))
+            .unwrap();
+        assert_eq!(stop, StopReason::RunTo);
+        assert_eq!(hacksys.engine.pc, 20);
+        assert_eq!(hacksys.engine.instruction_count(), 8);
+
+        hacksys.engine.ram[LCL] = 0;
+        assert!(hacksys.finish().is_err());
+    }
+
+    #[test]
+    fn test_step_and_next_by_line() {
+        use common::pdb::database::{FileInfo, SourceMap};
+
+        let mut hacksys = HackSystem::new();
+        let rom = &mut hacksys.engine.rom;
+        // line 1: call f, line 2: loop; f is line 5
+        rom[0] = 0x0006; // @RET
+        rom[1] = 0xec10; // D=A
+        rom[2] = 0x0001; // @LCL
+        rom[3] = 0xfdc8; // M=M+1   new frame
+        rom[4] = 0x000a; // @f
+        rom[5] = 0xea87; // 0;JMP
+        rom[6] = 0x0006; // RET: @RET
+        rom[7] = 0xea87; // 0;JMP
+        rom[10] = 0x0001; // f: @LCL
+        rom[11] = 0xfc88; // M=M-1   back to the caller's frame
+        rom[12] = 0xe320; // A=D
+        rom[13] = 0xea87; // 0;JMP
+        hacksys.engine.ram[LCL] = 300;
+        hacksys.pdb.file_info.push(FileInfo {
+            name: "Main.jack".into(),
+            file_type: FileType::Jack,
+        });
+        hacksys.pdb.source_lines = ["do f();", "while (true) {}", "", "", "let x = x + 1;"]
+            .map(String::from)
+            .to_vec();
+        for (addr, line_no) in [(0, 1), (6, 2), (10, 5)] {
+            hacksys.pdb.source_map.push(SourceMap {
+                file: 0,
+                line_no,
+                col_no: 0,
+                addr,
+            });
+        }
+        hacksys.load_waw().unwrap();
+
+        hacksys.step_line(false).unwrap();
+        let stop = hacksys
+            .execute_instructions(Duration::from_secs(10))
+            .unwrap();
+        assert_eq!(stop, StopReason::RunTo);
+        assert_eq!(hacksys.engine.pc, 10);
+        assert_eq!(
+            hacksys.source_location(10).unwrap(),
+            "Main.jack:5  let x = x + 1;"
+        );
+
+        // next from line 1 skips f, which runs with a deeper LCL
+        hacksys.engine.pc = 0;
+        hacksys.engine.ram[LCL] = 300;
+        hacksys.step_line(true).unwrap();
+        let stop = hacksys
+            .execute_instructions(Duration::from_secs(10))
+            .unwrap();
+        assert_eq!(stop, StopReason::RunTo);
+        assert_eq!(hacksys.engine.pc, 6);
+    }
+}
@@ -0,0 +1,627 @@
+/*
+
+Test scripts
+
+Runs nand2tetris CPUEmulator test scripts (`.tst`) against the engine,
+the way every project is graded:
+
+    load Max.hack,
+    output-file Max.out,
+    compare-to Max.cmp,
+    output-list RAM[0]%D2.6.2 RAM[1]%D2.6.2 RAM[2]%D2.6.2;
+    set RAM[0] 3, set RAM[1] 5;
+    repeat 14 {
+        ticktock;
+    }
+    output;
+
+Supported commands are `load`, `output-file`, `compare-to`, `output-list`,
+`set` (RAM[n], PC, A, D), `repeat n { .. }`, `ticktock`, `output`, `echo`
+and `clear-echo`; `,` and `;` both end a command. Paths are relative to
+the script. `load` goes through `HackSystem::load_code`, so `.asm` works
+as well as `.hack` / `.hx`.
+
+Output columns are `name%Fp.w.q`: format letter B, D, X or S, then left
+padding, value width and right padding. Each output line is compared with
+the same line of the compare file as it is produced (a `*` in the compare
+file matches any character) and the script stops at the first mismatch.
+
+*/
+
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, bail, Result};
+use web_time::Duration;
+
+use super::debug_em::HackSystem;
+
+#[derive(Debug, Clone, PartialEq)]
+enum Var {
+    Ram(u16),
+    Pc,
+    A,
+    D,
+    Time,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Column {
+    name: String,
+    var: Var,
+    format: char,
+    left: usize,
+    width: usize,
+    right: usize,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Command {
+    Load(String),
+    OutputFile(String),
+    CompareTo(String),
+    OutputList(Vec<Column>),
+    Set(Var, u16),
+    TickTock,
+    Output,
+    Echo(String),
+    ClearEcho,
+    Repeat(u32, Vec<Statement>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Statement {
+    line: usize,
+    command: Command,
+}
+
+/// First mismatch between the output and the compare file.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TestFailure {
+    /// 1-based line of the output / compare file.
+    pub line: usize,
+    pub expected: String,
+    pub actual: String,
+    /// Script line of the `output` (or `output-list`) that produced it.
+    pub script_line: usize,
+}
+
+#[derive(Debug, Default)]
+pub struct TestReport {
+    /// Lines written to the output file.
+    pub output: Vec<String>,
+    pub output_file: Option<PathBuf>,
+    pub compare_file: Option<PathBuf>,
+    /// Text of `echo` commands, in order.
+    pub echoes: Vec<String>,
+    pub failure: Option<TestFailure>,
+}
+
+impl TestReport {
+    pub fn passed(&self) -> bool {
+        self.failure.is_none()
+    }
+}
+
+impl std::fmt::Display for TestReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match (&self.failure, &self.compare_file) {
+            (Some(fail), _) => write!(
+                f,
+                "Comparison failure at line {} (script line {})\n  expected: {}\n  actual:   {}",
+                fail.line, fail.script_line, fail.expected, fail.actual
+            ),
+            (None, Some(_)) => write!(
+                f,
+                "End of script - Comparison ended successfully ({} lines)",
+                self.output.len()
+            ),
+            (None, None) => write!(f, "End of script ({} output lines)", self.output.len()),
+        }
+    }
+}
+
+/// Split a script into (token, line) pairs. Words run up to whitespace or
+/// one of `,;{}`, which are tokens themselves; `"..."` is one token.
+fn tokenize(script: &str) -> Result<Vec<(String, usize)>> {
+    let mut tokens = Vec::new();
+    let mut chars = script.chars().peekable();
+    let mut line = 1;
+    while let Some(c) = chars.next() {
+        match c {
+            '\n' => line += 1,
+            c if c.is_whitespace() => {}
+            '/' if chars.peek() == Some(&'/') => {
+                while chars.peek().is_some_and(|&c| c != '\n') {
+                    chars.next();
+                }
+            }
+            '/' if chars.peek() == Some(&'*') => {
+                chars.next();
+                let mut last = ' ';
+                loop {
+                    let Some(c) = chars.next() else {
+                        bail!("line {}: unterminated comment", line);
+                    };
+                    if c == '\n' {
+                        line += 1;
+                    }
+                    if last == '*' && c == '/' {
+                        break;
+                    }
+                    last = c;
+                }
+            }
+            ',' | ';' | '{' | '}' => tokens.push((c.to_string(), line)),
+            '"' => {
+                let mut text = String::from("\"");
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some('\n') | None => bail!("line {}: unterminated string", line),
+                        Some(c) => text.push(c),
+                    }
+                }
+                tokens.push((text, line));
+            }
+            c => {
+                let mut word = c.to_string();
+                while let Some(&c) = chars.peek() {
+                    if c.is_whitespace() || ",;{}".contains(c) {
+                        break;
+                    }
+                    word.push(c);
+                    chars.next();
+                }
+                tokens.push((word, line));
+            }
+        }
+    }
+    Ok(tokens)
+}
+
+fn parse_var(name: &str) -> Result<Var> {
+    Ok(match name {
+        "PC" => Var::Pc,
+        "A" => Var::A,
+        "D" => Var::D,
+        "time" => Var::Time,
+        _ => {
+            let index = name
+                .strip_prefix("RAM[")
+                .and_then(|n| n.strip_suffix(']'))
+                .ok_or_else(|| anyhow!("unknown variable '{}'", name))?;
+            let index = index
+                .parse::<u16>()
+                .ok()
+                .filter(|&n| n < 0x8000)
+                .ok_or_else(|| anyhow!("invalid RAM address '{}'", index))?;
+            Var::Ram(index)
+        }
+    })
+}
+
+/// A value in script notation: decimal, `%D`, `%X` or `%B` prefixed.
+fn parse_value(text: &str) -> Result<u16> {
+    let (radix, digits) = match text.get(..2) {
+        Some("%X") => (16, &text[2..]),
+        Some("%B") => (2, &text[2..]),
+        Some("%D") => (10, &text[2..]),
+        _ => (10, text),
+    };
+    let value = if radix == 10 {
+        digits
+            .parse::<i32>()
+            .ok()
+            .filter(|v| (-32768..=65535).contains(v))
+    } else {
+        i32::from_str_radix(digits, radix)
+            .ok()
+            .filter(|v| *v <= 0xffff)
+    };
+    value
+        .map(|v| v as u16)
+        .ok_or_else(|| anyhow!("invalid value '{}'", text))
+}
+
+fn parse_column(spec: &str) -> Result<Column> {
+    let (name, format) = spec.split_once('%').unwrap_or((spec, "D1.6.1"));
+    let invalid = || anyhow!("invalid output format '{}'", spec);
+    let mut chars = format.chars();
+    let letter = chars
+        .next()
+        .filter(|c| "BDXS".contains(*c))
+        .ok_or_else(invalid)?;
+    let sizes = chars
+        .as_str()
+        .split('.')
+        .map(|n| n.parse::<usize>())
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|_| invalid())?;
+    let [left, width, right] = sizes[..] else {
+        return Err(invalid());
+    };
+    Ok(Column {
+        name: name.to_string(),
+        var: parse_var(name)?,
+        format: letter,
+        left,
+        width,
+        right,
+    })
+}
+
+/// Parse statements up to the end of the tokens or a closing `}`.
+fn parse_block(
+    tokens: &mut std::iter::Peekable<std::vec::IntoIter<(String, usize)>>,
+    nested: bool,
+) -> Result<Vec<Statement>> {
+    let mut statements = Vec::new();
+    while let Some((word, line)) = tokens.next() {
+        let at = |e: anyhow::Error| anyhow!("line {}: {}", line, e);
+        let mut args = Vec::new();
+        let command = match word.as_str() {
+            "," | ";" => continue,
+            "}" if nested => return Ok(statements),
+            "repeat" => {
+                let count = match tokens.next() {
+                    Some((n, _)) => n
+                        .parse::<u32>()
+                        .map_err(|_| at(anyhow!("invalid repeat count '{}'", n)))?,
+                    None => return Err(at(anyhow!("missing repeat count"))),
+                };
+                if tokens.next().map(|t| t.0).as_deref() != Some("{") {
+                    return Err(at(anyhow!("expected '{{' after repeat {}", count)));
+                }
+                let body = parse_block(tokens, true)?;
+                statements.push(Statement {
+                    line,
+                    command: Command::Repeat(count, body),
+                });
+                continue;
+            }
+            _ => {
+                while let Some((arg, _)) = tokens.next_if(|(t, _)| !",;{}".contains(t.as_str())) {
+                    args.push(arg);
+                }
+                let arity = |n: usize| {
+                    if args.len() == n {
+                        Ok(())
+                    } else {
+                        Err(at(anyhow!("'{}' takes {} argument(s)", word, n)))
+                    }
+                };
+                match word.as_str() {
+                    "load" => {
+                        arity(1)?;
+                        Command::Load(args[0].clone())
+                    }
+                    "output-file" => {
+                        arity(1)?;
+                        Command::OutputFile(args[0].clone())
+                    }
+                    "compare-to" => {
+                        arity(1)?;
+                        Command::CompareTo(args[0].clone())
+                    }
+                    "output-list" => Command::OutputList(
+                        args.iter()
+                            .map(|a| parse_column(a))
+                            .collect::<Result<_>>()
+                            .map_err(at)?,
+                    ),
+                    "set" => {
+                        arity(2)?;
+                        let var = parse_var(&args[0]).map_err(at)?;
+                        if var == Var::Time {
+                            return Err(at(anyhow!("time cannot be set")));
+                        }
+                        Command::Set(var, parse_value(&args[1]).map_err(at)?)
+                    }
+                    "ticktock" => {
+                        arity(0)?;
+                        Command::TickTock
+                    }
+                    "output" => {
+                        arity(0)?;
+                        Command::Output
+                    }
+                    "echo" => {
+                        arity(1)?;
+                        Command::Echo(args[0].trim_start_matches('"').to_string())
+                    }
+                    "clear-echo" => {
+                        arity(0)?;
+                        Command::ClearEcho
+                    }
+                    _ => return Err(at(anyhow!("unsupported command '{}'", word))),
+                }
+            }
+        };
+        statements.push(Statement { line, command });
+    }
+    if nested {
+        bail!("missing '}}' at end of script");
+    }
+    Ok(statements)
+}
+
+fn parse_script(script: &str) -> Result<Vec<Statement>> {
+    let mut tokens = tokenize(script)?.into_iter().peekable();
+    parse_block(&mut tokens, false)
+}
+
+/// Does an output line match a compare line? `*` matches any character.
+fn lines_match(expected: &str, actual: &str) -> bool {
+    let expected = expected.trim_end();
+    let actual = actual.trim_end();
+    expected.len() == actual.len()
+        && expected
+            .chars()
+            .zip(actual.chars())
+            .all(|(e, a)| e == '*' || e == a)
+}
+
+/// State of a running script.
+struct Runner<'a> {
+    hacksys: &'a mut HackSystem,
+    dir: PathBuf,
+    columns: Vec<Column>,
+    compare: Option<Vec<String>>,
+    time: u64,
+    report: TestReport,
+}
+
+impl Runner<'_> {
+    fn value(&self, var: &Var) -> u16 {
+        let engine = &self.hacksys.engine;
+        match var {
+            Var::Ram(address) => engine.ram[*address as usize],
+            Var::Pc => engine.pc,
+            Var::A => engine.a,
+            Var::D => engine.d,
+            Var::Time => self.time as u16,
+        }
+    }
+
+    fn format(&self, column: &Column) -> String {
+        let value = self.value(&column.var);
+        let text = match column.format {
+            'B' => format!("{:016b}", value),
+            'X' => format!("{:04X}", value),
+            'S' if column.var == Var::Time => self.time.to_string(),
+            _ => (value as i16).to_string(),
+        };
+        // keep the low-order digits if the value is wider than its column
+        let text = &text[text.len().saturating_sub(column.width)..];
+        format!(
+            "{}{:>width$}{}",
+            " ".repeat(column.left),
+            text,
+            " ".repeat(column.right),
+            width = column.width
+        )
+    }
+
+    fn header(&self) -> String {
+        let mut line = String::from("|");
+        for column in &self.columns {
+            let width = column.left + column.width + column.right;
+            let name: String = column.name.chars().take(width).collect();
+            let pad = width - name.len();
+            line.push_str(&format!(
+                "{}{}{}|",
+                " ".repeat(pad / 2),
+                name,
+                " ".repeat(pad - pad / 2)
+            ));
+        }
+        line
+    }
+
+    /// Append a line to the output, comparing it. False on a mismatch.
+    fn emit(&mut self, line: String, script_line: usize) -> bool {
+        let number = self.report.output.len() + 1;
+        if let Some(compare) = &self.compare {
+            let expected = compare.get(number - 1).map_or("", |l| l.as_str());
+            if !lines_match(expected, &line) {
+                self.report.failure = Some(TestFailure {
+                    line: number,
+                    expected: expected.trim_end().to_string(),
+                    actual: line.clone(),
+                    script_line,
+                });
+            }
+        }
+        self.report.output.push(line);
+        self.report.failure.is_none()
+    }
+
+    /// Run statements; false once a comparison has failed.
+    fn run(&mut self, statements: &[Statement]) -> Result<bool> {
+        for statement in statements {
+            let at = |e: anyhow::Error| anyhow!("line {}: {}", statement.line, e);
+            match &statement.command {
+                Command::Load(file) => {
+                    let path = self.dir.join(file);
+                    let text = std::fs::read_to_string(&path)
+                        .map_err(|e| at(anyhow!("{}: {}", path.display(), e)))?;
+                    self.hacksys.load_code(&path, &text).map_err(at)?;
+                    self.time = 0;
+                }
+                Command::OutputFile(file) => self.report.output_file = Some(self.dir.join(file)),
+                Command::CompareTo(file) => {
+                    let path = self.dir.join(file);
+                    let text = std::fs::read_to_string(&path)
+                        .map_err(|e| at(anyhow!("{}: {}", path.display(), e)))?;
+                    self.compare = Some(text.lines().map(str::to_string).collect());
+                    self.report.compare_file = Some(path);
+                }
+                Command::OutputList(columns) => {
+                    self.columns = columns.clone();
+                    if !self.emit(self.header(), statement.line) {
+                        return Ok(false);
+                    }
+                }
+                Command::Set(var, value) => {
+                    let engine = &mut self.hacksys.engine;
+                    match var {
+                        Var::Ram(address) => engine.ram[*address as usize] = *value,
+                        Var::Pc => engine.pc = *value,
+                        Var::A => engine.a = *value,
+                        Var::D => engine.d = *value,
+                        Var::Time => unreachable!(),
+                    }
+                }
+                Command::TickTock => {
+                    self.hacksys
+                        .execute_instructions(Duration::ZERO)
+                        .map_err(at)?;
+                    self.time += 1;
+                }
+                Command::Output => {
+                    let mut line = String::from("|");
+                    for column in &self.columns {
+                        line.push_str(&self.format(column));
+                        line.push('|');
+                    }
+                    if !self.emit(line, statement.line) {
+                        return Ok(false);
+                    }
+                }
+                Command::Echo(text) => self.report.echoes.push(text.clone()),
+                Command::ClearEcho => {}
+                Command::Repeat(count, body) => {
+                    for _ in 0..*count {
+                        if !self.run(body)? {
+                            return Ok(false);
+                        }
+                    }
+                }
+            }
+        }
+        Ok(true)
+    }
+}
+
+impl HackSystem {
+    /// Run the test script `script`, read from `path`. The output file, if
+    /// the script names one, is written even when the comparison fails.
+    pub fn run_test_script(&mut self, path: &Path, script: &str) -> Result<TestReport> {
+        let name = path.display();
+        let statements = parse_script(script).map_err(|e| anyhow!("{}: {}", name, e))?;
+        let mut runner = Runner {
+            hacksys: self,
+            dir: path.parent().unwrap_or(Path::new("")).to_path_buf(),
+            columns: Vec::new(),
+            compare: None,
+            time: 0,
+            report: TestReport::default(),
+        };
+        runner
+            .run(&statements)
+            .map_err(|e| anyhow!("{}: {}", name, e))?;
+        let report = runner.report;
+        if let Some(out) = &report.output_file {
+            let mut text = report.output.join("\n");
+            text.push('\n');
+            std::fs::write(out, text)?;
+        }
+        Ok(report)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MAX_ASM: &str = "\
+@R0
+D=M
+@R1
+D=D-M
+@FIRST
+D;JGT
+@R1
+D=M
+@OUT
+0;JMP
+(FIRST)
+@R0
+D=M
+(OUT)
+@R2
+M=D
+(END)
+@END
+0;JMP
+";
+
+    const MAX_TST: &str = "\
+// compares the larger of RAM[0] and RAM[1]
+load Max.asm,
+output-file Max.out,
+compare-to Max.cmp,
+output-list RAM[0]%D2.6.2 RAM[1]%D2.6.2 RAM[2]%D2.6.2;
+
+set RAM[0] 3, set RAM[1] 5;
+repeat 14 { ticktock; }
+output;
+
+set PC 0, set RAM[0] %X17, set RAM[1] -1;  /* a negative */
+repeat 14 {
+    ticktock;
+}
+output;
+";
+
+    const MAX_CMP: &str = "\
+|  RAM[0]  |  RAM[1]  |  RAM[2]  |
+|       3  |       5  |       5  |
+|      23  |      -1  |      2*  |
+";
+
+    fn setup(name: &str, cmp: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("hackem_{}", name));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("Max.asm"), MAX_ASM).unwrap();
+        std::fs::write(dir.join("Max.cmp"), cmp).unwrap();
+        dir.join("Max.tst")
+    }
+
+    #[test]
+    fn test_script_passes() {
+        let tst = setup("tst_pass", MAX_CMP);
+        let mut hs = HackSystem::new();
+        let report = hs.run_test_script(&tst, MAX_TST).unwrap();
+        assert!(report.passed(), "{}", report);
+        assert_eq!(report.output.len(), 3);
+        let out = std::fs::read_to_string(tst.with_extension("out")).unwrap();
+        assert_eq!(
+            out.lines().nth(2).unwrap(),
+            "|      23  |      -1  |      23  |"
+        );
+    }
+
+    #[test]
+    fn test_script_failure_location() {
+        let cmp = MAX_CMP.replace("|       5  |       5  |", "|       5  |       3  |");
+        let tst = setup("tst_fail", &cmp);
+        let mut hs = HackSystem::new();
+        let report = hs.run_test_script(&tst, MAX_TST).unwrap();
+        let failure = report.failure.unwrap();
+        assert_eq!(failure.line, 2);
+        assert_eq!(failure.script_line, 9);
+        assert_eq!(failure.actual, "|       3  |       5  |       5  |");
+        // the script stopped at the failing line
+        assert_eq!(report.output.len(), 2);
+
+        let err = hs
+            .run_test_script(&tst, "load Max.asm;\nset X 1;\n")
+            .unwrap_err();
+        assert!(err.to_string().ends_with("line 2: unknown variable 'X'"));
+        let err = hs
+            .run_test_script(&tst, "repeat 2 { ticktock;\n")
+            .unwrap_err();
+        assert!(err.to_string().ends_with("missing '}' at end of script"));
+    }
+}
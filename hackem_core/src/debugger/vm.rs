@@ -0,0 +1,715 @@
+/*
+
+VM emulator
+
+Runs `.vm` files directly, like the nand2tetris VMEmulator, instead of the
+Hack code a VM translator would produce. The VM works on the engine's RAM
+through `get_ram` / `set_ram`, so the stack, segments, screen, keyboard,
+output port and watchpoints behave as they do for translated code.
+
+In VM mode `engine.pc` is the index of the next VM command, and
+breakpoints, run-to addresses and PDB addresses are command indices, so
+breakpoints, stepping, finish, the call stack and the segment view all
+work one VM command at a time. Labels are kept as (no-op) commands so the
+listing matches the source files line for line.
+
+Statics get one block per file, in load order, starting at RAM[16]. If a
+`Sys.init` function is loaded the program starts with SP=256 and a call to
+it, as the official bootstrap does; otherwise it starts at the first
+command with RAM as it is, as the VM test scripts expect. Running off the
+end of the program is a halt.
+
+Calls to Jack OS functions that are not loaded get a `builtin` command at
+the end of the program (with a PDB Func symbol), which the native OS runs.
+
+*/
+
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{anyhow, bail, Result};
+use common::pdb::database::{
+    FileInfo, FileType, Pdb, SourceMap, Symbol, SymbolType, STORAGE_STATIC,
+};
+use web_time::{Duration, Instant};
+
+use super::{
+    debug_em::HackSystem,
+    native_os,
+    segments::Segment,
+    stepping::{ARG, LCL, SP, THAT, THIS},
+};
+use crate::emulator::engine::{HackEngine, StopReason};
+
+const STATIC: u16 = 16;
+const STACK: u16 = 256;
+const TRUE: u16 = 0xffff;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum VmOp {
+    PushConstant(u16),
+    Push(Segment, u16),
+    Pop(Segment, u16),
+    Add,
+    Sub,
+    Neg,
+    Eq,
+    Gt,
+    Lt,
+    And,
+    Or,
+    Not,
+    Label,
+    Goto(u16),
+    IfGoto(u16),
+    Function(u16),
+    /// Call `name` with `args` arguments; `target` is None if the function
+    /// was not loaded, which is an error only if the call is executed.
+    Call {
+        name: String,
+        target: Option<u16>,
+        args: u16,
+    },
+    Return,
+    /// Entry of an OS function that was not loaded, appended for the
+    /// native OS to intercept.
+    Builtin(String),
+}
+
+#[derive(Debug, Clone)]
+pub struct VmCommand {
+    pub op: VmOp,
+    /// Source text, without comment.
+    pub text: String,
+    /// Index into the loaded files and 1-based line number.
+    pub file: usize,
+    pub line: usize,
+}
+
+#[derive(Debug, Default)]
+pub struct VmProgram {
+    pub commands: Vec<VmCommand>,
+    /// First static address of each file.
+    static_base: Vec<u16>,
+    /// Index of each function's `function` command.
+    functions: HashMap<String, u16>,
+    /// Index of the first builtin command, i.e. the end of the loaded code.
+    end: u16,
+}
+
+/// Accumulates RAM accesses for one command and whether any of them asked
+/// for the UI to refresh.
+struct Machine<'a> {
+    engine: &'a mut HackEngine,
+    ui_stop: bool,
+}
+
+impl Machine<'_> {
+    fn read(&mut self, address: u16) -> Result<u16> {
+        self.engine.get_ram(address)
+    }
+    fn write(&mut self, address: u16, value: u16) -> Result<()> {
+        self.ui_stop |= self.engine.set_ram(address, value)?;
+        Ok(())
+    }
+    fn push(&mut self, value: u16) -> Result<()> {
+        let sp = self.read(SP as u16)?;
+        self.write(sp, value)?;
+        self.write(SP as u16, sp.wrapping_add(1))
+    }
+    fn pop(&mut self) -> Result<u16> {
+        let sp = self.read(SP as u16)?.wrapping_sub(1);
+        self.write(SP as u16, sp)?;
+        self.read(sp)
+    }
+    fn address(&mut self, segment: Segment, index: u16, static_base: u16) -> Result<u16> {
+        let base = match segment {
+            Segment::Local => self.read(LCL as u16)?,
+            Segment::Argument => self.read(ARG as u16)?,
+            Segment::This => self.read(THIS as u16)?,
+            Segment::That => self.read(THAT as u16)?,
+            Segment::Pointer => THIS as u16,
+            Segment::Temp => 5,
+            Segment::Static => static_base,
+        };
+        Ok(base.wrapping_add(index))
+    }
+}
+
+fn parse_segment(name: &str) -> Option<Segment> {
+    Some(match name {
+        "local" => Segment::Local,
+        "argument" => Segment::Argument,
+        "this" => Segment::This,
+        "that" => Segment::That,
+        "pointer" => Segment::Pointer,
+        "temp" => Segment::Temp,
+        "static" => Segment::Static,
+        _ => return None,
+    })
+}
+
+fn parse_number(word: &str) -> Result<u16> {
+    word.parse::<u16>()
+        .ok()
+        .filter(|&n| n < 0x8000)
+        .ok_or_else(|| anyhow!("invalid number '{}'", word))
+}
+
+/// Read all `.vm` files in `dir`, sorted by name.
+pub fn read_vm_dir(dir: &Path) -> Result<Vec<(PathBuf, String)>> {
+    let mut paths = std::fs::read_dir(dir)?
+        .filter_map(|entry| entry.ok().map(|e| e.path()))
+        .filter(|p| p.extension().is_some_and(|e| e.eq_ignore_ascii_case("vm")))
+        .collect::<Vec<_>>();
+    if paths.is_empty() {
+        bail!("No .vm files in {}", dir.display());
+    }
+    paths.sort();
+    paths
+        .into_iter()
+        .map(|p| Ok((p.clone(), std::fs::read_to_string(&p)?)))
+        .collect()
+}
+
+impl VmProgram {
+    /// Parse `files` (path and contents) into one program and a PDB with
+    /// a Func symbol per function, a Var symbol per static and a source map
+    /// from command index to file line.
+    pub fn parse(files: &[(PathBuf, String)]) -> Result<(VmProgram, Pdb)> {
+        let mut program = VmProgram::default();
+        let mut pdb = Pdb::new();
+        let mut functions: HashMap<String, u16> = HashMap::new();
+        let mut labels: HashMap<String, u16> = HashMap::new();
+        // jumps waiting for their label: command index and label key
+        let mut fixups: Vec<(usize, String)> = Vec::new();
+        let mut next_static = STATIC;
+
+        for (file, (path, source)) in files.iter().enumerate() {
+            let stem = path
+                .file_stem()
+                .map_or(String::new(), |s| s.to_string_lossy().into_owned());
+            let mut function = String::new();
+            let mut statics = 0u16;
+            for (lineno, raw) in source.lines().enumerate() {
+                let text = raw.split("//").next().unwrap_or("").trim();
+                if text.is_empty() {
+                    continue;
+                }
+                let at = |e: anyhow::Error| anyhow!("{}:{}: {}", path.display(), lineno + 1, e);
+                let words: Vec<&str> = text.split_whitespace().collect();
+                let index = program.commands.len();
+                if index >= 0x8000 {
+                    return Err(at(anyhow!("program too large")));
+                }
+                let op = match words.as_slice() {
+                    ["push", "constant", n] => VmOp::PushConstant(parse_number(n).map_err(at)?),
+                    [cmd @ ("push" | "pop"), segment, n] => {
+                        let segment = parse_segment(segment)
+                            .ok_or_else(|| at(anyhow!("invalid segment '{}'", segment)))?;
+                        let n = parse_number(n).map_err(at)?;
+                        let limit = match segment {
+                            Segment::Pointer => 2,
+                            Segment::Temp => 8,
+                            _ => 0x8000,
+                        };
+                        if n >= limit {
+                            return Err(at(anyhow!("{} index {} out of range", segment, n)));
+                        }
+                        if segment == Segment::Static {
+                            statics = statics.max(n + 1);
+                        }
+                        if *cmd == "push" {
+                            VmOp::Push(segment, n)
+                        } else {
+                            VmOp::Pop(segment, n)
+                        }
+                    }
+                    ["add"] => VmOp::Add,
+                    ["sub"] => VmOp::Sub,
+                    ["neg"] => VmOp::Neg,
+                    ["eq"] => VmOp::Eq,
+                    ["gt"] => VmOp::Gt,
+                    ["lt"] => VmOp::Lt,
+                    ["and"] => VmOp::And,
+                    ["or"] => VmOp::Or,
+                    ["not"] => VmOp::Not,
+                    ["label", label] => {
+                        let key = format!("{}${}", function, label);
+                        if labels.insert(key, index as u16).is_some() {
+                            return Err(at(anyhow!("label '{}' already defined", label)));
+                        }
+                        VmOp::Label
+                    }
+                    [cmd @ ("goto" | "if-goto"), label] => {
+                        fixups.push((index, format!("{}${}", function, label)));
+                        if *cmd == "goto" {
+                            VmOp::Goto(0)
+                        } else {
+                            VmOp::IfGoto(0)
+                        }
+                    }
+                    ["function", name, n] => {
+                        function = name.to_string();
+                        if functions.insert(function.clone(), index as u16).is_some() {
+                            return Err(at(anyhow!("function '{}' already defined", name)));
+                        }
+                        let locals = parse_number(n).map_err(at)?;
+                        pdb.symbols.push(Symbol {
+                            symbol_type: SymbolType::Func,
+                            name: function.clone(),
+                            func_type: 0,
+                            var_type: 0,
+                            storage_class: STORAGE_STATIC,
+                            size: locals as i64,
+                            address: index as u16,
+                            instance_type: String::new(),
+                            file_type: FileType::Vm,
+                        });
+                        VmOp::Function(locals)
+                    }
+                    ["call", name, n] => VmOp::Call {
+                        name: name.to_string(),
+                        target: None,
+                        args: parse_number(n).map_err(at)?,
+                    },
+                    ["return"] => VmOp::Return,
+                    _ => return Err(at(anyhow!("invalid command '{}'", text))),
+                };
+                pdb.source_map.push(SourceMap {
+                    file,
+                    line_no: lineno + 1,
+                    col_no: 0,
+                    addr: index as u16,
+                });
+                program.commands.push(VmCommand {
+                    op,
+                    text: words.join(" "),
+                    file,
+                    line: lineno + 1,
+                });
+            }
+
+            pdb.file_info.push(FileInfo {
+                name: path.clone(),
+                file_type: FileType::Vm,
+            });
+            for i in 0..statics {
+                pdb.symbols.push(Symbol {
+                    symbol_type: SymbolType::Var,
+                    name: format!("{}.{}", stem, i),
+                    func_type: 0,
+                    var_type: 0,
+                    storage_class: STORAGE_STATIC,
+                    size: 1,
+                    address: next_static + i,
+                    instance_type: String::new(),
+                    file_type: FileType::Vm,
+                });
+            }
+            program.static_base.push(next_static);
+            next_static += statics;
+            if next_static > 0x100 {
+                bail!("{}: too many static variables", path.display());
+            }
+        }
+
+        for (index, key) in fixups {
+            let Some(&target) = labels.get(&key) else {
+                let command = &program.commands[index];
+                bail!(
+                    "{}:{}: unknown label '{}'",
+                    files[command.file].0.display(),
+                    command.line,
+                    key.rsplit('$').next().unwrap_or("")
+                );
+            };
+            match &mut program.commands[index].op {
+                VmOp::Goto(t) | VmOp::IfGoto(t) => *t = target,
+                _ => unreachable!(),
+            }
+        }
+        program.end = program.commands.len() as u16;
+        for index in 0..program.commands.len() {
+            let VmOp::Call { name, .. } = &program.commands[index].op else {
+                continue;
+            };
+            let name = name.clone();
+            if !functions.contains_key(&name) && native_os::arity(&name).is_some() {
+                let builtin = program.commands.len() as u16;
+                functions.insert(name.clone(), builtin);
+                pdb.symbols.push(Symbol {
+                    symbol_type: SymbolType::Func,
+                    name: name.clone(),
+                    func_type: 0,
+                    var_type: 0,
+                    storage_class: STORAGE_STATIC,
+                    size: 0,
+                    address: builtin,
+                    instance_type: String::new(),
+                    file_type: FileType::Vm,
+                });
+                program.commands.push(VmCommand {
+                    text: format!("builtin {}", name),
+                    op: VmOp::Builtin(name),
+                    file: 0,
+                    line: 0,
+                });
+            }
+        }
+        for command in program.commands.iter_mut() {
+            if let VmOp::Call { name, target, .. } = &mut command.op {
+                *target = functions.get(name).copied();
+            }
+        }
+        program.functions = functions;
+        Ok((program, pdb))
+    }
+
+    /// Index of the `function` command of `name`.
+    pub fn function(&self, name: &str) -> Option<u16> {
+        self.functions.get(name).copied()
+    }
+
+    /// `count` commands from `start`, as (index, text).
+    pub fn listing(&self, start: u16, count: u16) -> Vec<(u16, String)> {
+        self.commands
+            .iter()
+            .enumerate()
+            .skip(start as usize)
+            .take(count as usize)
+            .map(|(i, c)| (i as u16, c.text.clone()))
+            .collect()
+    }
+
+    /// True if a jump from `from` back to `to` loops without doing anything.
+    fn idle_loop(&self, to: u16, from: u16) -> bool {
+        to <= from
+            && self.commands[to as usize..from as usize]
+                .iter()
+                .all(|c| c.op == VmOp::Label)
+    }
+
+    /// Execute one command. Returns true if the UI should refresh.
+    fn step(&self, engine: &mut HackEngine) -> Result<bool> {
+        let pc = engine.pc;
+        let command = &self.commands[pc as usize];
+        let static_base = self.static_base[command.file];
+        let mut m = Machine {
+            engine,
+            ui_stop: false,
+        };
+        // falling off the loaded code skips the builtins and halts
+        let mut next = if pc + 1 == self.end {
+            self.commands.len() as u16
+        } else {
+            pc + 1
+        };
+        match &command.op {
+            VmOp::PushConstant(n) => m.push(*n)?,
+            VmOp::Push(segment, n) => {
+                let address = m.address(*segment, *n, static_base)?;
+                let value = m.read(address)?;
+                m.push(value)?;
+            }
+            VmOp::Pop(segment, n) => {
+                let address = m.address(*segment, *n, static_base)?;
+                let value = m.pop()?;
+                m.write(address, value)?;
+            }
+            VmOp::Neg | VmOp::Not => {
+                let x = m.pop()?;
+                m.push(if command.op == VmOp::Neg {
+                    x.wrapping_neg()
+                } else {
+                    !x
+                })?;
+            }
+            VmOp::Add | VmOp::Sub | VmOp::Eq | VmOp::Gt | VmOp::Lt | VmOp::And | VmOp::Or => {
+                let y = m.pop()?;
+                let x = m.pop()?;
+                let flag = |b: bool| if b { TRUE } else { 0 };
+                m.push(match command.op {
+                    VmOp::Add => x.wrapping_add(y),
+                    VmOp::Sub => x.wrapping_sub(y),
+                    VmOp::Eq => flag(x == y),
+                    VmOp::Gt => flag((x as i16) > (y as i16)),
+                    VmOp::Lt => flag((x as i16) < (y as i16)),
+                    VmOp::And => x & y,
+                    _ => x | y,
+                })?;
+            }
+            VmOp::Label => {}
+            VmOp::Goto(target) => next = *target,
+            VmOp::IfGoto(target) => {
+                if m.pop()? != 0 {
+                    next = *target;
+                }
+            }
+            VmOp::Function(locals) => {
+                for _ in 0..*locals {
+                    m.push(0)?;
+                }
+            }
+            VmOp::Call { name, target, args } => {
+                let Some(target) = target else {
+                    bail!("Call to undefined function {}", name);
+                };
+                m.push(next)?;
+                for register in [LCL, ARG, THIS, THAT] {
+                    let value = m.read(register as u16)?;
+                    m.push(value)?;
+                }
+                let sp = m.read(SP as u16)?;
+                m.write(ARG as u16, sp.wrapping_sub(args + 5))?;
+                m.write(LCL as u16, sp)?;
+                next = *target;
+            }
+            VmOp::Return => {
+                let frame = m.read(LCL as u16)?;
+                let ret = m.read(frame.wrapping_sub(5))?;
+                let value = m.pop()?;
+                let arg = m.read(ARG as u16)?;
+                m.write(arg, value)?;
+                m.write(SP as u16, arg.wrapping_add(1))?;
+                for (i, register) in [THAT, THIS, ARG, LCL].into_iter().enumerate() {
+                    let value = m.read(frame.wrapping_sub(i as u16 + 1))?;
+                    m.write(register as u16, value)?;
+                }
+                next = ret;
+            }
+            VmOp::Builtin(name) => {
+                bail!("{} is not loaded; turn on the native OS to run it", name)
+            }
+        }
+        let ui_stop = m.ui_stop;
+        engine.pc = next;
+        Ok(ui_stop)
+    }
+
+    /// Run for up to `run_time`, stopping like `HackEngine::execute_instructions`.
+    pub fn execute(&self, engine: &mut HackEngine, run_time: Duration) -> Result<StopReason> {
+        let start_time = Instant::now();
+        engine.speed = 0.0;
+        let mut counter = 0;
+        let mut executed = 0u64;
+        engine.triggered_watchpoint = None;
+        loop {
+            let pc = engine.pc;
+            if pc as usize >= self.commands.len() {
+                return Ok(StopReason::SysHalt);
+            }
+            if !engine.intercepts.is_empty() && engine.intercepts.contains(&pc) {
+                return Ok(StopReason::Intercept);
+            }
+            counter += 1;
+            if counter > 1000 {
+                let time = Instant::now() - start_time;
+                if time > run_time {
+                    engine.speed = executed as f32 / time.as_secs_f32() / 1000000.0;
+                    return Ok(StopReason::RefreshUI);
+                }
+                counter = 0;
+            }
+            if !engine.count_instruction() {
+                return Ok(StopReason::InstructionLimit);
+            }
+            executed += 1;
+
+            let ui_stop = self.step(engine)?;
+
+            if let Some(hit) = &mut engine.triggered_watchpoint {
+                hit.pc = pc;
+            }
+            let bp_addr = if engine.break_before { engine.pc } else { pc };
+            if let Some(bp) = engine.break_points.get(&bp_addr) {
+                if bp.enabled {
+                    engine.triggered_breakpoint = Some(bp_addr);
+                    return Ok(StopReason::BreakPoint);
+                }
+            }
+            if engine.triggered_watchpoint.is_some() {
+                return Ok(StopReason::WatchPoint);
+            }
+            if !engine.run_to.is_empty() && engine.run_to.contains(&engine.pc) {
+                return Ok(StopReason::RunTo);
+            }
+            if matches!(self.commands[pc as usize].op, VmOp::Goto(_))
+                && self.idle_loop(engine.pc, pc)
+            {
+                return Ok(StopReason::HardLoop);
+            }
+            if run_time == Duration::ZERO || ui_stop {
+                return Ok(StopReason::RefreshUI);
+            }
+        }
+    }
+}
+
+impl HackSystem {
+    /// Switch to VM mode running `files` (path and contents). Replaces the
+    /// PDB with one describing the VM program.
+    pub fn load_vm(&mut self, files: &[(PathBuf, String)]) -> Result<()> {
+        let (program, pdb) = VmProgram::parse(files)?;
+        self.native_os.reset_heap();
+        let engine = &mut self.engine;
+        engine.pc = 0;
+        engine.history.clear();
+        engine.executed.fill(false);
+        engine.rom_words_loaded = 0;
+        engine.ram_words_loaded = 0;
+        engine.set_screen_dirty(true);
+        if let Some(init) = program.function("Sys.init") {
+            // bootstrap: SP=256, call Sys.init 0 returning past the end
+            let ret = program.commands.len() as u16;
+            let frame = [
+                ret,
+                engine.ram[LCL],
+                engine.ram[ARG],
+                engine.ram[THIS],
+                engine.ram[THAT],
+            ];
+            engine.ram[STACK as usize..STACK as usize + 5].copy_from_slice(&frame);
+            engine.ram[SP] = STACK + 5;
+            engine.ram[ARG] = STACK;
+            engine.ram[LCL] = STACK + 5;
+            engine.pc = init;
+        }
+        self.pdb = pdb;
+        self.load_waw()?;
+        // source_lines is one flat list, so take each line's text from the command
+        for (addr, cl) in self.waw.iter_mut() {
+            cl.ctext = program.commands.get(*addr as usize).map(|c| c.text.clone());
+        }
+        self.vm = Some(program);
+        Ok(())
+    }
+
+    /// True when running VM commands rather than Hack instructions.
+    pub fn vm_mode(&self) -> bool {
+        self.vm.is_some()
+    }
+
+    /// Size of the loaded program, for load messages.
+    pub fn program_summary(&self) -> String {
+        match &self.vm {
+            Some(vm) => format!("VM: {} commands", vm.commands.len()),
+            None => format!(
+                "ROM: {} words  RAM: {} words",
+                self.engine.rom_words_loaded, self.engine.ram_words_loaded
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MAIN: &str = "\
+// doubles its argument
+function Main.double 1
+    push argument 0
+    push argument 0
+    add
+    pop local 0
+    push local 0
+    return
+";
+
+    const SYS: &str = "\
+function Sys.init 0
+    push constant 21
+    call Main.double 1
+    pop static 0
+    push constant 3
+    push constant 5
+    lt
+    pop static 1
+label END
+    goto END
+";
+
+    fn files() -> Vec<(PathBuf, String)> {
+        vec![
+            (PathBuf::from("Main.vm"), MAIN.to_string()),
+            (PathBuf::from("Sys.vm"), SYS.to_string()),
+        ]
+    }
+
+    #[test]
+    fn test_vm_run() {
+        let mut hs = HackSystem::new();
+        hs.load_vm(&files()).unwrap();
+        assert_eq!(hs.engine.pc, 7);
+        assert_eq!(hs.convert_addr("Main.double").unwrap().0, 0);
+        assert_eq!(hs.convert_addr("Sys.1").unwrap().0, 17);
+        let stop = hs.execute_instructions(Duration::from_secs(10)).unwrap();
+        assert_eq!(stop, StopReason::HardLoop);
+        assert_eq!(hs.engine.ram[16], 42);
+        assert_eq!(hs.engine.ram[17], 0xffff);
+        assert_eq!(hs.engine.ram[SP], 261);
+    }
+
+    #[test]
+    fn test_vm_debugging() {
+        let mut hs = HackSystem::new();
+        hs.load_vm(&files()).unwrap();
+
+        // break on the add inside Main.double
+        hs.engine.add_breakpoint(3);
+        let stop = hs.execute_instructions(Duration::from_secs(10)).unwrap();
+        assert_eq!(stop, StopReason::BreakPoint);
+        assert_eq!(hs.engine.pc, 4);
+        let names: Vec<_> = hs
+            .backtrace()
+            .into_iter()
+            .map(|f| f.function.unwrap_or_default())
+            .collect();
+        assert_eq!(names[..2], ["Main.double", "Sys.init"]);
+        assert!(hs
+            .source_location(4)
+            .unwrap()
+            .starts_with("Main.vm:6  pop local 0"));
+
+        // finish back into Sys.init, just after the call
+        hs.engine.remove_all_breakpoints();
+        hs.finish().unwrap();
+        let stop = hs.execute_instructions(Duration::from_secs(10)).unwrap();
+        assert_eq!(stop, StopReason::RunTo);
+        assert_eq!(hs.engine.pc, 10);
+        assert_eq!(hs.engine.ram[256 + 5], 42);
+
+        // the call is stepped over as one command
+        hs.load_vm(&files()).unwrap();
+        hs.execute_instructions(Duration::ZERO).unwrap();
+        hs.execute_instructions(Duration::ZERO).unwrap();
+        assert_eq!(hs.call_return_address(), Some(10));
+        assert!(hs.step_over());
+        let stop = hs.execute_instructions(Duration::from_secs(10)).unwrap();
+        assert_eq!(stop, StopReason::RunTo);
+        assert_eq!(hs.engine.pc, 10);
+    }
+
+    #[test]
+    fn test_vm_without_sys_init() {
+        let mut hs = HackSystem::new();
+        hs.engine.ram[SP] = 256;
+        let source = "push constant 7\npush constant 8\nadd\n";
+        hs.load_code(Path::new("SimpleAdd.vm"), source).unwrap();
+        assert!(hs.vm_mode());
+        let stop = hs.execute_instructions(Duration::from_secs(10)).unwrap();
+        assert_eq!(stop, StopReason::SysHalt);
+        assert_eq!(hs.engine.ram[256], 15);
+        assert_eq!(hs.engine.ram[SP], 257);
+
+        let err = VmProgram::parse(&[(PathBuf::from("Bad.vm"), "goto NOWHERE\n".to_string())])
+            .unwrap_err();
+        assert_eq!(err.to_string(), "Bad.vm:1: unknown label 'NOWHERE'");
+        let err = VmProgram::parse(&[(PathBuf::from("Bad.vm"), "\npop temp 8\n".to_string())])
+            .unwrap_err();
+        assert_eq!(err.to_string(), "Bad.vm:2: temp index 8 out of range");
+    }
+}
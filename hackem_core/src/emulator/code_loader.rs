@@ -97,9 +97,25 @@ impl HackEngine {
         self.rom_words_loaded = rom_count;
         self.ram_words_loaded = ram_count;
         self.pc = 0;
-        self.sync_screen_pixels_from_ram();
+        self.history.clear();
+        self.executed.fill(false);
+        self.set_screen_dirty(true);
         Ok(())
     }
+
+    /// Load already assembled machine code at ROM address 0, replacing the
+    /// whole ROM and any halt address from a previous hackem file.
+    pub fn load_rom(&mut self, code: &[u16]) {
+        self.rom.fill(0);
+        self.rom[..code.len()].copy_from_slice(code);
+        self.halt_addr = 0;
+        self.rom_words_loaded = code.len();
+        self.ram_words_loaded = 0;
+        self.pc = 0;
+        self.history.clear();
+        self.executed.fill(false);
+        self.set_screen_dirty(true);
+    }
 }
 #[cfg(test)]
 mod tests {
@@ -137,4 +153,14 @@ ffff"#;
         //  assert_eq!(hack.ram[0], 0x1234);
         //  assert_eq!(hack.ram[0x3334], 0xffff);
     }
+
+    #[test]
+    fn test_load_rom_replaces_program() {
+        let mut hack = HackEngine::new();
+        hack.load_file("hackem v1.0 0x0003\nROM@0000\n0001\n0002\n0003\n0004\n")
+            .unwrap();
+        hack.load_rom(&[0x0005]);
+        assert_eq!(hack.halt_addr, 0);
+        assert_eq!(&hack.rom[..4], &[0x0005, 0, 0, 0]);
+    }
 }
@@ -0,0 +1,274 @@
+/*
+
+Memory-mapped devices
+
+Every RAM access made by the CPU goes through the device bus. A device is
+registered on an inclusive address range and gets read and write callbacks
+for accesses inside it; addresses that no device claims are plain RAM.
+
+The standard Hack peripherals are registered by default:
+
+    0x4000-0x5fff  Screen      stored in RAM, marks the screen dirty
+    0x6000         Keyboard    reads return the current key, writes ignored
+    0x7fff         OutputPort  written bytes are buffered for the console
+
+Course peripherals can be added with `DeviceBus::register`, e.g. on the
+unused 0x6001-0x7ffe range, without touching the engine.
+
+*/
+
+use std::any::Any;
+
+use anyhow::{bail, Result};
+
+pub const SCREEN_START: u16 = 0x4000;
+pub const SCREEN_END: u16 = 0x5fff;
+pub const KEYBOARD: u16 = 0x6000;
+pub const OUTPUT_PORT: u16 = 0x7fff;
+
+/// Lets the bus hand back a registered device as its concrete type.
+/// Implemented automatically for every device.
+pub trait AsAny {
+    fn as_any(&self) -> &dyn Any;
+    fn as_any_mut(&mut self) -> &mut dyn Any;
+}
+
+impl<T: Any> AsAny for T {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+/// A memory-mapped peripheral.
+pub trait Device: AsAny {
+    /// Short name used when listing the bus.
+    fn name(&self) -> &str;
+
+    /// Value returned by a CPU read of `address`. `ram` is the whole of RAM,
+    /// for devices that keep their state in memory (like the screen).
+    fn read(&mut self, address: u16, ram: &[u16]) -> u16 {
+        ram[address as usize]
+    }
+
+    /// Handle a CPU write of `value` to `address`.
+    /// Return true to make the engine hand control back to the UI.
+    fn write(&mut self, address: u16, value: u16, ram: &mut [u16]) -> bool;
+}
+
+/// A device and the inclusive address range it answers to.
+pub struct Mapping {
+    pub start: u16,
+    pub end: u16,
+    pub device: Box<dyn Device>,
+}
+
+pub struct DeviceBus {
+    mappings: Vec<Mapping>,
+    /// Lowest mapped address, so ordinary RAM accesses skip the lookup.
+    lowest: u16,
+}
+
+impl Default for DeviceBus {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl DeviceBus {
+    /// An empty bus: all of RAM is plain memory.
+    pub fn new() -> Self {
+        Self {
+            mappings: Vec::new(),
+            lowest: u16::MAX,
+        }
+    }
+
+    /// A bus with the standard screen, keyboard and output port.
+    pub fn with_defaults() -> Self {
+        let mut bus = Self::new();
+        bus.register(SCREEN_START, SCREEN_END, Screen { dirty: true })
+            .unwrap();
+        bus.register(KEYBOARD, KEYBOARD, Keyboard { key: 0 })
+            .unwrap();
+        bus.register(OUTPUT_PORT, OUTPUT_PORT, OutputPort { buffer: Vec::new() })
+            .unwrap();
+        bus
+    }
+
+    /// Map `device` on `start..=end`. Fails if the range overlaps a device
+    /// that is already registered.
+    pub fn register(&mut self, start: u16, end: u16, device: impl Device + 'static) -> Result<()> {
+        if start > end || end >= 0x8000 {
+            bail!("invalid device range 0x{:04X}-0x{:04X}", start, end);
+        }
+        if let Some(m) = self
+            .mappings
+            .iter()
+            .find(|m| start <= m.end && end >= m.start)
+        {
+            bail!(
+                "0x{:04X}-0x{:04X} overlaps {} at 0x{:04X}-0x{:04X}",
+                start,
+                end,
+                m.device.name(),
+                m.start,
+                m.end
+            );
+        }
+        self.mappings.push(Mapping {
+            start,
+            end,
+            device: Box::new(device),
+        });
+        self.lowest = self.lowest.min(start);
+        Ok(())
+    }
+
+    /// Remove the device whose range starts at `start`.
+    pub fn unregister(&mut self, start: u16) -> Option<Box<dyn Device>> {
+        let index = self.mappings.iter().position(|m| m.start == start)?;
+        let mapping = self.mappings.remove(index);
+        self.lowest = self
+            .mappings
+            .iter()
+            .map(|m| m.start)
+            .min()
+            .unwrap_or(u16::MAX);
+        Some(mapping.device)
+    }
+
+    pub fn mappings(&self) -> impl Iterator<Item = &Mapping> {
+        self.mappings.iter()
+    }
+
+    /// The device mapped at `address`, if any.
+    pub fn find_mut(&mut self, address: u16) -> Option<&mut Mapping> {
+        if address < self.lowest {
+            return None;
+        }
+        self.mappings
+            .iter_mut()
+            .find(|m| address >= m.start && address <= m.end)
+    }
+
+    /// The first registered device of type `T`.
+    pub fn get<T: Device + 'static>(&self) -> Option<&T> {
+        self.mappings
+            .iter()
+            .find_map(|m| (*m.device).as_any().downcast_ref::<T>())
+    }
+
+    pub fn get_mut<T: Device + 'static>(&mut self) -> Option<&mut T> {
+        self.mappings
+            .iter_mut()
+            .find_map(|m| (*m.device).as_any_mut().downcast_mut::<T>())
+    }
+}
+
+/// The 512×256 screen. Pixels live in RAM so the data views show them too.
+pub struct Screen {
+    /// Set whenever screen RAM has changed. Cleared by whoever draws it.
+    pub dirty: bool,
+}
+
+impl Device for Screen {
+    fn name(&self) -> &str {
+        "screen"
+    }
+    fn write(&mut self, address: u16, value: u16, ram: &mut [u16]) -> bool {
+        ram[address as usize] = value;
+        self.dirty = true;
+        false
+    }
+}
+
+/// The keyboard register. The host sets `key`; the program can only read it.
+pub struct Keyboard {
+    pub key: u16,
+}
+
+impl Device for Keyboard {
+    fn name(&self) -> &str {
+        "keyboard"
+    }
+    fn read(&mut self, _address: u16, _ram: &[u16]) -> u16 {
+        self.key
+    }
+    fn write(&mut self, _address: u16, _value: u16, _ram: &mut [u16]) -> bool {
+        false
+    }
+}
+
+/// Output port: bytes written accumulate until the host drains them.
+pub struct OutputPort {
+    pub buffer: Vec<u8>,
+}
+
+impl Device for OutputPort {
+    fn name(&self) -> &str {
+        "output"
+    }
+    fn write(&mut self, _address: u16, value: u16, _ram: &mut [u16]) -> bool {
+        // never interrupt execution, the UI drains the buffer every frame
+        self.buffer.push(value as u8);
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emulator::engine::HackEngine;
+
+    /// Counts writes and reads back the count.
+    struct Counter {
+        count: u16,
+    }
+
+    impl Device for Counter {
+        fn name(&self) -> &str {
+            "counter"
+        }
+        fn read(&mut self, _address: u16, _ram: &[u16]) -> u16 {
+            self.count
+        }
+        fn write(&mut self, _address: u16, _value: u16, _ram: &mut [u16]) -> bool {
+            self.count += 1;
+            false
+        }
+    }
+
+    #[test]
+    fn test_custom_device() {
+        let mut engine = HackEngine::new();
+        engine
+            .devices
+            .register(0x6001, 0x6002, Counter { count: 0 })
+            .unwrap();
+        engine.set_ram(0x6001, 7).unwrap();
+        engine.set_ram(0x6002, 7).unwrap();
+        assert_eq!(engine.get_ram(0x6001).unwrap(), 2);
+        assert_eq!(engine.ram[0x6001], 0);
+        assert_eq!(engine.devices.get::<Counter>().unwrap().count, 2);
+
+        // unmapped addresses are still plain RAM
+        engine.set_ram(0x6003, 7).unwrap();
+        assert_eq!(engine.get_ram(0x6003).unwrap(), 7);
+
+        assert!(engine.devices.unregister(0x6001).is_some());
+        engine.set_ram(0x6001, 9).unwrap();
+        assert_eq!(engine.get_ram(0x6001).unwrap(), 9);
+    }
+
+    #[test]
+    fn test_overlapping_device_rejected() {
+        let mut bus = DeviceBus::with_defaults();
+        assert!(bus.register(0x5fff, 0x6000, Counter { count: 0 }).is_err());
+        assert!(bus.register(0x7000, 0x8000, Counter { count: 0 }).is_err());
+        assert!(bus.register(0x6001, 0x6001, Counter { count: 0 }).is_ok());
+        assert_eq!(bus.mappings().count(), 4);
+    }
+}
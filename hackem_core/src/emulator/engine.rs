@@ -1,19 +1,111 @@
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
 
-use crate::ui::app::RuntimeError;
-
-use crate::ui::widgets::screen::CURRENT_KEY;
 use anyhow::{bail, Result};
-use egui::Color32;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
 use web_time::{Duration, Instant};
-pub(crate) struct BreakPoint {
+
+use super::devices::{DeviceBus, Keyboard, OutputPort, Screen};
+use super::profiler::Profiler;
+use super::tracer::{TraceEntry, Tracer};
+#[derive(Clone, Serialize, Deserialize)]
+pub struct BreakPoint {
     pub enabled: bool,
+    /// Expression that must be non-zero for the breakpoint to stop.
+    /// Evaluated by `HackSystem::execute_instructions`.
+    #[serde(default)]
+    pub condition: Option<String>,
+    /// Number of times the breakpoint was reached with its condition true.
+    #[serde(default)]
+    pub hit_count: u32,
+    /// Hits to pass over before stopping.
+    #[serde(default)]
+    pub ignore_count: u32,
+    /// Delete the breakpoint the first time it stops (`tbreak`).
+    #[serde(default)]
+    pub temporary: bool,
+    /// Logpoint format (`trace`): print this message on each hit instead of stopping.
+    #[serde(default)]
+    pub log: Option<String>,
 }
 
+/// A watchpoint covers `len` words starting at the address it is keyed on.
+#[derive(Clone, Serialize, Deserialize)]
 pub struct WatchPoint {
     pub read: bool,
     pub write: bool,
     pub enabled: bool,
+    #[serde(default = "one")]
+    pub len: u16,
+    #[serde(default)]
+    pub condition: WatchCondition,
+}
+
+fn one() -> u16 {
+    1
+}
+
+/// Value test a watchpoint applies to the accessed word.
+#[derive(Clone, Default, Debug, PartialEq, Serialize, Deserialize)]
+pub enum WatchCondition {
+    #[default]
+    Always,
+    /// The value read or written equals this.
+    Equals(u16),
+    /// A write changes the stored value.
+    Changed,
+}
+
+impl WatchCondition {
+    fn matches(&self, write: bool, old: u16, new: u16) -> bool {
+        match self {
+            WatchCondition::Always => true,
+            WatchCondition::Equals(v) => new == *v,
+            WatchCondition::Changed => write && old != new,
+        }
+    }
+}
+
+/// The access that triggered a watchpoint. For reads `old` and `new` are
+/// both the value read.
+#[derive(Clone, Debug, PartialEq)]
+pub struct WatchHit {
+    pub address: u16,
+    /// Address of the instruction that made the access.
+    pub pc: u16,
+    pub write: bool,
+    pub old: u16,
+    pub new: u16,
+}
+
+impl std::fmt::Display for WatchHit {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.write {
+            write!(
+                f,
+                "Watchpoint 0x{:04X} written by 0x{:04X}: 0x{:04X} -> 0x{:04X}",
+                self.address, self.pc, self.old, self.new
+            )
+        } else {
+            write!(
+                f,
+                "Watchpoint 0x{:04X} read by 0x{:04X}: 0x{:04X}",
+                self.address, self.pc, self.new
+            )
+        }
+    }
+}
+
+/// Default number of instructions kept in the execution history.
+pub const DEFAULT_HISTORY_SIZE: usize = 100_000;
+
+/// Machine state captured before an instruction executed, enough to undo it.
+pub struct HistoryEntry {
+    pub pc: u16,
+    pub a: u16,
+    pub d: u16,
+    /// RAM word overwritten by the instruction, as (address, old value).
+    pub ram: Option<(u16, u16)>,
 }
 
 pub struct HackEngine {
@@ -27,24 +119,60 @@ pub struct HackEngine {
     pub rom_words_loaded: usize,
     pub ram_words_loaded: usize,
     inst_count: u64,
+    /// Stop with `StopReason::InstructionLimit` once this many instructions
+    /// have executed since the engine was created.
+    pub inst_limit: Option<u64>,
     pub break_points: BTreeMap<u16, BreakPoint>,
     pub watch_points: BTreeMap<u16, WatchPoint>,
-    pub triggered_watchpoint: Option<u16>,
-    /// Output port buffer: bytes written to RAM[0x7FFF] accumulate here.
-    output_buffer: Vec<u8>,
-    /// Pre-rendered screen pixels (512×256). Updated incrementally on every
-    /// screen-RAM write so the UI can blit without scanning all 8 192 words.
-    pub screen_pixels: Vec<Color32>,
-    /// Set whenever `screen_pixels` has changed since the last GPU upload.
-    pub screen_dirty: bool,
+    pub triggered_watchpoint: Option<WatchHit>,
+    /// Address of the breakpoint that caused the last `StopReason::BreakPoint`.
+    pub triggered_breakpoint: Option<u16>,
+    /// Stop at a breakpoint before its instruction runs, as editors and gdb
+    /// expect, instead of just after it.
+    pub break_before: bool,
+    /// Stop with `StopReason::RunTo` before executing any of these addresses.
+    /// Used by step over / finish / until.
+    pub run_to: BTreeSet<u16>,
+    /// Stop with `StopReason::Intercept` before executing any of these
+    /// addresses. Used by the native OS.
+    pub intercepts: BTreeSet<u16>,
+    /// Bounded execution history, oldest first. Used for reverse stepping.
+    pub history: VecDeque<HistoryEntry>,
+    /// Maximum number of entries kept in `history` (0 disables recording).
+    pub history_size: usize,
+    /// Memory-mapped peripherals: screen, keyboard, output port and any
+    /// devices registered by the embedder.
+    pub devices: DeviceBus,
+    /// Addresses executed since the program was loaded, for coverage.
+    pub executed: Vec<bool>,
+    /// Execution counts, while profiling is on.
+    pub profiler: Option<Profiler>,
+    /// Instruction trace, while tracing is on.
+    pub tracer: Option<Tracer>,
 }
 #[derive(Debug, PartialEq)]
-pub(crate) enum StopReason {
+pub enum StopReason {
     RefreshUI,
     SysHalt,
     HardLoop,
     BreakPoint,
     WatchPoint,
+    InstructionLimit,
+    RunTo,
+    /// About to execute an address in `intercepts`; handled by `HackSystem`.
+    Intercept,
+}
+
+#[derive(Debug, Error, PartialEq)]
+pub enum RuntimeError {
+    #[error("Invalid instruction")]
+    InvalidInstruction,
+    #[error("Invalid RAM read address {0}")]
+    InvalidReadAddress(u16),
+    #[error("Invalid RAM write address {0}")]
+    InvalidWriteAddress(u16),
+    #[error("Invalid instruction address {0}")]
+    InvalidPC(u16),
 }
 impl Default for HackEngine {
     fn default() -> Self {
@@ -65,12 +193,20 @@ impl HackEngine {
             rom_words_loaded: 0,
             ram_words_loaded: 0,
             inst_count: 0,
+            inst_limit: None,
             break_points: BTreeMap::new(),
             watch_points: BTreeMap::new(),
             triggered_watchpoint: None,
-            output_buffer: Vec::new(),
-            screen_pixels: vec![Color32::WHITE; 512 * 256],
-            screen_dirty: true,
+            triggered_breakpoint: None,
+            break_before: false,
+            run_to: BTreeSet::new(),
+            intercepts: BTreeSet::new(),
+            history: VecDeque::new(),
+            history_size: DEFAULT_HISTORY_SIZE,
+            devices: DeviceBus::with_defaults(),
+            executed: vec![false; 0x8000],
+            profiler: None,
+            tracer: None,
         }
     }
     fn alu(x_in: u16, y_in: u16, c: u16) -> u16 {
@@ -104,43 +240,16 @@ impl HackEngine {
             bail!(RuntimeError::InvalidWriteAddress(address));
         }
 
-        let ui_stop = match address {
-            0x0000..=0x3fff => {
-                self.ram[address as usize] = value;
-                false
-            }
-            0x4000..=0x5fff => {
-                // screen — update pixel cache immediately
-                self.ram[address as usize] = value;
-                let pixel_base = (address as usize - 0x4000) * 16;
-                for i in 0..16usize {
-                    self.screen_pixels[pixel_base + i] = if value & (1u16 << i) != 0 {
-                        Color32::BLACK
-                    } else {
-                        Color32::WHITE
-                    };
-                }
-                self.screen_dirty = true;
-                false
-            }
-            0x6000 => {
-                // keyboard - write ignored
-                false
-            }
-            0x7fff => {
-                // output port: buffer the byte, never interrupt execution
-                self.output_buffer.push(value as u8);
-                false
-            }
-            _ => {
+        let old = self.ram[address as usize];
+        let ui_stop = match self.devices.find_mut(address) {
+            Some(mapping) => mapping.device.write(address, value, &mut self.ram),
+            None => {
                 self.ram[address as usize] = value;
                 false
             }
         };
-        if let Some(wp) = self.watch_points.get(&address) {
-            if wp.write && wp.enabled {
-                self.triggered_watchpoint = Some(address);
-            }
+        if let Some(hit) = self.watch_hit(address, true, old, value) {
+            self.triggered_watchpoint = Some(hit);
         }
         Ok(ui_stop)
     }
@@ -148,52 +257,86 @@ impl HackEngine {
     /// Drain all bytes written to the output port (RAM[0x7FFF]) since the last call.
     /// Returns a lossy UTF-8 string of the accumulated output.
     pub fn take_output(&mut self) -> String {
-        let s = String::from_utf8_lossy(&self.output_buffer).into_owned();
-        self.output_buffer.clear();
-        s
+        match self.devices.get_mut::<OutputPort>() {
+            Some(port) => {
+                let s = String::from_utf8_lossy(&port.buffer).into_owned();
+                port.buffer.clear();
+                s
+            }
+            None => String::new(),
+        }
     }
 
-    /// Rebuild `screen_pixels` from the raw RAM contents.
-    /// Called after bulk RAM writes (e.g. `load_file`) that bypass `set_ram`.
-    pub fn sync_screen_pixels_from_ram(&mut self) {
-        for offset in 0..0x2000usize {
-            let word = self.ram[0x4000 + offset];
-            let pixel_base = offset * 16;
-            for i in 0..16usize {
-                self.screen_pixels[pixel_base + i] = if word & (1u16 << i) != 0 {
-                    Color32::BLACK
-                } else {
-                    Color32::WHITE
-                };
-            }
+    /// The raw screen buffer: 8 192 words, 32 per 512-pixel row. Bit 0 of
+    /// each word is the leftmost of its 16 pixels, and a set bit is black.
+    pub fn screen(&self) -> &[u16] {
+        &self.ram[0x4000..0x6000]
+    }
+
+    /// True if screen RAM has changed since the last `set_screen_dirty(false)`.
+    pub fn screen_dirty(&self) -> bool {
+        self.devices.get::<Screen>().is_some_and(|s| s.dirty)
+    }
+    pub fn set_screen_dirty(&mut self, dirty: bool) {
+        if let Some(screen) = self.devices.get_mut::<Screen>() {
+            screen.dirty = dirty;
+        }
+    }
+
+    /// Set the key code the program sees at RAM[0x6000] (0 = no key).
+    pub fn set_key(&mut self, key: u16) {
+        if let Some(keyboard) = self.devices.get_mut::<Keyboard>() {
+            keyboard.key = key;
         }
-        self.screen_dirty = true;
     }
     pub fn get_ram(&mut self, address: u16) -> Result<u16> {
         if address >= 0x8000 {
             bail!(RuntimeError::InvalidReadAddress(address));
         }
-        if address == 0x6000 {
-            unsafe {
-                return Ok(CURRENT_KEY as u16);
-            }
+        let value = match self.devices.find_mut(address) {
+            Some(mapping) => mapping.device.read(address, &self.ram),
+            None => self.ram[address as usize],
+        };
+        if let Some(hit) = self.watch_hit(address, false, value, value) {
+            self.triggered_watchpoint = Some(hit);
         }
-        if let Some(wp) = self.watch_points.get(&address) {
-            if wp.read && wp.enabled {
-                self.triggered_watchpoint = Some(address);
-            }
+        Ok(value)
+    }
+
+    /// Check an access against the watchpoints whose range covers `address`.
+    fn watch_hit(&self, address: u16, write: bool, old: u16, new: u16) -> Option<WatchHit> {
+        if self.watch_points.is_empty() {
+            return None;
         }
-        Ok(self.ram[address as usize])
+        self.watch_points
+            .range(..=address)
+            .find(|(start, wp)| {
+                wp.enabled
+                    && address - **start < wp.len
+                    && if write { wp.write } else { wp.read }
+                    && wp.condition.matches(write, old, new)
+            })
+            .map(|_| WatchHit {
+                address,
+                pc: self.pc,
+                write,
+                old,
+                new,
+            })
     }
-    pub(crate) fn execute_instructions(&mut self, run_time: Duration) -> Result<StopReason> {
+    pub fn execute_instructions(&mut self, run_time: Duration) -> Result<StopReason> {
         let start_time = Instant::now();
         self.speed = 0.0;
         let mut counter = 0;
         let inst_count_snap = self.inst_count;
+        self.triggered_watchpoint = None;
         loop {
             if self.pc >= 0x8000 {
                 bail!(RuntimeError::InvalidPC(self.pc));
             }
+            if !self.intercepts.is_empty() && self.intercepts.contains(&self.pc) {
+                return Ok(StopReason::Intercept);
+            }
 
             counter += 1;
             // every chunk of instructions check to see if we should refresh the UI
@@ -207,7 +350,16 @@ impl HackEngine {
                 }
                 counter = 0;
             }
+            if let Some(limit) = self.inst_limit {
+                if self.inst_count >= limit {
+                    return Ok(StopReason::InstructionLimit);
+                }
+            }
             self.inst_count += 1;
+            self.executed[self.pc as usize] = true;
+            if let Some(profiler) = &mut self.profiler {
+                profiler.record(self.pc, &self.ram);
+            }
 
             let instruction = self.rom[self.pc as usize];
 
@@ -216,14 +368,26 @@ impl HackEngine {
             if self.halt_addr != 0 && self.pc == self.halt_addr + 1 {
                 return Ok(StopReason::SysHalt);
             }
+            if self.history_size > 0 {
+                if self.history.len() >= self.history_size {
+                    self.history.pop_front();
+                }
+                self.history.push_back(HistoryEntry {
+                    pc: self.pc,
+                    a: self.a,
+                    d: self.d,
+                    ram: None,
+                });
+            }
             let opcode = instruction >> 15;
             let old_pc = self.pc;
             let mut ui_stop = false;
+            let mut written = None;
+            let mut hard_loop = false;
             self.pc += 1;
             match opcode {
                 0 => {
                     // A instruction
-                    // trace!("0x{:04x}  {:04x}", self.pc - 1, instruction);
                     self.a = instruction;
                 }
                 1 => {
@@ -239,19 +403,6 @@ impl HackEngine {
                         bail!(RuntimeError::InvalidInstruction);
                     }
 
-                    // let m = if a < 0x8000 {
-                    //     format!("{:04x}", self.get_ram(a).unwrap())
-                    // } else {
-                    //     "????".to_string()
-                    // };
-                    // trace!(
-                    //     "0x{:04x}: {:04x} A={:04x} D={:04x} M={}",
-                    //     self.pc - 1,
-                    //     instruction,
-                    //     self.a,
-                    //     self.d,
-                    //     m
-                    //);
                     let y = if a == 0 {
                         self.a
                     } else {
@@ -262,7 +413,14 @@ impl HackEngine {
 
                     // M
                     if d & 0x1 != 0 {
+                        if self.history_size > 0 {
+                            let old = self.ram[self.a as usize];
+                            if let Some(entry) = self.history.back_mut() {
+                                entry.ram = Some((self.a, old));
+                            }
+                        }
                         ui_stop = self.set_ram(self.a, alu_out)?;
+                        written = Some((self.a, alu_out));
                     }
                     // D
                     if d & 0x2 != 0 {
@@ -290,34 +448,156 @@ impl HackEngine {
                     // @halt
                     // 0;JMP
 
-                    if pc > 2 && self.pc == pc - 2 {
-                        return Ok(StopReason::HardLoop);
-                    }
+                    hard_loop = pc > 2 && self.pc == pc - 2;
                 }
                 _ => {
                     bail!(RuntimeError::InvalidInstruction);
                 }
             }
-            if let Some(bp) = self.break_points.get(&old_pc) {
+            if self.tracer.is_some() {
+                self.trace(old_pc, instruction, written)?;
+            }
+            if hard_loop {
+                return Ok(StopReason::HardLoop);
+            }
+            if let Some(hit) = &mut self.triggered_watchpoint {
+                hit.pc = old_pc;
+            }
+            let bp_addr = if self.break_before { self.pc } else { old_pc };
+            if let Some(bp) = self.break_points.get(&bp_addr) {
                 if bp.enabled {
+                    self.triggered_breakpoint = Some(bp_addr);
                     return Ok(StopReason::BreakPoint);
                 }
             }
-            if self.triggered_watchpoint.take().is_some() {
+            if self.triggered_watchpoint.is_some() {
                 return Ok(StopReason::WatchPoint);
             }
+            if !self.run_to.is_empty() && self.run_to.contains(&self.pc) {
+                return Ok(StopReason::RunTo);
+            }
             if run_time == Duration::ZERO || ui_stop {
                 return Ok(StopReason::RefreshUI);
             }
         }
     }
+
+    /// Record an executed instruction in the trace, if its address is traced.
+    fn trace(&mut self, pc: u16, instruction: u16, write: Option<(u16, u16)>) -> Result<()> {
+        let Some(tracer) = &mut self.tracer else {
+            return Ok(());
+        };
+        if tracer.wants(pc) {
+            tracer.record(TraceEntry {
+                pc,
+                instruction,
+                a: self.a,
+                d: self.d,
+                m: (self.a < 0x8000).then(|| self.ram[self.a as usize]),
+                write,
+            })?;
+        }
+        Ok(())
+    }
+
+    /// Restore the machine to the state before `entry` executed.
+    fn undo(&mut self, entry: HistoryEntry) {
+        self.pc = entry.pc;
+        self.a = entry.a;
+        self.d = entry.d;
+        if let Some((address, old)) = entry.ram {
+            self.ram[address as usize] = old;
+            if (0x4000..0x6000).contains(&address) {
+                self.set_screen_dirty(true);
+            }
+        }
+    }
+
+    /// Undo the most recently executed instruction.
+    /// Returns false if there is no history left.
+    pub fn reverse_step(&mut self) -> bool {
+        match self.history.pop_back() {
+            Some(entry) => {
+                self.undo(entry);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Run backwards until the previous breakpoint or write watchpoint.
+    /// Stops in the same place a forward run would have stopped, i.e. just
+    /// after the instruction that hit. Logpoints never stop; conditions and
+    /// ignore counts are left to `HackSystem::reverse_continue`. Returns
+    /// None if the start of the history was reached first.
+    pub fn reverse_continue(&mut self) -> Option<StopReason> {
+        let mut first = true;
+        while let Some(entry) = self.history.back() {
+            if !first {
+                if let Some(bp) = self.break_points.get(&entry.pc) {
+                    if bp.enabled && bp.log.is_none() {
+                        self.triggered_breakpoint = Some(entry.pc);
+                        return Some(StopReason::BreakPoint);
+                    }
+                }
+                if let Some((address, old)) = entry.ram {
+                    let new = self.ram[address as usize];
+                    if let Some(mut hit) = self.watch_hit(address, true, old, new) {
+                        hit.pc = entry.pc;
+                        self.triggered_watchpoint = Some(hit);
+                        return Some(StopReason::WatchPoint);
+                    }
+                }
+            }
+            first = false;
+            let entry = self.history.pop_back().unwrap();
+            self.undo(entry);
+        }
+        None
+    }
+
+    /// Total number of instructions executed since the engine was created.
+    pub fn instruction_count(&self) -> u64 {
+        self.inst_count
+    }
+
+    /// Count one instruction run outside `execute_instructions` (a VM
+    /// command). Returns false, without counting, once `inst_limit` is reached.
+    pub(crate) fn count_instruction(&mut self) -> bool {
+        if self
+            .inst_limit
+            .is_some_and(|limit| self.inst_count >= limit)
+        {
+            return false;
+        }
+        self.inst_count += 1;
+        self.executed[self.pc as usize & 0x7fff] = true;
+        if let Some(profiler) = &mut self.profiler {
+            profiler.record(self.pc, &self.ram);
+        }
+        true
+    }
+
     pub fn get_registers(&self) -> (u16, u16, u16) {
         (self.pc, self.a, self.d)
     }
 
     pub fn add_breakpoint(&mut self, address: u16) {
-        self.break_points
-            .insert(address, BreakPoint { enabled: true });
+        self.add_breakpoint_if(address, None);
+    }
+
+    pub fn add_breakpoint_if(&mut self, address: u16, condition: Option<String>) {
+        self.break_points.insert(
+            address,
+            BreakPoint {
+                enabled: true,
+                condition,
+                hit_count: 0,
+                ignore_count: 0,
+                temporary: false,
+                log: None,
+            },
+        );
     }
 
     pub fn remove_breakpoint(&mut self, address: u16) {
@@ -329,12 +609,26 @@ impl HackEngine {
     }
 
     pub fn add_watchpoint(&mut self, address: u16, read: bool, write: bool) {
+        self.add_watch_range(address, 1, read, write, WatchCondition::Always);
+    }
+
+    /// Watch `len` words starting at `address`.
+    pub fn add_watch_range(
+        &mut self,
+        address: u16,
+        len: u16,
+        read: bool,
+        write: bool,
+        condition: WatchCondition,
+    ) {
         self.watch_points.insert(
             address,
             WatchPoint {
                 read,
                 write,
                 enabled: true,
+                len,
+                condition,
             },
         );
     }
@@ -1081,13 +1375,158 @@ mod tests {
         assert!(cpu.ram[1] == 0);
     }
 
+    #[test]
+    fn test_reverse_step() {
+        let mut cpu = HackEngine::new();
+        // @2
+        // D=A
+        // @x
+        // M=D
+        cpu.rom[0] = 0x0002;
+        cpu.rom[1] = 0x8c10;
+        cpu.rom[2] = 0x0011;
+        cpu.rom[3] = 0x8308;
+        cpu.ram[17] = 0x1234;
+        for _ in 0..4 {
+            cpu.execute_instructions(Duration::ZERO).unwrap();
+        }
+        assert_eq!(cpu.ram[17], 2);
+        assert_eq!(cpu.history.len(), 4);
+
+        assert!(cpu.reverse_step());
+        assert_eq!(cpu.pc, 3);
+        assert_eq!(cpu.ram[17], 0x1234);
+        assert!(cpu.reverse_step());
+        assert_eq!((cpu.pc, cpu.a, cpu.d), (2, 2, 2));
+        assert!(cpu.reverse_step());
+        assert!(cpu.reverse_step());
+        assert_eq!((cpu.pc, cpu.a, cpu.d), (0, 0, 0));
+        assert!(!cpu.reverse_step());
+    }
+
+    #[test]
+    fn test_break_before() {
+        let mut cpu = HackEngine::new();
+        cpu.rom[0] = 0x0010; // @16
+        cpu.rom[1] = 0xfdc8; // M=M+1
+        cpu.rom[2] = 0x0000; // @0
+        cpu.rom[3] = 0xea87; // 0;JMP
+        cpu.add_breakpoint(1);
+        cpu.break_before = true;
+        let stop = cpu.execute_instructions(Duration::from_secs(1)).unwrap();
+        assert_eq!(stop, StopReason::BreakPoint);
+        assert_eq!((cpu.pc, cpu.ram[16]), (1, 0));
+        // resuming runs the instruction at the breakpoint
+        cpu.execute_instructions(Duration::from_secs(1)).unwrap();
+        assert_eq!((cpu.pc, cpu.ram[16]), (1, 1));
+    }
+
+    #[test]
+    fn test_reverse_continue() {
+        let mut cpu = HackEngine::new();
+        // same program as test_cpu
+        cpu.rom[0] = 0x0002;
+        cpu.rom[1] = 0x8c10;
+        cpu.rom[2] = 0x0011;
+        cpu.rom[3] = 0x8308;
+        cpu.rom[4] = 0x000a;
+        cpu.rom[5] = 0x8304;
+        cpu.rom[6] = 0x0003;
+        cpu.rom[7] = 0x8c10;
+        cpu.rom[8] = 0x0010;
+        cpu.rom[9] = 0x8308;
+        cpu.rom[10] = 0x000a;
+        cpu.rom[11] = 0x8307;
+        loop {
+            if cpu.execute_instructions(Duration::ZERO).unwrap() == StopReason::HardLoop {
+                break;
+            }
+        }
+        assert_eq!(cpu.ram[16], 3);
+
+        cpu.add_watchpoint(16, false, true);
+        assert_eq!(cpu.reverse_continue(), Some(StopReason::WatchPoint));
+        assert_eq!(cpu.pc, 10);
+        assert_eq!(cpu.ram[16], 3);
+
+        cpu.add_breakpoint(3);
+        cpu.add_breakpoint(7);
+        cpu.break_points.get_mut(&7).unwrap().log = Some("x".to_string());
+        assert_eq!(cpu.reverse_continue(), Some(StopReason::BreakPoint));
+        assert_eq!(cpu.triggered_breakpoint, Some(3));
+        assert_eq!(cpu.pc, 4);
+        assert_eq!(cpu.ram[16], 0);
+        assert_eq!(cpu.ram[17], 2);
+
+        assert_eq!(cpu.reverse_continue(), None);
+        assert_eq!(cpu.pc, 0);
+        assert_eq!(cpu.ram[17], 0);
+    }
+
+    #[test]
+    fn test_range_watchpoint() {
+        let mut cpu = HackEngine::new();
+        cpu.rom[0] = 0x4005; // @0x4005
+        cpu.rom[1] = 0xefc8; // M=1
+        cpu.rom[2] = 0x4005; // @0x4005
+        cpu.rom[3] = 0xefc8; // M=1
+        cpu.rom[4] = 0x0010; // @16
+        cpu.rom[5] = 0xefc8; // M=1
+        cpu.add_watch_range(0x4000, 0x2000, false, true, WatchCondition::Changed);
+        cpu.add_watch_range(16, 1, false, true, WatchCondition::Equals(2));
+
+        assert_eq!(
+            cpu.execute_instructions(Duration::ZERO).unwrap(),
+            StopReason::RefreshUI
+        );
+        assert_eq!(
+            cpu.execute_instructions(Duration::ZERO).unwrap(),
+            StopReason::WatchPoint
+        );
+        assert_eq!(
+            cpu.triggered_watchpoint,
+            Some(WatchHit {
+                address: 0x4005,
+                pc: 1,
+                write: true,
+                old: 0,
+                new: 1
+            })
+        );
+        // same value again is not a change, and RAM[16] is never 2
+        for _ in 0..4 {
+            assert_eq!(
+                cpu.execute_instructions(Duration::ZERO).unwrap(),
+                StopReason::RefreshUI
+            );
+        }
+        assert_eq!(cpu.ram[16], 1);
+        assert_eq!(cpu.triggered_watchpoint, None);
+    }
+
+    #[test]
+    fn test_keyboard_and_screen_buffer() {
+        let mut cpu = HackEngine::new();
+        assert_eq!(cpu.get_ram(0x6000).unwrap(), 0);
+        cpu.set_key(b'k' as u16);
+        assert_eq!(cpu.get_ram(0x6000).unwrap(), b'k' as u16);
+        cpu.set_ram(0x6000, 5).unwrap();
+        assert_eq!(cpu.get_ram(0x6000).unwrap(), b'k' as u16);
+
+        cpu.set_screen_dirty(false);
+        cpu.set_ram(0x4001, 0x8000).unwrap();
+        assert!(cpu.screen_dirty());
+        assert_eq!(cpu.screen().len(), 0x2000);
+        assert_eq!(cpu.screen()[1], 0x8000);
+    }
+
     // Run a real C program compiled by hack_cc:
     //   factorial(5) + fib(7) = 120 + 13 = 133
     // The return value of main ends up at RAM[256] (top of the call stack).
     #[test]
     fn test_c_program_factorial_fib() {
         let mut engine = HackEngine::new();
-        let content = include_str!("../../tests/data/test2.hackem");
+        let content = include_str!("../../../tests/data/test2.hackem");
         engine.load_file(content).unwrap();
 
         let result = loop {
@@ -1107,7 +1546,7 @@ mod tests {
     #[test]
     fn test_hello_screen_output() {
         let mut engine = HackEngine::new();
-        let content = include_str!("../../hello.hackem");
+        let content = include_str!("../../../hello.hackem");
         engine.load_file(content).unwrap();
 
         let result = loop {
@@ -0,0 +1,102 @@
+/*
+
+Execution profiler
+
+Counts how often each address executes and, for flame graphs, under which
+call stack. The stack is the chain of return addresses saved in the VM
+frames below LCL (see debugger/callstack.rs); it is only walked again when
+LCL or the return address of the current frame changes, i.e. on a call or
+a return, so the cost per instruction is two array lookups and one hash
+map update.
+
+The counts are raw addresses; `HackSystem::profile` names them from the
+PDB.
+
+*/
+
+use std::collections::HashMap;
+
+const LCL: usize = 1;
+/// Guards against walking a corrupt stack forever.
+const MAX_DEPTH: usize = 1024;
+
+pub struct Profiler {
+    /// Counting; when false the counts are kept but not updated.
+    pub running: bool,
+    /// Executions of each address.
+    pub hits: Vec<u64>,
+    /// Distinct call stacks seen, as return addresses, outermost first.
+    pub stacks: Vec<Vec<u16>>,
+    /// Executions of each address under each call stack (index into `stacks`).
+    pub stack_hits: HashMap<(usize, u16), u64>,
+    stack_ids: HashMap<Vec<u16>, usize>,
+    /// LCL and return address the current stack was walked for.
+    frame: Option<(u16, u16)>,
+    stack: usize,
+}
+
+impl Default for Profiler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Profiler {
+    pub fn new() -> Self {
+        Self {
+            running: true,
+            hits: vec![0; 0x8000],
+            stacks: Vec::new(),
+            stack_hits: HashMap::new(),
+            stack_ids: HashMap::new(),
+            frame: None,
+            stack: 0,
+        }
+    }
+
+    /// Total number of executions recorded.
+    pub fn total(&self) -> u64 {
+        self.hits.iter().sum()
+    }
+
+    /// Count one execution of `pc`.
+    pub fn record(&mut self, pc: u16, ram: &[u16]) {
+        if !self.running {
+            return;
+        }
+        self.hits[pc as usize & 0x7fff] += 1;
+        let lcl = ram[LCL];
+        let frame = (lcl, ram[lcl.wrapping_sub(5) as usize & 0x7fff]);
+        if self.frame != Some(frame) {
+            self.frame = Some(frame);
+            let stack = Self::walk(ram);
+            self.stack = match self.stack_ids.get(&stack) {
+                Some(&id) => id,
+                None => {
+                    let id = self.stacks.len();
+                    self.stack_ids.insert(stack.clone(), id);
+                    self.stacks.push(stack);
+                    id
+                }
+            };
+        }
+        *self.stack_hits.entry((self.stack, pc)).or_insert(0) += 1;
+    }
+
+    /// Return addresses of the frames below LCL, outermost first.
+    fn walk(ram: &[u16]) -> Vec<u16> {
+        let mut stack = Vec::new();
+        let mut lcl = ram[LCL];
+        while (5..0x4000).contains(&lcl) && stack.len() < MAX_DEPTH {
+            let saved = lcl as usize - 5;
+            stack.push(ram[saved]);
+            let caller = ram[saved + 1];
+            if caller >= lcl {
+                break;
+            }
+            lcl = caller;
+        }
+        stack.reverse();
+        stack
+    }
+}
@@ -0,0 +1,153 @@
+/*
+
+Instruction trace
+
+While `HackEngine::tracer` is set, every executed instruction whose
+address passes the filter is recorded with the machine state after it
+ran, one line per instruction:
+
+    0010  EC10  D=-1            A=0011 D=FFFF M=0000
+    0011  E308  M=D             A=0011 D=FFFF M=FFFF  RAM[0011]=FFFF
+
+The lines go to a file as they are produced, or else to a ring buffer
+holding the most recent `capacity` entries.
+
+*/
+
+use std::collections::VecDeque;
+use std::fmt;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+use anyhow::Result;
+
+use super::engine::HackEngine;
+
+pub const DEFAULT_TRACE_SIZE: usize = 10_000;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct TraceEntry {
+    pub pc: u16,
+    pub instruction: u16,
+    /// A, D and M after the instruction; M is None when A is not a RAM address.
+    pub a: u16,
+    pub d: u16,
+    pub m: Option<u16>,
+    /// RAM word written by the instruction, as (address, value).
+    pub write: Option<(u16, u16)>,
+}
+
+impl fmt::Display for TraceEntry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:04X}  {:04X}  {:<14}  A={:04X} D={:04X} M=",
+            self.pc,
+            self.instruction,
+            HackEngine::disassemble_one(self.instruction),
+            self.a,
+            self.d
+        )?;
+        match self.m {
+            Some(m) => write!(f, "{:04X}", m)?,
+            None => write!(f, "----")?,
+        }
+        if let Some((address, value)) = self.write {
+            write!(f, "  RAM[{:04X}]={:04X}", address, value)?;
+        }
+        Ok(())
+    }
+}
+
+pub struct Tracer {
+    /// Recording; turning it off keeps the ring buffer for inspection.
+    pub enabled: bool,
+    /// Inclusive address ranges to trace; empty traces everything.
+    pub ranges: Vec<(u16, u16)>,
+    /// Most recent entries, oldest first, when not writing to a file.
+    pub entries: VecDeque<TraceEntry>,
+    pub capacity: usize,
+    file: Option<BufWriter<File>>,
+    /// Number of entries recorded.
+    pub count: u64,
+}
+
+impl Tracer {
+    /// Trace into a ring buffer of `capacity` entries.
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            enabled: true,
+            ranges: Vec::new(),
+            entries: VecDeque::new(),
+            capacity,
+            file: None,
+            count: 0,
+        }
+    }
+
+    /// Trace into `path`, replacing its contents.
+    pub fn to_file(path: &Path) -> Result<Self> {
+        let mut tracer = Self::new(0);
+        tracer.file = Some(BufWriter::new(File::create(path)?));
+        Ok(tracer)
+    }
+
+    pub fn wants(&self, pc: u16) -> bool {
+        self.enabled
+            && (self.ranges.is_empty() || self.ranges.iter().any(|&(s, e)| (s..=e).contains(&pc)))
+    }
+
+    pub fn record(&mut self, entry: TraceEntry) -> Result<()> {
+        self.count += 1;
+        if let Some(file) = &mut self.file {
+            writeln!(file, "{}", entry)?;
+            return Ok(());
+        }
+        if self.entries.len() >= self.capacity {
+            self.entries.pop_front();
+        }
+        if self.capacity > 0 {
+            self.entries.push_back(entry);
+        }
+        Ok(())
+    }
+
+    /// Stop recording and close the file, if any.
+    pub fn stop(&mut self) -> Result<()> {
+        self.enabled = false;
+        if let Some(mut file) = self.file.take() {
+            file.flush()?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use web_time::Duration;
+
+    #[test]
+    fn test_trace_ring_and_filter() {
+        let mut engine = HackEngine::new();
+        // @17  D=-1  M=D  @3  0;JMP
+        engine.load_rom(&[0x0011, 0xEE90, 0xE308, 0x0003, 0xEA87]);
+        let mut tracer = Tracer::new(2);
+        tracer.ranges.push((1, 3));
+        engine.tracer = Some(tracer);
+        engine.execute_instructions(Duration::from_secs(1)).unwrap();
+        let tracer = engine.tracer.as_ref().unwrap();
+        assert_eq!(tracer.count, 3);
+        assert_eq!(tracer.entries.len(), 2);
+        assert_eq!(tracer.entries[0].write, Some((17, 0xFFFF)));
+        assert_eq!(
+            tracer.entries[0].to_string(),
+            "0002  E308  M=D             A=0011 D=FFFF M=FFFF  RAM[0011]=FFFF"
+        );
+        assert_eq!(
+            tracer.entries[1].to_string(),
+            "0003  0003  @3              A=0003 D=FFFF M=0000"
+        );
+    }
+}
@@ -0,0 +1,34 @@
+//! GUI-free core of hackem: the Hack CPU emulator, program loader,
+//! assembler, disassembler, VM emulator, native Jack OS, expression evaluator and the `HackSystem` debugger model.
+//!
+//! The screen is exposed as raw words through `HackEngine::screen` and the
+//! keyboard is driven through `HackEngine::set_key`, so the core can be
+//! embedded without any UI toolkit. Extra peripherals can be plugged into
+//! `HackEngine::devices`.
+
+// the hack cpu + ram / rom emulator
+pub mod emulator {
+    mod code_loader;
+    pub mod devices;
+    pub mod engine;
+    pub mod profiler;
+    pub mod tracer;
+}
+
+// debugger support: symbols, expressions, snapshots
+pub mod debugger {
+    pub mod assemble;
+    pub mod callstack;
+    pub mod coverage;
+    pub mod debug_em;
+    pub mod disassemble;
+    pub mod expr;
+    pub mod native_os;
+    pub mod pdbio;
+    pub mod profile;
+    pub mod segments;
+    pub mod snapshot;
+    pub mod stepping;
+    pub mod testscript;
+    pub mod vm;
+}
@@ -0,0 +1,618 @@
+/*
+
+Debug Adapter Protocol server
+
+    hackem --dap [--port N]
+
+Lets an editor (VS Code, nvim-dap, Emacs dap-mode, ...) debug a Hack
+program: the adapter speaks DAP over stdin/stdout, or over one TCP
+connection on 127.0.0.1:N with `--port`.
+
+The launch request takes
+    program      .hx/.hack/.asm/.vm file or a directory of .vm files
+    pdb          optional PDB (JSON) for source lines and symbols
+    stopOnEntry  stop before the first instruction
+    nativeOs     run the Jack OS natively
+
+Breakpoints are set by source line through the PDB source map (the first
+address of the first line at or after the requested one) and stop before
+that address runs, so the editor shows the line about to run. A condition
+or log message that does not parse fails just that breakpoint. A program
+that halts ends the session with `exited` and `terminated`. Stepping uses
+the source-level step/next/finish of the shell, the stack comes from
+`backtrace`, and each frame has one scope per VM segment (named from PDB
+Var symbols) plus the registers. Bytes written to the output port become
+`output` events. In the debug console an input that parses as a shell
+command runs as one (e.g. `mem 256 16`, `watch x`); anything else is
+evaluated as an expression.
+
+There is one thread (the CPU). Requests are read on a separate thread so
+`pause` and friends arrive while the program runs in 50ms slices.
+
+*/
+
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpListener;
+use std::path::Path;
+use std::sync::mpsc::{self, Receiver, TryRecvError};
+
+use anyhow::{anyhow, bail, Result};
+use clap::ArgMatches;
+use common::pdb::database::Pdb;
+use serde_json::{json, Value};
+use web_time::Duration;
+
+use crate::debugger::shell::Shell;
+use crate::utils;
+use hackem_core::{
+    debugger::{debug_em::HackSystem, segments::Segment, vm::read_vm_dir},
+    emulator::engine::StopReason,
+};
+
+const THREAD_ID: i64 = 1;
+/// variablesReference = frame * SCOPES_PER_FRAME + scope + 1
+const SCOPES_PER_FRAME: i64 = 16;
+const REGISTERS_SCOPE: i64 = 15;
+
+/// Run the adapter and return the process exit code.
+pub fn run(args: &ArgMatches) -> i32 {
+    // shell messages must not end up in the protocol stream
+    utils::set_say_cb(|s, _| eprintln!("{}", s));
+    let served = match args.get_one::<u16>("port") {
+        Some(&port) => TcpListener::bind(("127.0.0.1", port))
+            .and_then(|listener| {
+                eprintln!("DAP server listening on 127.0.0.1:{}", port);
+                listener.accept()
+            })
+            .map_err(anyhow::Error::from)
+            .and_then(|(stream, _)| serve(stream.try_clone()?, stream)),
+        None => serve(std::io::stdin(), std::io::stdout()),
+    };
+    match served {
+        Ok(()) => 0,
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            1
+        }
+    }
+}
+
+fn serve(input: impl Read + Send + 'static, output: impl Write) -> Result<()> {
+    let (tx, rx) = mpsc::channel();
+    std::thread::spawn(move || {
+        let mut reader = BufReader::new(input);
+        while let Ok(Some(message)) = read_message(&mut reader) {
+            if tx.send(message).is_err() {
+                break;
+            }
+        }
+    });
+    DapServer::new(output).serve(rx)
+}
+
+/// Read one `Content-Length` framed message; None at end of input.
+fn read_message(reader: &mut impl BufRead) -> Result<Option<Value>> {
+    let mut length = None;
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line)? == 0 {
+            return Ok(None);
+        }
+        let line = line.trim();
+        if line.is_empty() {
+            break;
+        }
+        if let Some(value) = line.strip_prefix("Content-Length:") {
+            length = Some(value.trim().parse::<usize>()?);
+        }
+    }
+    let length = length.ok_or_else(|| anyhow!("Message without Content-Length"))?;
+    let mut body = vec![0; length];
+    reader.read_exact(&mut body)?;
+    Ok(Some(serde_json::from_slice(&body)?))
+}
+
+struct DapServer<W: Write> {
+    output: W,
+    seq: i64,
+    hacksys: HackSystem,
+    shell: Shell,
+    running: bool,
+    /// Addresses of the breakpoints set for each PDB file.
+    source_breakpoints: HashMap<usize, Vec<u16>>,
+    stop_on_entry: bool,
+    done: bool,
+}
+
+impl<W: Write> DapServer<W> {
+    fn new(output: W) -> Self {
+        let mut hacksys = HackSystem::new();
+        hacksys.engine.break_before = true;
+        Self {
+            output,
+            seq: 0,
+            hacksys,
+            shell: Shell::new(),
+            running: false,
+            source_breakpoints: HashMap::new(),
+            stop_on_entry: false,
+            done: false,
+        }
+    }
+
+    fn serve(&mut self, rx: Receiver<Value>) -> Result<()> {
+        while !self.done {
+            let message = if self.running {
+                match rx.try_recv() {
+                    Ok(message) => Some(message),
+                    Err(TryRecvError::Empty) => None,
+                    Err(TryRecvError::Disconnected) => break,
+                }
+            } else {
+                match rx.recv() {
+                    Ok(message) => Some(message),
+                    Err(_) => break,
+                }
+            };
+            match message {
+                Some(request) => self.request(&request)?,
+                None => self.run_slice()?,
+            }
+        }
+        Ok(())
+    }
+
+    fn send(&mut self, mut message: Value) -> Result<()> {
+        self.seq += 1;
+        message["seq"] = json!(self.seq);
+        let body = message.to_string();
+        write!(
+            self.output,
+            "Content-Length: {}\r\n\r\n{}",
+            body.len(),
+            body
+        )?;
+        self.output.flush()?;
+        Ok(())
+    }
+
+    fn event(&mut self, event: &str, body: Value) -> Result<()> {
+        self.send(json!({ "type": "event", "event": event, "body": body }))
+    }
+
+    fn stopped(&mut self, reason: &str, text: Option<String>) -> Result<()> {
+        self.running = false;
+        self.flush_output()?;
+        let mut body =
+            json!({ "reason": reason, "threadId": THREAD_ID, "allThreadsStopped": true });
+        if let Some(text) = text {
+            body["description"] = json!(text);
+            body["text"] = json!(text);
+        }
+        self.event("stopped", body)
+    }
+
+    /// The program has finished: there is nothing left to continue.
+    fn exited(&mut self, text: String) -> Result<()> {
+        self.running = false;
+        self.flush_output()?;
+        self.event(
+            "output",
+            json!({ "category": "console", "output": format!("{}\n", text) }),
+        )?;
+        self.event("exited", json!({ "exitCode": 0 }))?;
+        self.event("terminated", json!({}))
+    }
+
+    /// Forward output port bytes and logpoint messages to the editor.
+    fn flush_output(&mut self) -> Result<()> {
+        let output = self.hacksys.engine.take_output();
+        if !output.is_empty() {
+            self.event("output", json!({ "category": "stdout", "output": output }))?;
+        }
+        for message in self.hacksys.take_messages() {
+            self.event(
+                "output",
+                json!({ "category": "console", "output": format!("{}\n", message) }),
+            )?;
+        }
+        Ok(())
+    }
+
+    fn run_slice(&mut self) -> Result<()> {
+        let stop = self.hacksys.execute_instructions(Duration::from_millis(50));
+        let pc = self.hacksys.engine.pc;
+        match stop {
+            Ok(StopReason::RefreshUI | StopReason::Intercept) => self.flush_output(),
+            Ok(StopReason::BreakPoint) => self.stopped("breakpoint", None),
+            Ok(StopReason::WatchPoint) => {
+                let text = self
+                    .hacksys
+                    .engine
+                    .triggered_watchpoint
+                    .as_ref()
+                    .map(|hit| hit.to_string());
+                self.stopped("data breakpoint", text)
+            }
+            Ok(StopReason::RunTo) => self.stopped("step", None),
+            Ok(StopReason::SysHalt) => self.exited("Program halted (Sys.halt)".to_string()),
+            Ok(StopReason::HardLoop) => self.exited(format!("Program halted at 0x{:04X}", pc)),
+            Ok(StopReason::InstructionLimit) => {
+                self.stopped("pause", Some("Instruction limit reached".to_string()))
+            }
+            Err(e) => self.stopped("exception", Some(e.to_string())),
+        }
+    }
+
+    fn request(&mut self, request: &Value) -> Result<()> {
+        let command = request["command"].as_str().unwrap_or("").to_string();
+        let args = &request["arguments"];
+        let result = self.handle(&command, args);
+        let ok = result.is_ok();
+        let mut response = json!({
+            "type": "response",
+            "request_seq": request["seq"],
+            "command": command,
+            "success": result.is_ok(),
+        });
+        match result {
+            Ok(body) => response["body"] = body,
+            Err(e) => response["message"] = json!(e.to_string()),
+        }
+        self.send(response)?;
+        if !ok {
+            return Ok(());
+        }
+        // events that must follow the response
+        match command.as_str() {
+            "initialize" => self.event("initialized", json!({})),
+            "configurationDone" if self.stop_on_entry => self.stopped("entry", None),
+            "configurationDone" | "continue" | "next" | "stepIn" | "stepOut" => {
+                self.running = true;
+                Ok(())
+            }
+            "pause" => self.stopped("pause", None),
+            "disconnect" | "terminate" => {
+                self.done = true;
+                self.event("terminated", json!({}))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn handle(&mut self, command: &str, args: &Value) -> Result<Value> {
+        match command {
+            "initialize" => Ok(json!({
+                "supportsConfigurationDoneRequest": true,
+                "supportsConditionalBreakpoints": true,
+                "supportsLogPoints": true,
+                "supportsTerminateRequest": true,
+            })),
+            "launch" => {
+                self.launch(args)?;
+                Ok(json!({}))
+            }
+            "setBreakpoints" => self.set_breakpoints(args),
+            "setExceptionBreakpoints" | "configurationDone" | "disconnect" | "terminate" => {
+                Ok(json!({}))
+            }
+            "threads" => Ok(json!({ "threads": [{ "id": THREAD_ID, "name": "Hack CPU" }] })),
+            "stackTrace" => self.stack_trace(),
+            "scopes" => self.scopes(args),
+            "variables" => self.variables(args),
+            "continue" => Ok(json!({ "allThreadsContinued": true })),
+            "next" | "stepIn" => {
+                self.hacksys.step_line(command == "next")?;
+                Ok(json!({}))
+            }
+            "stepOut" => {
+                self.hacksys.finish()?;
+                Ok(json!({}))
+            }
+            "pause" => Ok(json!({})),
+            "evaluate" => self.evaluate(args),
+            _ => bail!("Unsupported request '{}'", command),
+        }
+    }
+
+    fn launch(&mut self, args: &Value) -> Result<()> {
+        let Some(program) = args["program"].as_str() else {
+            bail!("launch needs a 'program'");
+        };
+        let path = Path::new(program);
+        if path.is_dir() {
+            self.hacksys.load_vm(&read_vm_dir(path)?)?;
+        } else {
+            let text = std::fs::read_to_string(path)?;
+            self.hacksys.load_code(path, &text)?;
+        }
+        if let Some(pdb) = args["pdb"].as_str() {
+            self.hacksys.pdb = Pdb::load_json(&std::fs::read_to_string(pdb)?)?;
+            self.hacksys.load_waw()?;
+        }
+        if args["nativeOs"].as_bool() == Some(true) {
+            self.hacksys.set_native_os(true);
+        }
+        self.stop_on_entry = args["stopOnEntry"].as_bool() == Some(true);
+        self.source_breakpoints.clear();
+        Ok(())
+    }
+
+    /// The PDB file a client path refers to: same path, same file after
+    /// resolving, or failing that the same file name.
+    fn file_index(&self, path: &str) -> Option<usize> {
+        let files = &self.hacksys.pdb.file_info;
+        let path = Path::new(path);
+        let canonical = path.canonicalize().ok();
+        files
+            .iter()
+            .position(|f| f.name == path)
+            .or_else(|| {
+                files
+                    .iter()
+                    .position(|f| canonical.is_some() && f.name.canonicalize().ok() == canonical)
+            })
+            .or_else(|| {
+                files
+                    .iter()
+                    .position(|f| f.name.file_name() == path.file_name())
+            })
+    }
+
+    fn set_breakpoints(&mut self, args: &Value) -> Result<Value> {
+        let path = args["source"]["path"].as_str().unwrap_or("");
+        let file = self.file_index(path);
+        if let Some(file) = file {
+            for addr in self.source_breakpoints.remove(&file).unwrap_or_default() {
+                self.hacksys.engine.remove_breakpoint(addr);
+            }
+        }
+        let mut result = Vec::new();
+        let mut addrs = Vec::new();
+        for bp in args["breakpoints"].as_array().into_iter().flatten() {
+            let line = bp["line"].as_u64().unwrap_or(0) as usize;
+            // first line with code at or after the requested one
+            let location = file.and_then(|file| {
+                self.hacksys
+                    .pdb
+                    .source_map
+                    .iter()
+                    .filter(|s| s.file == file && s.line_no >= line)
+                    .min_by_key(|s| (s.line_no, s.addr))
+                    .map(|s| (s.line_no, s.addr))
+            });
+            let Some((line, addr)) = location else {
+                result.push(json!({ "verified": false, "message": "No code at this line" }));
+                continue;
+            };
+            let condition = bp["condition"].as_str().map(str::to_string);
+            let log = bp["logMessage"].as_str();
+            let checked = condition
+                .as_deref()
+                .map_or(Ok(()), HackSystem::check_expression)
+                .and_then(|_| log.map_or(Ok(()), HackSystem::check_format));
+            if let Err(e) = checked {
+                result.push(json!({ "verified": false, "line": line, "message": e.to_string() }));
+                continue;
+            }
+            self.hacksys.engine.add_breakpoint_if(addr, condition);
+            self.hacksys.engine.break_points.get_mut(&addr).unwrap().log = log.map(str::to_string);
+            addrs.push(addr);
+            result.push(json!({ "verified": true, "line": line }));
+        }
+        if let Some(file) = file {
+            self.source_breakpoints.insert(file, addrs);
+        }
+        Ok(json!({ "breakpoints": result }))
+    }
+
+    fn stack_trace(&mut self) -> Result<Value> {
+        let frames = self.hacksys.backtrace();
+        let frames: Vec<Value> = frames
+            .iter()
+            .enumerate()
+            .map(|(i, frame)| {
+                // callers are at their return address; show the call itself
+                let pc = if i == 0 {
+                    frame.pc
+                } else {
+                    frame.pc.wrapping_sub(1)
+                };
+                let mut value = json!({
+                    "id": i,
+                    "name": frame.function.as_deref().unwrap_or("??"),
+                    "line": 0,
+                    "column": 0,
+                    "instructionPointerReference": format!("0x{:04X}", frame.pc),
+                });
+                let location = self.hacksys.where_are_we(pc);
+                let file = location
+                    .cfile
+                    .and_then(|f| self.hacksys.pdb.file_info.get(f));
+                if let Some(file) = file {
+                    value["line"] = json!(location.cline);
+                    value["column"] = json!(1);
+                    value["source"] = json!({
+                        "name": file.name.file_name().map(|n| n.to_string_lossy()),
+                        "path": file.name,
+                    });
+                }
+                value
+            })
+            .collect();
+        Ok(json!({ "stackFrames": frames, "totalFrames": frames.len() }))
+    }
+
+    fn scopes(&mut self, args: &Value) -> Result<Value> {
+        let frame = args["frameId"].as_i64().unwrap_or(0);
+        self.hacksys.select_frame(frame as usize)?;
+        let mut scopes: Vec<Value> = self
+            .hacksys
+            .vm_segments()
+            .iter()
+            .enumerate()
+            .map(|(i, view)| {
+                json!({
+                    "name": view.segment.to_string(),
+                    "variablesReference": frame * SCOPES_PER_FRAME + i as i64 + 1,
+                    "namedVariables": view.slots.len(),
+                    "expensive": false,
+                    "presentationHint": match view.segment {
+                        Segment::Local => "locals",
+                        Segment::Argument => "arguments",
+                        _ => "",
+                    },
+                })
+            })
+            .collect();
+        scopes.push(json!({
+            "name": "registers",
+            "variablesReference": frame * SCOPES_PER_FRAME + REGISTERS_SCOPE + 1,
+            "expensive": false,
+            "presentationHint": "registers",
+        }));
+        Ok(json!({ "scopes": scopes }))
+    }
+
+    fn variables(&mut self, args: &Value) -> Result<Value> {
+        let reference = args["variablesReference"].as_i64().unwrap_or(0) - 1;
+        let (frame, scope) = (reference / SCOPES_PER_FRAME, reference % SCOPES_PER_FRAME);
+        self.hacksys.select_frame(frame.max(0) as usize)?;
+        let value = |v: u16| format!("{} (0x{:04X})", v as i16, v);
+        let variables: Vec<Value> = if scope == REGISTERS_SCOPE {
+            let engine = &self.hacksys.engine;
+            let mut registers = vec![("PC", engine.pc), ("A", engine.a), ("D", engine.d)];
+            for (i, name) in ["SP", "LCL", "ARG", "THIS", "THAT"].into_iter().enumerate() {
+                registers.push((name, engine.ram[i]));
+            }
+            registers
+                .into_iter()
+                .map(
+                    |(name, v)| json!({ "name": name, "value": value(v), "variablesReference": 0 }),
+                )
+                .collect()
+        } else {
+            let views = self.hacksys.vm_segments();
+            let Some(view) = views.get(scope.max(0) as usize) else {
+                bail!("Unknown variables reference {}", reference + 1);
+            };
+            view.slots
+                .iter()
+                .map(|slot| {
+                    json!({
+                        "name": slot
+                            .name
+                            .clone()
+                            .unwrap_or_else(|| format!("{} {}", view.segment, slot.index)),
+                        "value": value(slot.value),
+                        "variablesReference": 0,
+                        "memoryReference": format!("0x{:04X}", slot.address),
+                    })
+                })
+                .collect()
+        };
+        Ok(json!({ "variables": variables }))
+    }
+
+    /// Debug console input runs as a shell command if it is one, else as an
+    /// expression; hovers and watches are always expressions.
+    fn evaluate(&mut self, args: &Value) -> Result<Value> {
+        let expression = args["expression"].as_str().unwrap_or("").trim();
+        if args["context"].as_str() == Some("repl") {
+            if let Ok(response) = self.shell.dispatch(expression, &mut self.hacksys) {
+                let (output, action) = Shell::split_action(&response);
+                match action {
+                    Some("__go__" | "__run__") => {
+                        self.running = true;
+                        self.event("continued", json!({ "threadId": THREAD_ID }))?;
+                    }
+                    Some("__stop__") => self.stopped("pause", None)?,
+                    Some(_) => {
+                        self.done = true;
+                        self.event("terminated", json!({}))?;
+                    }
+                    None => {}
+                }
+                return Ok(json!({ "result": output, "variablesReference": 0 }));
+            }
+        }
+        let v = self.hacksys.evaluate(expression)?;
+        Ok(json!({
+            "result": format!("{} (0x{:04X})", v as i16, v),
+            "variablesReference": 0,
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_session() {
+        let dir = std::env::temp_dir().join("hackem_dap");
+        std::fs::create_dir_all(&dir).unwrap();
+        let asm = dir.join("Count.asm");
+        std::fs::write(&asm, "@5\nD=A\n@0\nM=D\n(END)\n@END\n0;JMP\n").unwrap();
+        let path = asm.to_string_lossy();
+
+        let mut server = DapServer::new(Vec::new());
+        let (tx, rx) = mpsc::channel();
+        for (seq, (command, arguments)) in [
+            ("initialize", json!({})),
+            ("launch", json!({ "program": path, "stopOnEntry": false })),
+            (
+                "setBreakpoints",
+                json!({ "source": { "path": path }, "breakpoints": [
+                    { "line": 4 },
+                    { "line": 2, "condition": "@(0" },
+                ] }),
+            ),
+            ("configurationDone", json!({})),
+        ]
+        .into_iter()
+        .enumerate()
+        {
+            tx.send(json!({ "seq": seq + 1, "type": "request", "command": command, "arguments": arguments }))
+                .unwrap();
+        }
+        drop(tx);
+        server.serve(rx).unwrap();
+        // the channel closed while running: finish the slice by hand
+        while server.running {
+            server.run_slice().unwrap();
+        }
+        // line 4 (M=D) is address 3; breakpoints stop before it runs
+        assert_eq!(server.hacksys.engine.triggered_breakpoint, Some(3));
+        assert_eq!(server.hacksys.engine.pc, 3);
+        assert_eq!(server.hacksys.engine.ram[0], 0);
+        assert_eq!(server.stack_trace().unwrap()["stackFrames"][0]["line"], 4);
+
+        let output = String::from_utf8(server.output.clone()).unwrap();
+        let mut reader = BufReader::new(output.as_bytes());
+        let mut messages = Vec::new();
+        while let Some(message) = read_message(&mut reader).unwrap() {
+            messages.push(message);
+        }
+        assert_eq!(messages[1]["event"], "initialized");
+        assert_eq!(messages[3]["body"]["breakpoints"][0]["verified"], true);
+        assert_eq!(messages[3]["body"]["breakpoints"][1]["verified"], false);
+        assert_eq!(server.source_breakpoints[&0], [3]);
+        assert_eq!(messages.last().unwrap()["body"]["reason"], "breakpoint");
+
+        // running into the end loop finishes the session
+        server.output.clear();
+        server.running = true;
+        while server.running {
+            server.run_slice().unwrap();
+        }
+        let output = String::from_utf8(server.output.clone()).unwrap();
+        let mut reader = BufReader::new(output.as_bytes());
+        let mut events = Vec::new();
+        while let Some(message) = read_message(&mut reader).unwrap() {
+            events.push(message["event"].as_str().unwrap_or("").to_string());
+        }
+        assert_eq!(events[events.len() - 2..], ["exited", "terminated"]);
+    }
+}
@@ -1,19 +1,104 @@
 use std::path::Path;
 
-use anyhow::Result;
+use anyhow::{anyhow, bail, Result};
 use common::pdb::database::Pdb;
 use web_time::Duration;
 
 use crate::utils;
+use hackem_core::{
+    debugger::{callstack::Frame, debug_em::HackSystem, vm::read_vm_dir},
+    emulator::{
+        engine::{HackEngine, StopReason, WatchCondition},
+        tracer::Tracer,
+    },
+};
 
-use super::{debug_em::HackSystem, syntax};
+use super::syntax;
 
-pub struct Shell {}
+/// Responses that ask the caller to act instead of printing something.
+pub const ACTIONS: &[&str] = &["__go__", "__run__", "__stop__", "__quit__"];
+
+/// How deep `source` may nest.
+const MAX_SOURCE_DEPTH: usize = 16;
+
+pub struct Shell {
+    source_depth: usize,
+}
 
 impl Shell {
     pub fn new() -> Self {
         utils::set_say_cb(Self::say);
-        Self {}
+        Self { source_depth: 0 }
+    }
+
+    /// Split a response into its output and the action on its last line,
+    /// if any. A script that ends with `go` answers with its output
+    /// followed by `__go__`.
+    pub fn split_action(response: &str) -> (&str, Option<&str>) {
+        let (output, last) = response.rsplit_once('\n').unwrap_or(("", response));
+        if ACTIONS.contains(&last) {
+            (output, Some(last))
+        } else {
+            (response, None)
+        }
+    }
+
+    /// Run the commands in a script, skipping blank lines and `#` comments
+    /// and stopping at the first error. A command that starts the program
+    /// has to be the last one; the program runs once the script is done.
+    fn source(&mut self, path: &Path, hacksys: &mut HackSystem) -> Result<String> {
+        if self.source_depth >= MAX_SOURCE_DEPTH {
+            bail!("Scripts nested more than {} deep", MAX_SOURCE_DEPTH);
+        }
+        let text =
+            std::fs::read_to_string(path).map_err(|e| anyhow!("{}: {}", path.display(), e))?;
+        self.source_depth += 1;
+        let result = self.source_lines(path, &text, hacksys);
+        self.source_depth -= 1;
+        result
+    }
+
+    fn source_lines(
+        &mut self,
+        path: &Path,
+        text: &str,
+        hacksys: &mut HackSystem,
+    ) -> Result<String> {
+        let mut output = Vec::new();
+        let mut lines = text
+            .lines()
+            .enumerate()
+            .map(|(n, line)| (n + 1, line.trim()))
+            .filter(|(_, line)| !line.is_empty() && !line.starts_with('#'))
+            .peekable();
+        while let Some((n, line)) = lines.next() {
+            let response = match self.dispatch(line, hacksys) {
+                Ok(response) => response,
+                Err(e) => {
+                    hacksys.clear_goal();
+                    output.push(format!("{}:{}: {}", path.display(), n, e));
+                    return Err(anyhow!(output.join("\n")));
+                }
+            };
+            let (text, action) = Self::split_action(&response);
+            if !text.is_empty() {
+                output.push(text.to_string());
+            }
+            if let Some(action) = action {
+                if lines.peek().is_some() {
+                    hacksys.clear_goal();
+                    output.push(format!(
+                        "{}:{}: '{}' must be the last command of a script",
+                        path.display(),
+                        n,
+                        line
+                    ));
+                    return Err(anyhow!(output.join("\n")));
+                }
+                output.push(action.to_string());
+            }
+        }
+        Ok(output.join("\n"))
     }
 
     fn say(s: &str, _v: bool) {
@@ -43,20 +128,114 @@ impl Shell {
         Ok(hacksys.convert_addr(arg)?.0)
     }
 
+    /// Execute one instruction and show the registers.
+    fn step_instruction(&self, hacksys: &mut HackSystem) -> Result<String> {
+        let stop = hacksys.execute_instructions(Duration::ZERO)?;
+        let (pc, a, d) = hacksys.engine.get_registers();
+        let regs = format!("PC={:04X}  A={:04X}  D={:04X}", pc, a, d);
+        match (stop, &hacksys.engine.triggered_watchpoint) {
+            (StopReason::WatchPoint, Some(hit)) => Ok(format!("{}\n{}", hit, regs)),
+            _ => Ok(regs),
+        }
+    }
+
+    fn format_frame(index: usize, frame: &Frame) -> String {
+        format!(
+            "#{:<3} 0x{:04X}  {:<24} LCL=0x{:04X} ARG=0x{:04X}",
+            index,
+            frame.pc,
+            frame.function.as_deref().unwrap_or("??"),
+            frame.lcl,
+            frame.arg
+        )
+    }
+
+    fn format_range(start: u16, len: u16) -> String {
+        if len > 1 {
+            format!("0x{:04X}..0x{:04X}", start, start + (len - 1))
+        } else {
+            format!("0x{:04X}", start)
+        }
+    }
+
+    fn format_watch_condition(condition: &WatchCondition) -> String {
+        match condition {
+            WatchCondition::Always => String::new(),
+            WatchCondition::Equals(v) => format!("  == 0x{:04X}", v),
+            WatchCondition::Changed => "  changed".to_string(),
+        }
+    }
+
+    /// Split a command line into words. Quotes group words, so expressions
+    /// can contain spaces (`break f -if '@(0) > 3'`), and single-dash long
+    /// options such as `-if` are rewritten to the `--if` form clap expects.
+    fn split_line(line: &str) -> Result<Vec<String>> {
+        let mut words = Vec::new();
+        let mut word = String::new();
+        let mut in_word = false;
+        let mut quote = None;
+        for c in line.chars() {
+            match quote {
+                Some(q) if c == q => quote = None,
+                Some(_) => word.push(c),
+                None if c == '\'' || c == '"' => {
+                    quote = Some(c);
+                    in_word = true;
+                }
+                None if c.is_whitespace() => {
+                    if in_word {
+                        words.push(std::mem::take(&mut word));
+                        in_word = false;
+                    }
+                }
+                None => {
+                    word.push(c);
+                    in_word = true;
+                }
+            }
+        }
+        if quote.is_some() {
+            bail!("Unterminated quote");
+        }
+        if in_word {
+            words.push(word);
+        }
+        for word in words.iter_mut() {
+            if let Some(name) = word.strip_prefix('-') {
+                if syntax::SINGLE_DASH_LONG.contains(&name) {
+                    word.insert(0, '-');
+                }
+            }
+        }
+        Ok(words)
+    }
+
     pub fn dispatch(&mut self, line: &str, hacksys: &mut HackSystem) -> Result<String> {
-        let args = line.split_whitespace();
+        let args = Self::split_line(line)?;
+        self.dispatch_words(args, hacksys)
+    }
+
+    /// Run a command already split into words, e.g. from a JSON array.
+    /// Words are taken as they are: no quotes, and options in `--if` form.
+    pub fn dispatch_words(
+        &mut self,
+        args: Vec<String>,
+        hacksys: &mut HackSystem,
+    ) -> Result<String> {
         let matches = syntax::syntax().try_get_matches_from(args)?;
 
         match matches.subcommand() {
             // load
             Some(("load_code", args)) => {
                 let file = args.get_one::<String>("file").unwrap();
-                let bin = std::fs::read_to_string(Path::new(file))?;
-                hacksys.engine.load_file(&bin)?;
-                Ok(format!(
-                    "Loaded {}  ROM: {} words  RAM: {} words",
-                    file, hacksys.engine.rom_words_loaded, hacksys.engine.ram_words_loaded
-                ))
+                let path = Path::new(file);
+                if path.is_dir() {
+                    hacksys.load_vm(&read_vm_dir(path)?)?;
+                } else {
+                    let bin = std::fs::read_to_string(path)?;
+                    hacksys.load_code(path, &bin)?;
+                }
+                Ok(format!("Loaded {}  {}", file, hacksys.program_summary()))
             }
             Some(("load_pdb", args)) => {
                 let file = args.get_one::<String>("file").unwrap();
@@ -65,22 +244,329 @@ impl Shell {
                 hacksys.load_waw()?;
                 Ok(format!("Loaded PDB {}", file))
             }
+            Some(("save_state", args)) => {
+                let file = args.get_one::<String>("file").unwrap();
+                std::fs::write(Path::new(file), hacksys.save_state()?)?;
+                Ok(format!("Saved state to {}", file))
+            }
+            Some(("load_state", args)) => {
+                let file = args.get_one::<String>("file").unwrap();
+                let json = std::fs::read_to_string(Path::new(file))?;
+                hacksys.load_state(&json)?;
+                Ok(format!(
+                    "Loaded state {}  PC=0x{:04X}",
+                    file, hacksys.engine.pc
+                ))
+            }
+
+            Some(("test", args)) => {
+                let file = args.get_one::<String>("file").unwrap();
+                let script = std::fs::read_to_string(Path::new(file))?;
+                let report = hacksys.run_test_script(Path::new(file), &script)?;
+                let mut out = report.echoes.join("\n");
+                if !out.is_empty() {
+                    out.push('\n');
+                }
+                out.push_str(&report.to_string());
+                Ok(out)
+            }
+            Some(("native_os", args)) => {
+                let enabled = match args.get_one::<String>("state") {
+                    Some(state) => state == "on",
+                    None => hacksys.native_os.enabled,
+                };
+                let names = hacksys.set_native_os(enabled);
+                if !enabled {
+                    Ok("Native OS off".to_string())
+                } else if names.is_empty() {
+                    Ok("Native OS on (no OS functions in the program)".to_string())
+                } else {
+                    Ok(format!("Native OS on: {}", names.join(" ")))
+                }
+            }
+            Some(("profile", args)) => match args.get_one::<String>("action").map(|s| s.as_str()) {
+                Some("on") => {
+                    hacksys.set_profiling(true);
+                    Ok("Profiling on".to_string())
+                }
+                Some("off") => {
+                    hacksys.set_profiling(false);
+                    Ok("Profiling off".to_string())
+                }
+                Some("clear") => {
+                    hacksys.clear_profile();
+                    Ok("Profile cleared".to_string())
+                }
+                Some(_) => {
+                    let Some(file) = args.get_one::<String>("file") else {
+                        bail!("profile save needs a file name");
+                    };
+                    let folded = hacksys.folded_stacks()?;
+                    std::fs::write(file, &folded)?;
+                    Ok(format!(
+                        "{} stacks saved to {}",
+                        folded.lines().count(),
+                        file
+                    ))
+                }
+                None => {
+                    if hacksys.engine.profiler.is_none() {
+                        bail!("Nothing profiled; use 'profile on' first");
+                    }
+                    let count = *args.get_one::<usize>("count").unwrap();
+                    let mut report = hacksys.profile(count);
+                    report.functions.truncate(count);
+                    Ok(report.to_string())
+                }
+            },
+            Some(("coverage", args)) => {
+                match args.get_one::<String>("action").map(|s| s.as_str()) {
+                    Some("clear") => {
+                        hacksys.clear_coverage();
+                        Ok("Coverage cleared".to_string())
+                    }
+                    Some(_) => {
+                        let Some(file) = args.get_one::<String>("file") else {
+                            bail!("coverage save needs a file name");
+                        };
+                        std::fs::write(file, hacksys.lcov())?;
+                        Ok(format!("Coverage saved to {}", file))
+                    }
+                    None => {
+                        let files = hacksys.coverage();
+                        if files.is_empty() {
+                            bail!("No source map loaded");
+                        }
+                        let percent =
+                            |hit: usize, found: usize| hit as f64 * 100.0 / found.max(1) as f64;
+                        Ok(files
+                            .iter()
+                            .map(|f| {
+                                format!(
+                                    "{:>5.1}%  {}/{} lines  {}/{} functions  {}",
+                                    percent(f.lines_hit(), f.lines.len()),
+                                    f.lines_hit(),
+                                    f.lines.len(),
+                                    f.functions_hit(),
+                                    f.functions.len(),
+                                    f.path.display()
+                                )
+                            })
+                            .collect::<Vec<_>>()
+                            .join("\n"))
+                    }
+                }
+            }
 
             // navigation
             Some(("next_instruction", _)) | Some(("step_instruction", _)) => {
-                hacksys.engine.execute_instructions(Duration::ZERO)?;
+                self.step_instruction(hacksys)
+            }
+            Some(("step", _)) => {
+                hacksys.step_line(false)?;
+                Ok("__run__".to_string())
+            }
+            Some(("next", _)) => {
+                hacksys.step_line(true)?;
+                Ok("__run__".to_string())
+            }
+            Some(("step_over", _)) => {
+                if hacksys.step_over() {
+                    Ok("__run__".to_string())
+                } else {
+                    self.step_instruction(hacksys)
+                }
+            }
+            Some(("finish", _)) => {
+                hacksys.finish()?;
+                Ok("__run__".to_string())
+            }
+            Some(("until", args)) => {
+                let raw = args.get_one::<String>("address").unwrap();
+                let addr = self.resolve_addr(raw, hacksys)?;
+                hacksys.run_until(addr);
+                Ok("__run__".to_string())
+            }
+            Some(("reverse_step", args)) => {
+                let count = *args.get_one::<u32>("count").unwrap();
+                for _ in 0..count {
+                    if !hacksys.engine.reverse_step() {
+                        return Ok("No more history".to_string());
+                    }
+                }
                 let (pc, a, d) = hacksys.engine.get_registers();
                 Ok(format!("PC={:04X}  A={:04X}  D={:04X}", pc, a, d))
             }
+            Some(("reverse_continue", _)) => match hacksys.reverse_continue() {
+                Some(StopReason::BreakPoint) => {
+                    Ok(format!("Breakpoint hit at 0x{:04X}", hacksys.engine.pc))
+                }
+                Some(StopReason::WatchPoint) => Ok(hacksys
+                    .engine
+                    .triggered_watchpoint
+                    .take()
+                    .map(|hit| hit.to_string())
+                    .unwrap_or_default()),
+                _ => Ok(format!(
+                    "Reached start of history at 0x{:04X}",
+                    hacksys.engine.pc
+                )),
+            },
             Some(("go", _)) => Ok("__go__".to_string()),
             Some(("stop", _)) => Ok("__stop__".to_string()),
 
             // breakpoints
-            Some(("break", args)) => {
+            Some((cmd @ ("break" | "tbreak"), args)) => {
+                let raw = args.get_one::<String>("address").unwrap();
+                let addr = self.resolve_addr(raw, hacksys)?;
+                let condition = args.get_one::<String>("if").cloned();
+                if let Some(cond) = &condition {
+                    HackSystem::check_expression(cond)?;
+                }
+                if hacksys
+                    .engine
+                    .break_points
+                    .get(&addr)
+                    .is_some_and(|bp| bp.log.is_some())
+                {
+                    bail!("0x{:04X} has a logpoint; delete it first (dbp)", addr);
+                }
+                let mut msg = format!("Breakpoint set at 0x{:04X}", addr);
+                if let Some(cond) = &condition {
+                    msg.push_str(&format!(" if {}", cond));
+                }
+                hacksys.engine.add_breakpoint_if(addr, condition);
+                let bp = hacksys.engine.break_points.get_mut(&addr).unwrap();
+                if let Some(ignore) = args.get_one::<u32>("ignore") {
+                    bp.ignore_count = *ignore;
+                    msg.push_str(&format!(", ignoring {} hits", ignore));
+                }
+                if cmd == "tbreak" {
+                    bp.temporary = true;
+                    msg.push_str(" (temporary)");
+                }
+                Ok(msg)
+            }
+            Some(("trace", args)) => {
                 let raw = args.get_one::<String>("address").unwrap();
                 let addr = self.resolve_addr(raw, hacksys)?;
-                hacksys.engine.add_breakpoint(addr);
-                Ok(format!("Breakpoint set at 0x{:04X}", addr))
+                let format = args.get_one::<String>("format").unwrap();
+                HackSystem::check_format(format)?;
+                let condition = args.get_one::<String>("if").cloned();
+                if let Some(cond) = &condition {
+                    HackSystem::check_expression(cond)?;
+                }
+                if hacksys
+                    .engine
+                    .break_points
+                    .get(&addr)
+                    .is_some_and(|bp| bp.log.is_none())
+                {
+                    bail!("0x{:04X} has a breakpoint; delete it first (dbp)", addr);
+                }
+                hacksys.engine.add_breakpoint_if(addr, condition);
+                hacksys.engine.break_points.get_mut(&addr).unwrap().log = Some(format.clone());
+                Ok(format!("Logpoint set at 0x{:04X}", addr))
+            }
+            Some(("trace_on", args)) => {
+                if hacksys.vm_mode() {
+                    bail!("Tracing records Hack instructions; not available in VM mode");
+                }
+                let mut ranges = Vec::new();
+                for raw in args.get_many::<String>("range").unwrap_or_default() {
+                    let Some((start, end)) = raw.split_once("..") else {
+                        bail!("Invalid range {} (expected A..B)", raw);
+                    };
+                    let start = self.resolve_addr(start, hacksys)?;
+                    let end = self.resolve_addr(end, hacksys)?;
+                    if end < start {
+                        bail!("Invalid range {}", raw);
+                    }
+                    ranges.push((start, end));
+                }
+                for name in args.get_many::<String>("func").unwrap_or_default() {
+                    ranges.push(hacksys.function_range(name)?);
+                }
+                let file = args.get_one::<String>("file");
+                let mut tracer = match file {
+                    Some(file) => Tracer::to_file(Path::new(file))?,
+                    None => Tracer::new(*args.get_one::<usize>("size").unwrap()),
+                };
+                let filter = if ranges.is_empty() {
+                    "all addresses".to_string()
+                } else {
+                    ranges
+                        .iter()
+                        .map(|&(s, e)| format!("{:04X}..{:04X}", s, e))
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                };
+                tracer.ranges = ranges;
+                hacksys.engine.tracer = Some(tracer);
+                Ok(format!(
+                    "Tracing {} to {}",
+                    filter,
+                    file.map_or("memory", |f| f.as_str())
+                ))
+            }
+            Some(("trace_off", _)) => {
+                let Some(tracer) = &mut hacksys.engine.tracer else {
+                    bail!("Not tracing");
+                };
+                tracer.stop()?;
+                Ok(format!("Trace off: {} instructions traced", tracer.count))
+            }
+            Some(("trace_show", args)) => {
+                let Some(tracer) = &hacksys.engine.tracer else {
+                    bail!("No trace; use trace_on first");
+                };
+                let count = *args.get_one::<usize>("count").unwrap();
+                let skip = tracer.entries.len().saturating_sub(count);
+                Ok(tracer
+                    .entries
+                    .iter()
+                    .skip(skip)
+                    .map(|e| e.to_string())
+                    .collect::<Vec<_>>()
+                    .join("\n"))
+            }
+            Some((cmd @ ("enable" | "disable"), args)) => {
+                let enabled = cmd == "enable";
+                let watch = args.get_flag("watch");
+                let kind = if watch { "Watchpoint" } else { "Breakpoint" };
+                let state = if enabled { "enabled" } else { "disabled" };
+                if let Some(raw) = args.get_one::<String>("address") {
+                    let addr = self.resolve_addr(raw, hacksys)?;
+                    let flag = if watch {
+                        hacksys
+                            .engine
+                            .watch_points
+                            .get_mut(&addr)
+                            .map(|wp| &mut wp.enabled)
+                    } else {
+                        hacksys
+                            .engine
+                            .break_points
+                            .get_mut(&addr)
+                            .map(|bp| &mut bp.enabled)
+                    };
+                    match flag {
+                        Some(flag) => *flag = enabled,
+                        None => bail!("No {} at 0x{:04X}", kind.to_lowercase(), addr),
+                    }
+                    Ok(format!("{} at 0x{:04X} {}", kind, addr, state))
+                } else {
+                    if watch {
+                        for wp in hacksys.engine.watch_points.values_mut() {
+                            wp.enabled = enabled;
+                        }
+                    } else {
+                        for bp in hacksys.engine.break_points.values_mut() {
+                            bp.enabled = enabled;
+                        }
+                    }
+                    Ok(format!("All {}s {}", kind.to_lowercase(), state))
+                }
             }
             Some(("list_breakpoints", _)) => {
                 if hacksys.engine.break_points.is_empty() {
@@ -89,10 +575,24 @@ impl Shell {
                 let mut out = String::new();
                 for (addr, bp) in &hacksys.engine.break_points {
                     out.push_str(&format!(
-                        "0x{:04X}  {}\n",
+                        "0x{:04X}  {}",
                         addr,
                         if bp.enabled { "enabled" } else { "disabled" }
                     ));
+                    out.push_str(&format!("  hits {}", bp.hit_count));
+                    if bp.ignore_count > bp.hit_count {
+                        out.push_str(&format!("  ignore {}", bp.ignore_count - bp.hit_count));
+                    }
+                    if bp.temporary {
+                        out.push_str("  temporary");
+                    }
+                    if let Some(log) = &bp.log {
+                        out.push_str(&format!("  log \"{}\"", log));
+                    }
+                    if let Some(cond) = &bp.condition {
+                        out.push_str(&format!("  if {}", cond));
+                    }
+                    out.push('\n');
                 }
                 Ok(out.trim_end().to_string())
             }
@@ -110,7 +610,23 @@ impl Shell {
             // watchpoints
             Some(("watch", args)) => {
                 let raw = args.get_one::<String>("address").unwrap();
-                let addr = self.resolve_addr(raw, hacksys)?;
+                let (start, len) = match raw.split_once("..") {
+                    Some((start, end)) => {
+                        let start = self.resolve_addr(start, hacksys)?;
+                        let end = self.resolve_addr(end, hacksys)?;
+                        if end < start {
+                            bail!("Invalid range {}", raw);
+                        }
+                        (start, end - start + 1)
+                    }
+                    None => (
+                        self.resolve_addr(raw, hacksys)?,
+                        *args.get_one::<u16>("len").unwrap(),
+                    ),
+                };
+                if len == 0 || start as u32 + len as u32 > 0x8000 {
+                    bail!("Watch range must be inside RAM");
+                }
                 let read = args.get_flag("read");
                 let write = args.get_flag("write");
                 let (r, w) = if !read && !write {
@@ -118,13 +634,22 @@ impl Shell {
                 } else {
                     (read, write)
                 };
-                hacksys.engine.add_watchpoint(addr, r, w);
-                Ok(format!(
-                    "Watchpoint set at 0x{:04X} ({}{})",
-                    addr,
+                let condition = if let Some(raw) = args.get_one::<String>("equals") {
+                    WatchCondition::Equals(self.resolve_addr(raw, hacksys)?)
+                } else if args.get_flag("changed") {
+                    WatchCondition::Changed
+                } else {
+                    WatchCondition::Always
+                };
+                let mut msg = format!(
+                    "Watchpoint set at {} ({}{})",
+                    Self::format_range(start, len),
                     if r { "r" } else { "" },
                     if w { "w" } else { "" }
-                ))
+                );
+                msg.push_str(&Self::format_watch_condition(&condition));
+                hacksys.engine.add_watch_range(start, len, r, w, condition);
+                Ok(msg)
             }
             Some(("list_watchpoints", _)) => {
                 if hacksys.engine.watch_points.is_empty() {
@@ -133,10 +658,12 @@ impl Shell {
                 let mut out = String::new();
                 for (addr, wp) in &hacksys.engine.watch_points {
                     out.push_str(&format!(
-                        "0x{:04X}  {}{}\n",
-                        addr,
+                        "{}  {}{}  {}{}\n",
+                        Self::format_range(*addr, wp.len),
                         if wp.read { "r" } else { "" },
-                        if wp.write { "w" } else { "" }
+                        if wp.write { "w" } else { "" },
+                        if wp.enabled { "enabled" } else { "disabled" },
+                        Self::format_watch_condition(&wp.condition)
                     ));
                 }
                 Ok(out.trim_end().to_string())
@@ -152,7 +679,42 @@ impl Shell {
                 }
             }
 
+            // call stack
+            Some(("backtrace", _)) => {
+                let selected = hacksys.selected_frame_index();
+                let mut out = String::new();
+                for (i, frame) in hacksys.backtrace().iter().enumerate() {
+                    let marker = if i == selected { ">" } else { " " };
+                    out.push_str(&format!("{}{}\n", marker, Self::format_frame(i, frame)));
+                }
+                Ok(out.trim_end().to_string())
+            }
+            Some(("frame", args)) => {
+                let (index, frame) = match args.get_one::<usize>("index") {
+                    Some(index) => (*index, hacksys.select_frame(*index)?),
+                    None => (hacksys.selected_frame_index(), hacksys.selected_frame()),
+                };
+                Ok(Self::format_frame(index, &frame))
+            }
+
             // disassembly
+            Some(("asm", args)) => {
+                let addr =
+                    self.resolve_addr(args.get_one::<String>("address").unwrap(), hacksys)?;
+                let text = args
+                    .get_many::<String>("instruction")
+                    .unwrap()
+                    .cloned()
+                    .collect::<Vec<_>>()
+                    .join(" ");
+                let word = hacksys.assemble_at(addr, &text)?;
+                Ok(format!(
+                    "{:04X}  {:04X}  {}",
+                    addr,
+                    word,
+                    HackEngine::disassemble_one(word)
+                ))
+            }
             Some(("dis", args)) => {
                 let start = if let Some(raw) = args.get_one::<String>("address") {
                     self.resolve_addr(raw, hacksys)?
@@ -160,8 +722,20 @@ impl Shell {
                     hacksys.engine.pc
                 };
                 let count = *args.get_one::<u16>("count").unwrap_or(&16);
-                let lines = hacksys.engine.disassemble_range(start, count);
                 let mut out = String::new();
+                if let Some(vm) = &hacksys.vm {
+                    for (addr, text) in vm.listing(start, count) {
+                        let bp = if hacksys.engine.break_points.contains_key(&addr) {
+                            "*"
+                        } else {
+                            " "
+                        };
+                        let pc = if addr == hacksys.engine.pc { ">" } else { " " };
+                        out.push_str(&format!("{}{} {:04X}  {}\n", pc, bp, addr, text));
+                    }
+                    return Ok(out.trim_end().to_string());
+                }
+                let lines = hacksys.engine.disassemble_range(start, count);
                 for (addr, raw, mnemonic) in lines {
                     let bp = if hacksys.engine.break_points.contains_key(&addr) {
                         "*"
@@ -269,6 +843,10 @@ impl Shell {
             }
 
             // misc
+            Some(("source", args)) => {
+                let file = args.get_one::<String>("file").unwrap();
+                self.source(Path::new(file), hacksys)
+            }
             Some(("cd", args)) => {
                 let dir = args.get_one::<String>("directory").unwrap();
                 std::env::set_current_dir(dir)?;
@@ -281,3 +859,65 @@ impl Shell {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_split_line() {
+        let words = Shell::split_line("break Math.multiply -if '@(0) > 300'").unwrap();
+        assert_eq!(words, ["break", "Math.multiply", "--if", "@(0) > 300"]);
+        let words = Shell::split_line(r#"  mem  "=@(ptr + 0x20)" -n 4 "#).unwrap();
+        assert_eq!(words, ["mem", "=@(ptr + 0x20)", "-n", "4"]);
+        assert!(Shell::split_line("break f -if '@(0").is_err());
+    }
+
+    #[test]
+    fn test_break_and_trace() {
+        let mut shell = Shell::new();
+        let mut hacksys = HackSystem::new();
+        shell.dispatch("break 5", &mut hacksys).unwrap();
+        assert!(shell.dispatch("trace 5 \"x\"", &mut hacksys).is_err());
+        shell.dispatch("trace 6 \"x\"", &mut hacksys).unwrap();
+        assert!(shell.dispatch("break 6", &mut hacksys).is_err());
+        assert!(hacksys.engine.break_points[&5].log.is_none());
+        assert_eq!(hacksys.engine.break_points[&6].log.as_deref(), Some("x"));
+        // the same kind can be set again
+        shell.dispatch("break 5 --if 0", &mut hacksys).unwrap();
+        shell.dispatch("trace 6 \"y\"", &mut hacksys).unwrap();
+    }
+
+    #[test]
+    fn test_source() {
+        let dir = std::env::temp_dir().join("hackem_source");
+        std::fs::create_dir_all(&dir).unwrap();
+        let asm = dir.join("Two.asm");
+        std::fs::write(&asm, "(LOOP)\n@LOOP\n0;JMP\n").unwrap();
+        let script = dir.join("setup.hkm");
+        std::fs::write(
+            &script,
+            format!("# set up\n\nload \"{}\"\n  break LOOP\ngo\n", asm.display()),
+        )
+        .unwrap();
+
+        let mut shell = Shell::new();
+        let mut hacksys = HackSystem::new();
+        let line = format!("source \"{}\"", script.display());
+        let response = shell.dispatch(&line, &mut hacksys).unwrap();
+        let (output, action) = Shell::split_action(&response);
+        assert_eq!(action, Some("__go__"));
+        assert!(output.starts_with("Loaded"));
+        assert!(hacksys.engine.break_points.contains_key(&0));
+
+        std::fs::write(&script, "break 1\nbreak NOWHERE\nbreak 2\n").unwrap();
+        let error = shell.dispatch(&line, &mut hacksys).unwrap_err().to_string();
+        assert!(error.contains("setup.hkm:2: "));
+        assert!(hacksys.engine.break_points.contains_key(&1));
+        assert!(!hacksys.engine.break_points.contains_key(&2));
+
+        std::fs::write(&script, "go\nbreak 2\n").unwrap();
+        let error = shell.dispatch(&line, &mut hacksys).unwrap_err().to_string();
+        assert!(error.ends_with("setup.hkm:1: 'go' must be the last command of a script"));
+    }
+}
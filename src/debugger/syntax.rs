@@ -1,6 +1,9 @@
 use clap::{arg, Arg};
 use clap::{ArgGroup, Command};
 
+/// Long options that may also be typed with a single dash (`-if`).
+pub const SINGLE_DASH_LONG: &[&str] = &["if", "ignore", "equals", "changed", "range", "func"];
+
 pub fn syntax() -> Command {
     const PARSER_TEMPLATE: &str = "\
         {all-args}
@@ -22,7 +25,7 @@ pub fn syntax() -> Command {
         .subcommand(
             Command::new("load_code")
                 .visible_alias("load")
-                .about("Load binary file (.hx or raw binary)")
+                .about("Load binary file (.hx or raw binary) or assemble .asm source")
                 .arg(Arg::new("file").required(true))
                 .arg_required_else_help(true)
                 .help_template(APPLET_TEMPLATE),
@@ -35,6 +38,63 @@ pub fn syntax() -> Command {
                 .arg_required_else_help(true)
                 .help_template(APPLET_TEMPLATE),
         )
+        .subcommand(
+            Command::new("save_state")
+                .about("Save a snapshot of the whole machine")
+                .arg(Arg::new("file").required(true))
+                .arg_required_else_help(true)
+                .help_template(APPLET_TEMPLATE),
+        )
+        .subcommand(
+            Command::new("load_state")
+                .about("Restore a snapshot saved by save_state")
+                .arg(Arg::new("file").required(true))
+                .arg_required_else_help(true)
+                .help_template(APPLET_TEMPLATE),
+        )
+        .subcommand(
+            Command::new("test")
+                .about("Run a .tst test script, comparing its output with the .cmp file")
+                .arg(Arg::new("file").required(true))
+                .arg_required_else_help(true)
+                .help_template(APPLET_TEMPLATE),
+        )
+        .subcommand(
+            Command::new("native_os")
+                .about("Run Jack OS functions natively instead of their code")
+                .arg(Arg::new("state").value_parser(["on", "off"]))
+                .help_template(APPLET_TEMPLATE),
+        )
+        .subcommand(
+            Command::new("profile")
+                .about("Count executions per address and function")
+                .arg(
+                    Arg::new("action")
+                        .value_parser(["on", "off", "clear", "save"])
+                        .help("Start, stop, reset or export (default: show the profile)"),
+                )
+                .arg(Arg::new("file").help("Folded-stack file for 'save', for flame graph tools"))
+                .arg(
+                    Arg::new("count")
+                        .short('n')
+                        .long("count")
+                        .value_parser(clap::value_parser!(usize))
+                        .default_value("20")
+                        .help("Number of functions and addresses to show"),
+                )
+                .help_template(APPLET_TEMPLATE),
+        )
+        .subcommand(
+            Command::new("coverage")
+                .about("Show or save which source lines have executed")
+                .arg(
+                    Arg::new("action")
+                        .value_parser(["save", "clear"])
+                        .help("Write an LCOV file or reset (default: show a summary)"),
+                )
+                .arg(Arg::new("file").help("LCOV .info file for 'save'"))
+                .help_template(APPLET_TEMPLATE),
+        )
         .subcommand(
             Command::new("quit")
                 .visible_aliases(["exit", "q"])
@@ -53,6 +113,56 @@ pub fn syntax() -> Command {
                 .about("Step one instruction (alias of ni)")
                 .help_template(APPLET_TEMPLATE),
         )
+        .subcommand(
+            Command::new("step")
+                .visible_alias("s")
+                .about("Step one source line, into calls")
+                .help_template(APPLET_TEMPLATE),
+        )
+        .subcommand(
+            Command::new("next")
+                .visible_alias("n")
+                .about("Step one source line, over calls")
+                .help_template(APPLET_TEMPLATE),
+        )
+        .subcommand(
+            Command::new("step_over")
+                .visible_alias("so")
+                .about("Step one instruction, running a VM call until it returns")
+                .help_template(APPLET_TEMPLATE),
+        )
+        .subcommand(
+            Command::new("finish")
+                .visible_alias("fin")
+                .about("Run until the current VM function returns")
+                .help_template(APPLET_TEMPLATE),
+        )
+        .subcommand(
+            Command::new("until")
+                .visible_alias("u")
+                .about("Run until PC reaches an address")
+                .arg(Arg::new("address").required(true))
+                .arg_required_else_help(true)
+                .help_template(APPLET_TEMPLATE),
+        )
+        .subcommand(
+            Command::new("reverse_step")
+                .visible_alias("rsi")
+                .about("Step back one instruction")
+                .arg(
+                    Arg::new("count")
+                        .value_parser(clap::value_parser!(u32))
+                        .default_value("1")
+                        .help("Number of instructions"),
+                )
+                .help_template(APPLET_TEMPLATE),
+        )
+        .subcommand(
+            Command::new("reverse_continue")
+                .visible_alias("rc")
+                .about("Run backwards to the previous breakpoint or watchpoint")
+                .help_template(APPLET_TEMPLATE),
+        )
         .subcommand(
             Command::new("go")
                 .visible_alias("g")
@@ -70,15 +180,151 @@ pub fn syntax() -> Command {
                 .about("Set breakpoint")
                 .visible_alias("b")
                 .arg(Arg::new("address").required(true))
+                .arg(
+                    Arg::new("if")
+                        .long("if")
+                        .value_name("EXPR")
+                        .help("Only stop when the expression is non-zero"),
+                )
+                .arg(
+                    Arg::new("ignore")
+                        .long("ignore")
+                        .value_name("N")
+                        .value_parser(clap::value_parser!(u32))
+                        .help("Skip the first N hits"),
+                )
+                .help_template(APPLET_TEMPLATE),
+        )
+        .subcommand(
+            Command::new("tbreak")
+                .about("Set temporary breakpoint, deleted when first hit")
+                .visible_alias("tb")
+                .arg(Arg::new("address").required(true))
+                .arg(
+                    Arg::new("if")
+                        .long("if")
+                        .value_name("EXPR")
+                        .help("Only stop when the expression is non-zero"),
+                )
+                .arg(
+                    Arg::new("ignore")
+                        .long("ignore")
+                        .value_name("N")
+                        .value_parser(clap::value_parser!(u32))
+                        .help("Skip the first N hits"),
+                )
+                .help_template(APPLET_TEMPLATE),
+        )
+        .subcommand(
+            Command::new("trace")
+                .about("Print a message each time an address is reached, without stopping")
+                .visible_alias("tp")
+                .arg(Arg::new("address").required(true))
+                .arg(
+                    Arg::new("format")
+                        .required(true)
+                        .help("Message; {expr} is replaced by the expression's value"),
+                )
+                .arg(
+                    Arg::new("if")
+                        .long("if")
+                        .value_name("EXPR")
+                        .help("Only print when the expression is non-zero"),
+                )
+                .arg_required_else_help(true)
+                .help_template(APPLET_TEMPLATE),
+        )
+        .subcommand(
+            Command::new("trace_on")
+                .about("Record every executed instruction with A, D, M and RAM writes")
+                .arg(
+                    Arg::new("file")
+                        .help("Write the trace here (default: keep the last entries in memory)"),
+                )
+                .arg(
+                    Arg::new("range")
+                        .long("range")
+                        .value_name("A..B")
+                        .action(clap::ArgAction::Append)
+                        .help("Only trace addresses A to B (repeatable)"),
+                )
+                .arg(
+                    Arg::new("func")
+                        .long("func")
+                        .value_name("NAME")
+                        .action(clap::ArgAction::Append)
+                        .help("Only trace this function (repeatable)"),
+                )
+                .arg(
+                    Arg::new("size")
+                        .long("size")
+                        .value_parser(clap::value_parser!(usize))
+                        .default_value("10000")
+                        .help("Entries kept in memory"),
+                )
+                .help_template(APPLET_TEMPLATE),
+        )
+        .subcommand(
+            Command::new("trace_off")
+                .about("Stop tracing")
+                .help_template(APPLET_TEMPLATE),
+        )
+        .subcommand(
+            Command::new("trace_show")
+                .about("Show the most recent entries of the in-memory trace")
+                .arg(
+                    Arg::new("count")
+                        .value_parser(clap::value_parser!(usize))
+                        .default_value("20"),
+                )
+                .help_template(APPLET_TEMPLATE),
+        )
+        .subcommand(
+            Command::new("enable")
+                .about("Enable breakpoint (omit address to enable all)")
+                .arg(Arg::new("address").required(false))
+                .arg(arg!(-w --watch "enable watchpoints instead"))
+                .help_template(APPLET_TEMPLATE),
+        )
+        .subcommand(
+            Command::new("disable")
+                .about("Disable breakpoint without deleting it (omit address to disable all)")
+                .arg(Arg::new("address").required(false))
+                .arg(arg!(-w --watch "disable watchpoints instead"))
                 .help_template(APPLET_TEMPLATE),
         )
         .subcommand(
             Command::new("watch")
                 .about("Set watchpoint")
                 .visible_alias("w")
-                .arg(Arg::new("address").required(true))
+                .arg(
+                    Arg::new("address")
+                        .required(true)
+                        .help("Address, or inclusive range START..END"),
+                )
+                .arg(
+                    Arg::new("len")
+                        .short('l')
+                        .long("len")
+                        .value_parser(clap::value_parser!(u16))
+                        .default_value("1")
+                        .help("Number of words to watch"),
+                )
                 .arg(arg!(-r --read   "watch for reads"))
                 .arg(arg!(-w --write  "watch for writes"))
+                .arg(
+                    Arg::new("equals")
+                        .long("equals")
+                        .value_name("VALUE")
+                        .help("Only stop when the value read or written equals VALUE"),
+                )
+                .arg(
+                    Arg::new("changed")
+                        .long("changed")
+                        .action(clap::ArgAction::SetTrue)
+                        .conflicts_with("equals")
+                        .help("Only stop when a write changes the value"),
+                )
                 .help_template(APPLET_TEMPLATE),
         )
         .subcommand(
@@ -107,6 +353,37 @@ pub fn syntax() -> Command {
                 .about("Delete watchpoint (omit address to delete all)")
                 .help_template(APPLET_TEMPLATE),
         )
+        .subcommand(
+            Command::new("backtrace")
+                .visible_aliases(["bt", "where"])
+                .about("Show the VM call stack")
+                .help_template(APPLET_TEMPLATE),
+        )
+        .subcommand(
+            Command::new("frame")
+                .visible_alias("f")
+                .about("Select a call stack frame for .lcl/.arg/.this/.that (0 = innermost)")
+                .arg(
+                    Arg::new("index")
+                        .value_parser(clap::value_parser!(usize))
+                        .help("Frame number from backtrace (default: show the selected frame)"),
+                )
+                .help_template(APPLET_TEMPLATE),
+        )
+        .subcommand(
+            Command::new("asm")
+                .about("Assemble one instruction into ROM")
+                .arg(Arg::new("address").required(true).help("ROM address"))
+                .arg(
+                    Arg::new("instruction")
+                        .required(true)
+                        .num_args(1..)
+                        .allow_hyphen_values(true)
+                        .help("Instruction, e.g. \"D=D+M;JGT\" or @LOOP"),
+                )
+                .arg_required_else_help(true)
+                .help_template(APPLET_TEMPLATE),
+        )
         .subcommand(
             Command::new("dis")
                 .about("Disassemble instructions")
@@ -172,6 +449,13 @@ pub fn syntax() -> Command {
                 .about("Evaluate an address expression")
                 .help_template(APPLET_TEMPLATE),
         )
+        .subcommand(
+            Command::new("source")
+                .about("Run debugger commands from a file ('#' starts a comment)")
+                .arg(Arg::new("file").required(true))
+                .arg_required_else_help(true)
+                .help_template(APPLET_TEMPLATE),
+        )
         .subcommand(
             Command::new("cd")
                 .about("Change working directory")
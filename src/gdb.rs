@@ -0,0 +1,590 @@
+/*
+
+GDB remote serial protocol stub
+
+    hackem --gdb <port> [file]
+
+Serves one GDB (or LLDB, or scripted RSP client) connection on
+127.0.0.1:<port>:
+
+    (gdb) target remote :1234
+
+The Hack CPU is presented as a target with 16-bit addressable units:
+every address is a word address and every memory unit is one word, sent
+as 4 hex digits low byte first, like the registers.
+
+    registers   0 = pc, 1 = a, 2 = d            (g G p P)
+    memory      0x00000..0x07FFF  RAM           (m M)
+                0x10000..0x17FFF  ROM
+    breakpoints Z0/Z1 at a ROM address; stops before the instruction runs
+    watchpoints Z2 write, Z3 read, Z4 access over a RAM range
+    execution   c, s, ^C interrupts a running program
+
+`monitor <command>` runs a debugger shell command (`monitor pdb prog.pdb`,
+`monitor dis 0 10`, ...) and shows its output. Output port bytes are sent
+to the client as console output while the program runs.
+
+*/
+
+use std::collections::BTreeSet;
+use std::io::{BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::Path;
+use std::sync::mpsc::{self, Receiver, TryRecvError};
+
+use anyhow::{bail, Result};
+use clap::ArgMatches;
+use web_time::Duration;
+
+use crate::debugger::shell::Shell;
+use hackem_core::{
+    debugger::{debug_em::HackSystem, vm::read_vm_dir},
+    emulator::engine::{StopReason, WatchCondition},
+};
+
+/// Base of the ROM address space.
+const ROM_BASE: u32 = 0x10000;
+const MEMORY_SIZE: u32 = 0x8000;
+
+const TARGET_XML: &str = r#"<?xml version="1.0"?>
+<!DOCTYPE target SYSTEM "gdb-target.dtd">
+<target version="1.0">
+  <feature name="org.hackem.cpu">
+    <reg name="pc" bitsize="16" type="code_ptr" regnum="0"/>
+    <reg name="a" bitsize="16" type="uint16"/>
+    <reg name="d" bitsize="16" type="int16"/>
+  </feature>
+</target>
+"#;
+
+/// Run the stub and return the process exit code.
+pub fn run(args: &ArgMatches) -> i32 {
+    let port = *args.get_one::<u16>("gdb").unwrap();
+    let mut stub = GdbStub::new();
+    let served = match args.get_one::<String>("file") {
+        Some(file) => stub.load(Path::new(file)),
+        None => Ok(()),
+    }
+    .and_then(|()| {
+        let listener = TcpListener::bind(("127.0.0.1", port))?;
+        eprintln!("GDB stub listening on 127.0.0.1:{}", port);
+        let (stream, peer) = listener.accept()?;
+        eprintln!("GDB connected from {}", peer);
+        stub.serve(stream)
+    });
+    match served {
+        Ok(()) => 0,
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            1
+        }
+    }
+}
+
+/// What the client sent, as split up by the reader thread.
+enum Input {
+    Packet(String),
+    BadChecksum,
+    Interrupt,
+}
+
+/// Read packets until the connection closes. Acks from the client are
+/// dropped: a reliable stream never needs a retransmit.
+fn read_packets(stream: impl Read, tx: mpsc::Sender<Input>) {
+    let mut bytes = BufReader::new(stream).bytes().map_while(|b| b.ok());
+    while let Some(byte) = bytes.next() {
+        let input = match byte {
+            0x03 => Input::Interrupt,
+            b'$' => {
+                let data: Vec<u8> = bytes.by_ref().take_while(|&b| b != b'#').collect();
+                let checksum: Vec<u8> = bytes.by_ref().take(2).collect();
+                let expected = std::str::from_utf8(&checksum)
+                    .ok()
+                    .and_then(|s| u8::from_str_radix(s, 16).ok());
+                if expected == Some(checksum_of(&data)) {
+                    Input::Packet(String::from_utf8_lossy(&data).into_owned())
+                } else {
+                    Input::BadChecksum
+                }
+            }
+            _ => continue,
+        };
+        if tx.send(input).is_err() {
+            break;
+        }
+    }
+}
+
+fn checksum_of(data: &[u8]) -> u8 {
+    data.iter().fold(0u8, |sum, &b| sum.wrapping_add(b))
+}
+
+fn hex_encode(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn hex_decode(hex: &str) -> Result<Vec<u8>> {
+    if hex.len() % 2 != 0 {
+        bail!("Odd length hex string");
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| Ok(u8::from_str_radix(&hex[i..i + 2], 16)?))
+        .collect()
+}
+
+/// A word as it goes over the wire: 4 hex digits, low byte first.
+fn word_hex(word: u16) -> String {
+    hex_encode(&word.to_le_bytes())
+}
+
+fn parse_word(hex: &str) -> Result<u16> {
+    match hex_decode(hex)?.as_slice() {
+        [lo, hi] => Ok(u16::from_le_bytes([*lo, *hi])),
+        _ => bail!("Expected one word, got '{}'", hex),
+    }
+}
+
+/// Parse the `addr,length` of m/M/Z packets.
+fn parse_range(text: &str) -> Result<(u32, u32)> {
+    let Some((addr, len)) = text.split_once(',') else {
+        bail!("Expected addr,length");
+    };
+    Ok((
+        u32::from_str_radix(addr, 16)?,
+        u32::from_str_radix(len, 16)?,
+    ))
+}
+
+/// How the main loop should go on after a packet.
+enum Action {
+    Reply(String),
+    Continue,
+    Step,
+    Detach,
+}
+
+struct GdbStub {
+    hacksys: HackSystem,
+    shell: Shell,
+    /// Z0/Z1 addresses, given to the engine as run-to addresses so the
+    /// program stops before executing them.
+    breakpoints: BTreeSet<u16>,
+    no_ack: bool,
+    /// Reply to `?`.
+    last_stop: String,
+}
+
+impl GdbStub {
+    fn new() -> Self {
+        Self {
+            hacksys: HackSystem::new(),
+            shell: Shell::new(),
+            breakpoints: BTreeSet::new(),
+            no_ack: false,
+            last_stop: "S05".to_string(),
+        }
+    }
+
+    fn load(&mut self, path: &Path) -> Result<()> {
+        if path.is_dir() {
+            self.hacksys.load_vm(&read_vm_dir(path)?)?;
+        } else {
+            let text = std::fs::read_to_string(path)?;
+            self.hacksys.load_code(path, &text)?;
+        }
+        if self.hacksys.vm_mode() {
+            bail!("The GDB stub debugs Hack machine code, not VM code");
+        }
+        Ok(())
+    }
+
+    fn serve(&mut self, stream: TcpStream) -> Result<()> {
+        stream.set_nodelay(true)?;
+        let (tx, rx) = mpsc::channel();
+        let reader = stream.try_clone()?;
+        std::thread::spawn(move || read_packets(reader, tx));
+        self.serve_on(rx, stream)
+    }
+
+    fn serve_on(&mut self, rx: Receiver<Input>, mut out: impl Write) -> Result<()> {
+        let mut running = false;
+        loop {
+            let input = if running {
+                match rx.try_recv() {
+                    Ok(input) => Some(input),
+                    Err(TryRecvError::Empty) => None,
+                    Err(TryRecvError::Disconnected) => return Ok(()),
+                }
+            } else {
+                match rx.recv() {
+                    Ok(input) => Some(input),
+                    Err(_) => return Ok(()),
+                }
+            };
+            // (reply, whether it reports a stop)
+            let (reply, stop) = match input {
+                None => (self.run_slice(Duration::from_millis(50), &mut out)?, true),
+                Some(Input::Interrupt) if running => (Some("S02".to_string()), true),
+                Some(Input::Interrupt) => (None, false),
+                Some(Input::BadChecksum) => {
+                    out.write_all(b"-")?;
+                    (None, false)
+                }
+                Some(Input::Packet(packet)) => {
+                    if !self.no_ack {
+                        out.write_all(b"+")?;
+                    }
+                    match self.packet(&packet) {
+                        Action::Reply(reply) => {
+                            // acks stop after the reply to this one
+                            if packet == "QStartNoAckMode" {
+                                self.no_ack = true;
+                            }
+                            (Some(reply), false)
+                        }
+                        Action::Continue => {
+                            running = true;
+                            (None, false)
+                        }
+                        Action::Step => (self.run_slice(Duration::ZERO, &mut out)?, true),
+                        Action::Detach => {
+                            self.send(&mut out, "OK")?;
+                            return Ok(());
+                        }
+                    }
+                }
+            };
+            if let Some(reply) = reply {
+                if stop {
+                    running = false;
+                    self.last_stop = reply.clone();
+                }
+                self.send(&mut out, &reply)?;
+            }
+        }
+    }
+
+    fn send(&self, out: &mut impl Write, data: &str) -> Result<()> {
+        write!(out, "${}#{:02x}", data, checksum_of(data.as_bytes()))?;
+        out.flush()?;
+        Ok(())
+    }
+
+    /// Run for up to `run_time` (one instruction for zero) and return the
+    /// stop reply, or None if the program is still running.
+    fn run_slice(&mut self, run_time: Duration, out: &mut impl Write) -> Result<Option<String>> {
+        self.hacksys.engine.run_to = self.breakpoints.clone();
+        let stop = self.hacksys.execute_instructions(run_time);
+        self.hacksys.clear_goal();
+        let output = self.hacksys.engine.take_output();
+        if !output.is_empty() {
+            self.send(out, &format!("O{}", hex_encode(output.as_bytes())))?;
+        }
+        let reply = match stop {
+            Ok(StopReason::RefreshUI | StopReason::Intercept) if run_time > Duration::ZERO => {
+                return Ok(None)
+            }
+            Ok(StopReason::RunTo) => "T05swbreak:;".to_string(),
+            Ok(StopReason::WatchPoint) => match &self.hacksys.engine.triggered_watchpoint {
+                Some(hit) => format!(
+                    "T05{}:{:x};",
+                    if hit.write { "watch" } else { "rwatch" },
+                    hit.address
+                ),
+                None => "T05".to_string(),
+            },
+            Ok(_) => "T05".to_string(),
+            Err(e) => {
+                self.send(
+                    out,
+                    &format!("O{}", hex_encode(format!("{}\n", e).as_bytes())),
+                )?;
+                "S04".to_string()
+            }
+        };
+        Ok(Some(reply))
+    }
+
+    /// Handle one packet.
+    fn packet(&mut self, packet: &str) -> Action {
+        let reply = match packet.chars().next() {
+            Some('c') => return Action::Continue,
+            Some('s') => return Action::Step,
+            Some('D') | Some('k') => return Action::Detach,
+            _ => self.query(packet),
+        };
+        Action::Reply(reply.unwrap_or_else(|_| "E01".to_string()))
+    }
+
+    fn query(&mut self, packet: &str) -> Result<String> {
+        let (kind, rest) = packet.split_at(packet.len().min(1));
+        let reply = match kind {
+            "?" => self.last_stop.clone(),
+            "g" => {
+                let engine = &self.hacksys.engine;
+                [engine.pc, engine.a, engine.d]
+                    .into_iter()
+                    .map(word_hex)
+                    .collect()
+            }
+            "G" => {
+                if rest.len() != 12 {
+                    bail!("Expected 3 registers");
+                }
+                let engine = &mut self.hacksys.engine;
+                engine.pc = parse_word(&rest[0..4])?;
+                engine.a = parse_word(&rest[4..8])?;
+                engine.d = parse_word(&rest[8..12])?;
+                "OK".to_string()
+            }
+            "p" => {
+                let engine = &self.hacksys.engine;
+                match usize::from_str_radix(rest, 16)? {
+                    0 => word_hex(engine.pc),
+                    1 => word_hex(engine.a),
+                    2 => word_hex(engine.d),
+                    _ => bail!("No such register"),
+                }
+            }
+            "P" => {
+                let Some((reg, value)) = rest.split_once('=') else {
+                    bail!("Expected reg=value");
+                };
+                let value = parse_word(value)?;
+                let engine = &mut self.hacksys.engine;
+                match usize::from_str_radix(reg, 16)? {
+                    0 => engine.pc = value,
+                    1 => engine.a = value,
+                    2 => engine.d = value,
+                    _ => bail!("No such register"),
+                }
+                "OK".to_string()
+            }
+            "m" => {
+                let (addr, len) = parse_range(rest)?;
+                let words: String = (addr..addr.saturating_add(len))
+                    .map_while(|a| self.memory(a).map(|m| word_hex(*m)))
+                    .collect();
+                if words.is_empty() {
+                    bail!("Address out of range");
+                }
+                words
+            }
+            "M" => {
+                let Some((range, data)) = rest.split_once(':') else {
+                    bail!("Expected addr,length:data");
+                };
+                let (addr, len) = parse_range(range)?;
+                if addr.checked_add(len).is_none() {
+                    bail!("Address out of range");
+                }
+                if data.len() != len as usize * 4 {
+                    bail!("Data does not match length");
+                }
+                for i in 0..len {
+                    let word = parse_word(&data[i as usize * 4..i as usize * 4 + 4])?;
+                    match self.memory(addr + i) {
+                        Some(m) => *m = word,
+                        None => bail!("Address out of range"),
+                    }
+                }
+                "OK".to_string()
+            }
+            "Z" | "z" => self.break_watch(kind == "Z", rest)?,
+            "H" | "T" => "OK".to_string(),
+            "q" | "Q" => self.general_query(packet)?,
+            // vCont, X and anything else: not supported, the client falls back
+            _ => String::new(),
+        };
+        Ok(reply)
+    }
+
+    /// The word at a client address, in RAM or ROM.
+    fn memory(&mut self, addr: u32) -> Option<&mut u16> {
+        let engine = &mut self.hacksys.engine;
+        match addr {
+            a if a < MEMORY_SIZE => Some(&mut engine.ram[a as usize]),
+            a if (ROM_BASE..ROM_BASE + MEMORY_SIZE).contains(&a) => {
+                Some(&mut engine.rom[(a - ROM_BASE) as usize])
+            }
+            _ => None,
+        }
+    }
+
+    /// Z/z packets: `type,addr,kind`.
+    fn break_watch(&mut self, insert: bool, rest: &str) -> Result<String> {
+        let Some((kind, range)) = rest.split_once(',') else {
+            bail!("Expected type,addr,kind");
+        };
+        let (addr, len) = parse_range(range)?;
+        let engine = &mut self.hacksys.engine;
+        match kind {
+            "0" | "1" => {
+                // code addresses may come with or without the ROM base
+                let addr = addr.checked_sub(ROM_BASE).unwrap_or(addr);
+                if addr >= MEMORY_SIZE {
+                    bail!("Address out of range");
+                }
+                if insert {
+                    self.breakpoints.insert(addr as u16);
+                } else {
+                    self.breakpoints.remove(&(addr as u16));
+                }
+            }
+            "2" | "3" | "4" => {
+                if len == 0 || !matches!(addr.checked_add(len), Some(end) if end <= MEMORY_SIZE) {
+                    bail!("Watch range out of RAM");
+                }
+                let (addr, len) = (addr as u16, len as u16);
+                let (read, write) = (kind != "2", kind != "3");
+                // Z2 and Z3 on one address share the engine's watchpoint
+                match engine.watch_points.get_mut(&addr) {
+                    Some(wp) if insert => {
+                        if wp.len != len {
+                            bail!("Another watchpoint starts at this address");
+                        }
+                        wp.read |= read;
+                        wp.write |= write;
+                    }
+                    None if insert => {
+                        engine.add_watch_range(addr, len, read, write, WatchCondition::Always);
+                    }
+                    Some(wp) => {
+                        wp.read &= !read;
+                        wp.write &= !write;
+                        if !wp.read && !wp.write {
+                            engine.remove_watchpoint(addr);
+                        }
+                    }
+                    None => {}
+                }
+            }
+            _ => return Ok(String::new()),
+        }
+        Ok("OK".to_string())
+    }
+
+    fn general_query(&mut self, packet: &str) -> Result<String> {
+        if let Some(command) = packet.strip_prefix("qRcmd,") {
+            return self.monitor(&String::from_utf8_lossy(&hex_decode(command)?));
+        }
+        if let Some(range) = packet.strip_prefix("qXfer:features:read:target.xml:") {
+            let (offset, len) = parse_range(range)?;
+            let start = (offset as usize).min(TARGET_XML.len());
+            let end = (start + len as usize).min(TARGET_XML.len());
+            let more = if end < TARGET_XML.len() { 'm' } else { 'l' };
+            return Ok(format!("{}{}", more, &TARGET_XML[start..end]));
+        }
+        let name = packet.split([':', ',']).next().unwrap_or("");
+        Ok(match name {
+            "qSupported" => {
+                "PacketSize=4000;qXfer:features:read+;QStartNoAckMode+;swbreak+".to_string()
+            }
+            "QStartNoAckMode" => "OK".to_string(),
+            "qAttached" => "1".to_string(),
+            "qC" => "QC1".to_string(),
+            "qfThreadInfo" => "m1".to_string(),
+            "qsThreadInfo" => "l".to_string(),
+            _ => String::new(),
+        })
+    }
+
+    /// `monitor <command>`: the shell output, hex encoded.
+    fn monitor(&mut self, command: &str) -> Result<String> {
+        let output = match self.shell.dispatch(command, &mut self.hacksys) {
+            Ok(response) => match Shell::split_action(&response) {
+                ("", Some(_)) => "Use the GDB commands to run the program\n".to_string(),
+                (output, Some(_)) => {
+                    format!("{}\nUse the GDB commands to run the program\n", output)
+                }
+                ("", None) => return Ok("OK".to_string()),
+                (output, None) => format!("{}\n", output),
+            },
+            Err(e) => format!("{}\n", e),
+        };
+        Ok(hex_encode(output.as_bytes()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const COUNT: &str = "\
+    @5
+    D=A
+    @16
+    M=D
+(END)
+    @END
+    0;JMP
+";
+
+    fn reply(stub: &mut GdbStub, packet: &str) -> String {
+        match stub.packet(packet) {
+            Action::Reply(reply) => reply,
+            _ => panic!("'{}' did not reply", packet),
+        }
+    }
+
+    #[test]
+    fn test_gdb_packets() {
+        let mut stub = GdbStub::new();
+        stub.hacksys
+            .load_code(Path::new("Count.asm"), COUNT)
+            .unwrap();
+        let mut out = Vec::new();
+
+        // ROM word 1 is D=A
+        assert_eq!(reply(&mut stub, "m10001,1"), word_hex(0xEC10));
+        assert_eq!(reply(&mut stub, "Z0,3,1"), "OK");
+        assert!(matches!(stub.packet("c"), Action::Continue));
+        let stop = stub.run_slice(Duration::from_secs(1), &mut out).unwrap();
+        assert_eq!(stop.as_deref(), Some("T05swbreak:;"));
+        // stopped before M=D ran
+        assert_eq!(reply(&mut stub, "g"), "030010000500");
+        assert_eq!(reply(&mut stub, "m10,1"), "0000");
+
+        assert_eq!(reply(&mut stub, "Z2,10,1"), "OK");
+        assert_eq!(
+            stub.run_slice(Duration::from_secs(1), &mut out)
+                .unwrap()
+                .as_deref(),
+            Some("T05watch:10;")
+        );
+        assert_eq!(reply(&mut stub, "m10,1"), "0500");
+
+        // read and write watches on one address are merged, and removed separately
+        assert_eq!(reply(&mut stub, "Z3,10,1"), "OK");
+        let wp = &stub.hacksys.engine.watch_points[&0x10];
+        assert!(wp.read && wp.write);
+        assert_eq!(reply(&mut stub, "z2,10,1"), "OK");
+        let wp = &stub.hacksys.engine.watch_points[&0x10];
+        assert!(wp.read && !wp.write);
+        assert_eq!(reply(&mut stub, "z3,10,1"), "OK");
+        assert!(stub.hacksys.engine.watch_points.is_empty());
+        assert_eq!(reply(&mut stub, "Z2,7fff,ffffffff"), "E01");
+        assert_eq!(reply(&mut stub, "M7fff,ffffffff:0000"), "E01");
+
+        assert_eq!(reply(&mut stub, "P2=ffff"), "OK");
+        assert_eq!(reply(&mut stub, "p2"), "ffff");
+        assert_eq!(reply(&mut stub, "M20,2:01000200"), "OK");
+        assert_eq!(stub.hacksys.engine.ram[0x21], 2);
+        assert_eq!(reply(&mut stub, "m7fff,4"), "0000");
+        assert_eq!(reply(&mut stub, "vCont?"), "");
+        assert_eq!(
+            reply(&mut stub, &format!("qRcmd,{}", hex_encode(b"expr =.d"))),
+            hex_encode(b"0xFFFF  (-1)\n")
+        );
+    }
+
+    #[test]
+    fn test_read_packets() {
+        let (tx, rx) = mpsc::channel();
+        read_packets(&b"+$g#67$m0,1#00\x03"[..], tx);
+        assert!(matches!(rx.recv(), Ok(Input::Packet(p)) if p == "g"));
+        assert!(matches!(rx.recv(), Ok(Input::BadChecksum)));
+        assert!(matches!(rx.recv(), Ok(Input::Interrupt)));
+    }
+}
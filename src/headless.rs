@@ -0,0 +1,278 @@
+/*
+
+Headless runner
+
+    hackem --headless <file> [--max-instructions N] [--screen out.pbm]
+                             [--dump-ram <start>..<end>=<file>]...
+                             [--coverage <file.info>]
+
+Loads a .hx, .hack or .asm program, or .vm file(s) (a directory loads
+every .vm file in it) and runs it without opening a window until
+Sys.halt is reached, the program spins in a hard loop, or the instruction
+limit is hit. Bytes written to the output port are streamed to stdout as
+the program runs; the reason for stopping goes to stderr.
+
+A .tst file is run as a test script instead: its output file is written
+and compared with its .cmp file, and the result goes to stderr.
+
+Exit status: 0 = halted normally (or test passed), 1 = load or runtime
+error, 2 = instruction limit reached, 3 = test comparison failure.
+
+The screen dump is a plain PBM (P1) image, 512x256, 1 = black.
+RAM dumps use the RAM@ section layout of the .hx format so they can be
+inspected by hand or diffed against an expected dump. The coverage file is
+LCOV for the source files in the program's PDB.
+
+*/
+
+use std::io::Write;
+use std::path::Path;
+
+use anyhow::{anyhow, bail, Result};
+use clap::{Arg, ArgAction, ArgGroup, ArgMatches, Command};
+use web_time::Duration;
+
+use hackem_core::{
+    debugger::{debug_em::HackSystem, vm::read_vm_dir},
+    emulator::engine::StopReason,
+};
+
+pub fn cli() -> Command {
+    Command::new("hackem")
+        .about("Hack CPU emulator and debugger")
+        .arg(
+            Arg::new("headless")
+                .long("headless")
+                .action(ArgAction::SetTrue)
+                .requires("file")
+                .help("Run a program without the GUI"),
+        )
+        .arg(Arg::new("file").help(
+            "Program to load (.hx, .hack, .asm, .vm or a directory of .vm files), or a .tst script",
+        ))
+        .arg(
+            Arg::new("max-instructions")
+                .long("max-instructions")
+                .value_parser(clap::value_parser!(u64))
+                .help("Stop after this many instructions"),
+        )
+        .arg(
+            Arg::new("screen")
+                .long("screen")
+                .help("Write the final screen to this file (PBM)"),
+        )
+        .arg(
+            Arg::new("dump-ram")
+                .long("dump-ram")
+                .action(ArgAction::Append)
+                .value_name("START..END=FILE")
+                .help("Write a RAM range to a file (repeatable)"),
+        )
+        .arg(
+            Arg::new("coverage")
+                .long("coverage")
+                .value_name("FILE")
+                .help("Write source line coverage to this file (LCOV)"),
+        )
+        .arg(
+            Arg::new("dap")
+                .long("dap")
+                .action(ArgAction::SetTrue)
+                .conflicts_with("headless")
+                .help("Run as a Debug Adapter Protocol server on stdin/stdout"),
+        )
+        .arg(
+            Arg::new("rpc")
+                .long("rpc")
+                .action(ArgAction::SetTrue)
+                .conflicts_with_all(["headless", "dap"])
+                .help("Run as a JSON-RPC server on stdin/stdout"),
+        )
+        .group(ArgGroup::new("server").args(["dap", "rpc"]))
+        .arg(
+            Arg::new("port")
+                .long("port")
+                .requires("server")
+                .value_parser(clap::value_parser!(u16))
+                .help("Serve DAP or JSON-RPC on this TCP port of 127.0.0.1 instead"),
+        )
+        .arg(
+            Arg::new("gdb")
+                .long("gdb")
+                .value_name("PORT")
+                .value_parser(clap::value_parser!(u16))
+                .conflicts_with_all(["headless", "dap", "rpc"])
+                .help("Serve the GDB remote protocol on this TCP port of 127.0.0.1"),
+        )
+}
+
+/// Run headless and return the process exit code.
+pub fn run(args: &ArgMatches) -> i32 {
+    let mut hacksys = HackSystem::new();
+    match run_program(&mut hacksys, args) {
+        Ok(code) => code,
+        Err(e) => {
+            let _ = std::io::stdout().flush();
+            eprintln!("Error: {}", e);
+            1
+        }
+    }
+}
+
+fn run_program(hacksys: &mut HackSystem, args: &ArgMatches) -> Result<i32> {
+    let file = args.get_one::<String>("file").unwrap();
+    let path = Path::new(file);
+    let is_test = path
+        .extension()
+        .is_some_and(|e| e.eq_ignore_ascii_case("tst"));
+    if path.is_dir() {
+        hacksys.load_vm(&read_vm_dir(path)?)?;
+    } else if !is_test {
+        let bin = std::fs::read_to_string(path)?;
+        hacksys.load_code(path, &bin)?;
+    }
+    hacksys.engine.inst_limit = args.get_one::<u64>("max-instructions").copied();
+
+    // validate dump requests before spending time running the program
+    let dumps = args
+        .get_many::<String>("dump-ram")
+        .unwrap_or_default()
+        .map(|spec| parse_dump(hacksys, spec))
+        .collect::<Result<Vec<_>>>()?;
+
+    let code = if is_test {
+        let script = std::fs::read_to_string(path)?;
+        let report = hacksys.run_test_script(path, &script)?;
+        for echo in &report.echoes {
+            eprintln!("{}", echo);
+        }
+        eprintln!("{}", report);
+        if report.passed() {
+            0
+        } else {
+            3
+        }
+    } else {
+        run_to_stop(hacksys)?
+    };
+
+    if let Some(screen) = args.get_one::<String>("screen") {
+        std::fs::write(
+            Path::new(screen),
+            screen_pbm(&hacksys.engine.ram[0x4000..0x6000]),
+        )?;
+    }
+    for (start, end, path) in dumps {
+        std::fs::write(Path::new(&path), ram_dump(&hacksys.engine.ram, start, end))?;
+    }
+    if let Some(coverage) = args.get_one::<String>("coverage") {
+        std::fs::write(Path::new(coverage), hacksys.lcov())?;
+    }
+    Ok(code)
+}
+
+/// Run until the program stops; returns the exit code for the stop reason.
+fn run_to_stop(hacksys: &mut HackSystem) -> Result<i32> {
+    let mut stdout = std::io::stdout();
+    let code = loop {
+        let stop = hacksys.execute_instructions(Duration::from_millis(50));
+        stdout.write_all(hacksys.engine.take_output().as_bytes())?;
+        stdout.flush()?;
+        for message in hacksys.take_messages() {
+            eprintln!("{}", message);
+        }
+        let (pc, _, _) = hacksys.engine.get_registers();
+        let count = hacksys.engine.instruction_count();
+        match stop? {
+            StopReason::RefreshUI | StopReason::Intercept => continue,
+            StopReason::SysHalt => {
+                eprintln!("SysHalt after {} instructions", count);
+                break 0;
+            }
+            StopReason::HardLoop => {
+                eprintln!("Hard loop at 0x{:04X} after {} instructions", pc, count);
+                break 0;
+            }
+            StopReason::InstructionLimit => {
+                eprintln!("Instruction limit reached at 0x{:04X}", pc);
+                break 2;
+            }
+            StopReason::WatchPoint => {
+                if let Some(hit) = &hacksys.engine.triggered_watchpoint {
+                    eprintln!("{} after {} instructions", hit, count);
+                }
+                break 0;
+            }
+            StopReason::BreakPoint | StopReason::RunTo => {
+                eprintln!("Stopped at 0x{:04X} after {} instructions", pc, count);
+                break 0;
+            }
+        }
+    };
+    Ok(code)
+}
+
+/// Parse `<start>..<end>=<file>`; the range is inclusive.
+fn parse_dump(hacksys: &HackSystem, spec: &str) -> Result<(u16, u16, String)> {
+    let (range, path) = spec
+        .split_once('=')
+        .ok_or_else(|| anyhow!("dump '{}' must look like START..END=FILE", spec))?;
+    let (start, end) = range
+        .split_once("..")
+        .ok_or_else(|| anyhow!("range '{}' must look like START..END", range))?;
+    let start = hacksys.convert_addr(start)?.0;
+    let end = hacksys.convert_addr(end)?.0;
+    if start > end || end >= 0x8000 {
+        bail!("invalid RAM range '{}'", range);
+    }
+    Ok((start, end, path.to_string()))
+}
+
+fn screen_pbm(screen: &[u16]) -> String {
+    let mut out = String::from("P1\n512 256\n");
+    for row in screen.chunks(32) {
+        let mut line = String::with_capacity(512);
+        for word in row {
+            for bit in 0..16 {
+                line.push(if word & (1 << bit) != 0 { '1' } else { '0' });
+            }
+        }
+        out.push_str(&line);
+        out.push('\n');
+    }
+    out
+}
+
+fn ram_dump(ram: &[u16], start: u16, end: u16) -> String {
+    let mut out = format!("RAM@{:04x}\n", start);
+    for word in &ram[start as usize..=end as usize] {
+        out.push_str(&format!("{:04x}\n", word));
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_screen_pbm_bit_order() {
+        let mut screen = vec![0u16; 0x2000];
+        screen[0] = 0x0001;
+        screen[32] = 0x8000;
+        let pbm = screen_pbm(&screen);
+        let rows: Vec<&str> = pbm.lines().skip(2).collect();
+        assert_eq!(rows.len(), 256);
+        assert!(rows[0].starts_with("10000"));
+        assert_eq!(&rows[1][..16], "0000000000000001");
+    }
+
+    #[test]
+    fn test_parse_dump() {
+        let hacksys = HackSystem::new();
+        let (start, end, path) = parse_dump(&hacksys, "0x100..263=out.txt").unwrap();
+        assert_eq!((start, end, path.as_str()), (0x100, 263, "out.txt"));
+        assert!(parse_dump(&hacksys, "0x100..0x10=out.txt").is_err());
+        assert!(parse_dump(&hacksys, "0x100=out.txt").is_err());
+    }
+}
@@ -6,11 +6,14 @@ mod utils;
 // the egui ui
 mod ui {
     pub mod widgets {
+        pub mod callstack;
         pub mod code;
         // pub mod console; -- replaced by egui_console crate
         pub mod cpu;
         pub mod data;
+        pub mod profile;
         pub mod screen;
+        pub mod segments;
     }
     pub mod app;
     mod key_lookup;
@@ -18,22 +21,28 @@ mod ui {
     pub mod wasm;
 }
 
-// the hack cpu + ram / rom emulator
-mod emulator {
-    mod code_loader;
-    pub mod engine;
-}
-
-// the main application
+// the debugger command shell (the emulator itself lives in hackem_core)
 mod debugger {
-    pub mod debug_em;
-    pub mod disassemble;
-    pub mod expr;
-    pub mod pdbio;
     pub mod shell;
     pub mod syntax;
 }
 
+// command line runner without the GUI
+#[cfg(not(target_arch = "wasm32"))]
+mod headless;
+
+// Debug Adapter Protocol server for editors
+#[cfg(not(target_arch = "wasm32"))]
+mod dap;
+
+// GDB remote serial protocol stub
+#[cfg(not(target_arch = "wasm32"))]
+mod gdb;
+
+// JSON-RPC control API for automation
+#[cfg(not(target_arch = "wasm32"))]
+mod rpc;
+
 pub use ui::app::HackEgui;
 
 use simplelog::*;
@@ -42,6 +51,20 @@ use std::fs::File;
 // When compiling natively:
 #[cfg(not(target_arch = "wasm32"))]
 fn main() -> eframe::Result<()> {
+    let args = headless::cli().get_matches();
+    if args.get_flag("headless") {
+        std::process::exit(headless::run(&args));
+    }
+    if args.get_flag("dap") {
+        std::process::exit(dap::run(&args));
+    }
+    if args.contains_id("gdb") {
+        std::process::exit(gdb::run(&args));
+    }
+    if args.get_flag("rpc") {
+        std::process::exit(rpc::run(&args));
+    }
+
     CombinedLogger::init(vec![WriteLogger::new(
         LevelFilter::Off,
         Config::default(),
@@ -0,0 +1,558 @@
+/*
+
+JSON-RPC control API
+
+    hackem --rpc [--port N] [file]
+
+For autograders, test suites and other programs that drive the emulator.
+Requests and responses are JSON-RPC 2.0 objects, one per line, on
+stdin/stdout or, with `--port`, on TCP connections to 127.0.0.1:N (served
+one after another, sharing the same machine until `shutdown`).
+
+    {"jsonrpc":"2.0","id":1,"method":"load","params":{"program":"Max.asm"}}
+    {"jsonrpc":"2.0","id":1,"result":{}}
+
+Methods
+    load        program, pdb?, native_os?
+    registers   -> pc, a, d, sp, lcl, arg, this, that, instructions
+    memory      address, count = 1, rom = false -> address, words
+    set_memory  address, words, rom = false
+    screen      -> width, height, words (8192, 32 per row, bit 0 leftmost)
+    run         timeout_ms = 10000, max_instructions? -> stop
+    step        count = 1 -> stop
+    output      -> text written to the output port and not yet returned
+    evaluate    expression -> value, signed
+    command     line -> output, stop?
+    shutdown
+
+Addresses are numbers or strings in the debugger's address syntax (symbols,
+`0x..`, `=expr`). Any other method runs the debugger command of that name
+with the params array as its arguments, so `{"method":"break",
+"params":["Main.main"]}` is `break Main.main`. Each param is one word, taken
+as is: quotes in it are not special, and options are written `--if`.
+
+A stop is
+    reason        breakpoint, watchpoint, run_to, halt, hard_loop,
+                  instruction_limit, step, timeout or error
+    pc, instructions, output, messages
+    breakpoint    address, for breakpoint stops
+    watchpoint    address, pc, write, old, new, for watchpoint stops
+    error         message, for error stops
+
+Errors use the JSON-RPC codes (-32700 parse error, -32600 invalid request,
+-32601 unknown method, -32602 invalid params) and -32000 for everything
+that failed while carrying out a valid request.
+
+*/
+
+use std::io::{BufRead, BufReader, Write};
+use std::net::TcpListener;
+use std::path::Path;
+
+use anyhow::Result;
+use clap::ArgMatches;
+use common::pdb::database::Pdb;
+use serde_json::{json, Value};
+use web_time::{Duration, Instant};
+
+use crate::debugger::shell::Shell;
+use crate::utils;
+use hackem_core::{
+    debugger::{debug_em::HackSystem, vm::read_vm_dir},
+    emulator::engine::StopReason,
+};
+
+const PARSE_ERROR: i64 = -32700;
+const INVALID_REQUEST: i64 = -32600;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+const FAILED: i64 = -32000;
+
+const DEFAULT_TIMEOUT_MS: u64 = 10_000;
+
+/// Run the server and return the process exit code.
+pub fn run(args: &ArgMatches) -> i32 {
+    // shell messages must not end up in the response stream
+    utils::set_say_cb(|s, _| eprintln!("{}", s));
+    let mut server = RpcServer::new();
+    let served = match args.get_one::<String>("file") {
+        Some(file) => server
+            .load(&json!({ "program": file }))
+            .map_err(|e| anyhow::anyhow!(e.message)),
+        None => Ok(()),
+    }
+    .and_then(|()| match args.get_one::<u16>("port") {
+        Some(&port) => {
+            let listener = TcpListener::bind(("127.0.0.1", port))?;
+            eprintln!("JSON-RPC server listening on 127.0.0.1:{}", port);
+            while !server.done {
+                let (stream, _) = listener.accept()?;
+                server.serve(BufReader::new(stream.try_clone()?), stream)?;
+            }
+            Ok(())
+        }
+        None => server.serve(std::io::stdin().lock(), std::io::stdout()),
+    });
+    match served {
+        Ok(()) => 0,
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            1
+        }
+    }
+}
+
+struct RpcError {
+    code: i64,
+    message: String,
+}
+
+impl From<anyhow::Error> for RpcError {
+    fn from(e: anyhow::Error) -> Self {
+        Self {
+            code: FAILED,
+            message: e.to_string(),
+        }
+    }
+}
+
+fn invalid_params(message: impl Into<String>) -> RpcError {
+    RpcError {
+        code: INVALID_PARAMS,
+        message: message.into(),
+    }
+}
+
+type RpcResult = std::result::Result<Value, RpcError>;
+
+fn stop_reason(stop: &StopReason) -> &'static str {
+    match stop {
+        StopReason::BreakPoint => "breakpoint",
+        StopReason::WatchPoint => "watchpoint",
+        StopReason::RunTo => "run_to",
+        StopReason::SysHalt => "halt",
+        StopReason::HardLoop => "hard_loop",
+        StopReason::InstructionLimit => "instruction_limit",
+        StopReason::RefreshUI | StopReason::Intercept => "step",
+    }
+}
+
+struct RpcServer {
+    hacksys: HackSystem,
+    shell: Shell,
+    done: bool,
+}
+
+impl RpcServer {
+    fn new() -> Self {
+        Self {
+            hacksys: HackSystem::new(),
+            shell: Shell::new(),
+            done: false,
+        }
+    }
+
+    /// Answer requests until the input ends or `shutdown`.
+    fn serve(&mut self, input: impl BufRead, mut output: impl Write) -> Result<()> {
+        for line in input.lines() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            if let Some(response) = self.request(&line) {
+                writeln!(output, "{}", response)?;
+                output.flush()?;
+            }
+            if self.done {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    /// The response to one request line; None for notifications.
+    fn request(&mut self, line: &str) -> Option<Value> {
+        let (id, result) = match serde_json::from_str::<Value>(line) {
+            Err(e) => (
+                Value::Null,
+                Err(RpcError {
+                    code: PARSE_ERROR,
+                    message: e.to_string(),
+                }),
+            ),
+            Ok(request) => {
+                let id = request.get("id").cloned();
+                let result = match request["method"].as_str() {
+                    Some(method) => self.call(method, &request["params"]),
+                    None => Err(RpcError {
+                        code: INVALID_REQUEST,
+                        message: "Request without a method".to_string(),
+                    }),
+                };
+                (id?, result)
+            }
+        };
+        Some(match result {
+            Ok(result) => json!({ "jsonrpc": "2.0", "id": id, "result": result }),
+            Err(e) => json!({
+                "jsonrpc": "2.0",
+                "id": id,
+                "error": { "code": e.code, "message": e.message },
+            }),
+        })
+    }
+
+    fn call(&mut self, method: &str, params: &Value) -> RpcResult {
+        match method {
+            "load" => {
+                self.load(params)?;
+                Ok(json!({}))
+            }
+            "registers" => Ok(self.registers()),
+            "memory" => self.memory(params),
+            "set_memory" => self.set_memory(params),
+            "screen" => Ok(json!({
+                "width": 512,
+                "height": 256,
+                "words": self.hacksys.engine.screen(),
+            })),
+            "run" => {
+                let timeout = params["timeout_ms"].as_u64().unwrap_or(DEFAULT_TIMEOUT_MS);
+                let max = params["max_instructions"].as_u64();
+                Ok(self.run(Duration::from_millis(timeout), max))
+            }
+            "step" => {
+                let count = params["count"].as_u64().unwrap_or(1);
+                Ok(self.step(count))
+            }
+            "output" => Ok(json!({ "text": self.hacksys.engine.take_output() })),
+            "evaluate" => {
+                let Some(expression) = params["expression"].as_str() else {
+                    return Err(invalid_params("evaluate needs an 'expression'"));
+                };
+                let value = self.hacksys.evaluate(expression)?;
+                Ok(json!({ "value": value, "signed": value as i16 }))
+            }
+            "command" => {
+                let Some(line) = params["line"].as_str() else {
+                    return Err(invalid_params("command needs a 'line'"));
+                };
+                self.command(|shell, hacksys| shell.dispatch(line, hacksys))
+            }
+            "shutdown" => {
+                self.done = true;
+                Ok(json!({}))
+            }
+            _ => {
+                let mut words = vec![method.to_string()];
+                for arg in params.as_array().into_iter().flatten() {
+                    words.push(match arg {
+                        Value::String(s) => s.clone(),
+                        other => other.to_string(),
+                    });
+                }
+                self.command(|shell, hacksys| shell.dispatch_words(words, hacksys))
+            }
+        }
+    }
+
+    fn load(&mut self, params: &Value) -> std::result::Result<(), RpcError> {
+        let Some(program) = params["program"].as_str() else {
+            return Err(invalid_params("load needs a 'program'"));
+        };
+        let path = Path::new(program);
+        if path.is_dir() {
+            self.hacksys.load_vm(&read_vm_dir(path)?)?;
+        } else {
+            let text = std::fs::read_to_string(path).map_err(anyhow::Error::from)?;
+            self.hacksys.load_code(path, &text)?;
+        }
+        if let Some(pdb) = params["pdb"].as_str() {
+            let text = std::fs::read_to_string(pdb).map_err(anyhow::Error::from)?;
+            self.hacksys.pdb = Pdb::load_json(&text)?;
+            self.hacksys.load_waw()?;
+        }
+        if let Some(on) = params["native_os"].as_bool() {
+            self.hacksys.set_native_os(on);
+        }
+        Ok(())
+    }
+
+    fn registers(&self) -> Value {
+        let engine = &self.hacksys.engine;
+        json!({
+            "pc": engine.pc,
+            "a": engine.a,
+            "d": engine.d,
+            "sp": engine.ram[0],
+            "lcl": engine.ram[1],
+            "arg": engine.ram[2],
+            "this": engine.ram[3],
+            "that": engine.ram[4],
+            "instructions": engine.instruction_count(),
+        })
+    }
+
+    /// An address param: a number, or a string in the shell's address syntax.
+    fn address(&self, params: &Value) -> std::result::Result<u16, RpcError> {
+        match &params["address"] {
+            Value::Number(n) => n
+                .as_u64()
+                .filter(|&n| n < 0x8000)
+                .map(|n| n as u16)
+                .ok_or_else(|| invalid_params(format!("Address {} out of range", n))),
+            Value::String(s) => match s.strip_prefix('=') {
+                Some(expr) => Ok(self.hacksys.evaluate(expr)?),
+                None => Ok(self.hacksys.convert_addr(s)?.0),
+            },
+            _ => Err(invalid_params("Expected an 'address'")),
+        }
+    }
+
+    fn memory(&self, params: &Value) -> RpcResult {
+        let address = self.address(params)?;
+        let count = params["count"].as_u64().unwrap_or(1) as usize;
+        let memory = match params["rom"].as_bool() {
+            Some(true) => &self.hacksys.engine.rom,
+            _ => &self.hacksys.engine.ram,
+        };
+        let start = address as usize;
+        let range = start.checked_add(count).map(|end| start..end);
+        let Some(words) = range.and_then(|range| memory.get(range)) else {
+            return Err(invalid_params("Range goes past the end of memory"));
+        };
+        Ok(json!({ "address": address, "words": words }))
+    }
+
+    fn set_memory(&mut self, params: &Value) -> RpcResult {
+        let address = self.address(params)? as usize;
+        let Some(words) = params["words"].as_array() else {
+            return Err(invalid_params("set_memory needs 'words'"));
+        };
+        let words = words
+            .iter()
+            .map(|w| w.as_u64().filter(|&w| w <= 0xFFFF).map(|w| w as u16))
+            .collect::<Option<Vec<u16>>>()
+            .ok_or_else(|| invalid_params("Words must be numbers 0..65535"))?;
+        let engine = &mut self.hacksys.engine;
+        let memory = match params["rom"].as_bool() {
+            Some(true) => &mut engine.rom,
+            _ => &mut engine.ram,
+        };
+        let Some(target) = memory.get_mut(address..address + words.len()) else {
+            return Err(invalid_params("Range goes past the end of memory"));
+        };
+        target.copy_from_slice(&words);
+        Ok(json!({}))
+    }
+
+    /// Run until the program stops, `max_instructions` have run or
+    /// `timeout` has passed.
+    fn run(&mut self, timeout: Duration, max_instructions: Option<u64>) -> Value {
+        let start = Instant::now();
+        let saved_limit = self.hacksys.engine.inst_limit;
+        if let Some(max) = max_instructions {
+            let limit = self.hacksys.engine.instruction_count().saturating_add(max);
+            self.hacksys.engine.inst_limit = Some(saved_limit.map_or(limit, |l| l.min(limit)));
+        }
+        let mut output = String::new();
+        let mut messages = Vec::new();
+        let stop = loop {
+            let remaining = timeout.saturating_sub(start.elapsed());
+            let stop = self
+                .hacksys
+                .execute_instructions(remaining.min(Duration::from_millis(50)));
+            output.push_str(&self.hacksys.engine.take_output());
+            messages.extend(self.hacksys.take_messages());
+            match stop {
+                Ok(StopReason::RefreshUI | StopReason::Intercept) if start.elapsed() < timeout => {}
+                Ok(StopReason::RefreshUI | StopReason::Intercept) => break Ok(None),
+                other => break other.map(Some),
+            }
+        };
+        self.hacksys.engine.inst_limit = saved_limit;
+        self.stopped(stop, output, messages)
+    }
+
+    /// Execute `count` instructions, stopping early at anything that stops
+    /// a run.
+    fn step(&mut self, count: u64) -> Value {
+        let mut output = String::new();
+        let mut messages = Vec::new();
+        let mut stop = Ok(Some(StopReason::RefreshUI));
+        for _ in 0..count {
+            stop = self.hacksys.execute_instructions(Duration::ZERO).map(Some);
+            output.push_str(&self.hacksys.engine.take_output());
+            messages.extend(self.hacksys.take_messages());
+            if !matches!(
+                stop,
+                Ok(Some(StopReason::RefreshUI | StopReason::Intercept))
+            ) {
+                break;
+            }
+        }
+        self.stopped(stop, output, messages)
+    }
+
+    /// The stop object for a run that ended with `stop`; Ok(None) means
+    /// it timed out.
+    fn stopped(
+        &self,
+        stop: Result<Option<StopReason>>,
+        output: String,
+        messages: Vec<String>,
+    ) -> Value {
+        let engine = &self.hacksys.engine;
+        let mut result = json!({
+            "pc": engine.pc,
+            "instructions": engine.instruction_count(),
+            "output": output,
+            "messages": messages,
+        });
+        match stop {
+            Ok(Some(stop)) => {
+                result["reason"] = json!(stop_reason(&stop));
+                if stop == StopReason::BreakPoint {
+                    result["breakpoint"] = json!(engine.triggered_breakpoint);
+                }
+                if let (StopReason::WatchPoint, Some(hit)) = (stop, &engine.triggered_watchpoint) {
+                    result["watchpoint"] = json!({
+                        "address": hit.address,
+                        "pc": hit.pc,
+                        "write": hit.write,
+                        "old": hit.old,
+                        "new": hit.new,
+                    });
+                }
+            }
+            Ok(None) => result["reason"] = json!("timeout"),
+            Err(e) => {
+                result["reason"] = json!("error");
+                result["error"] = json!(e.to_string());
+            }
+        }
+        result
+    }
+
+    /// Run a debugger command through `dispatch`. Commands that start the
+    /// program run it to the next stop, with the default timeout.
+    fn command(
+        &mut self,
+        dispatch: impl FnOnce(&mut Shell, &mut HackSystem) -> Result<String>,
+    ) -> RpcResult {
+        let response = match dispatch(&mut self.shell, &mut self.hacksys) {
+            Ok(response) => response,
+            Err(e) => {
+                let unknown = e
+                    .downcast_ref::<clap::Error>()
+                    .is_some_and(|e| e.kind() == clap::error::ErrorKind::InvalidSubcommand);
+                return Err(RpcError {
+                    code: if unknown { METHOD_NOT_FOUND } else { FAILED },
+                    message: e.to_string(),
+                });
+            }
+        };
+        let (output, action) = Shell::split_action(&response);
+        let mut result = json!({ "output": output });
+        match action {
+            Some("__go__" | "__run__") => {
+                result["stop"] = self.run(Duration::from_millis(DEFAULT_TIMEOUT_MS), None);
+            }
+            Some("__quit__") => self.done = true,
+            _ => {}
+        }
+        Ok(result)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HELLO: &str = "\
+    @72
+    D=A
+    @32767
+    M=D
+    @105
+    D=A
+    @32767
+    M=D
+(END)
+    @END
+    0;JMP
+";
+
+    fn call(server: &mut RpcServer, request: Value) -> Value {
+        server.request(&request.to_string()).unwrap()
+    }
+
+    #[test]
+    fn test_rpc() {
+        let dir = std::env::temp_dir().join("hackem_rpc");
+        std::fs::create_dir_all(&dir).unwrap();
+        let asm = dir.join("Hello.asm");
+        std::fs::write(&asm, HELLO).unwrap();
+
+        let mut server = RpcServer::new();
+        let input = [
+            json!({ "jsonrpc": "2.0", "id": 1, "method": "load", "params": { "program": asm } }),
+            json!({ "jsonrpc": "2.0", "method": "break", "params": ["4"] }),
+            json!({ "jsonrpc": "2.0", "id": 2, "method": "run" }),
+            json!({ "jsonrpc": "2.0", "id": 3, "method": "memory", "params": { "address": "0x10", "count": 2, "rom": true } }),
+        ]
+        .map(|r| r.to_string())
+        .join("\n");
+        let mut output = Vec::new();
+        server.serve(input.as_bytes(), &mut output).unwrap();
+        let responses: Vec<Value> = String::from_utf8(output)
+            .unwrap()
+            .lines()
+            .map(|l| serde_json::from_str(l).unwrap())
+            .collect();
+        // the notification gets no response
+        assert_eq!(responses.len(), 3);
+        assert_eq!(responses[0]["result"], json!({}));
+        let stop = &responses[1]["result"];
+        assert_eq!(stop["reason"], "breakpoint");
+        assert_eq!(stop["breakpoint"], 4);
+        assert_eq!(stop["output"], "H");
+        assert_eq!(responses[2]["result"]["words"], json!([0, 0]));
+
+        let stop = call(
+            &mut server,
+            json!({ "id": 4, "method": "command", "params": { "line": "go" } }),
+        );
+        assert_eq!(stop["result"]["stop"]["reason"], "hard_loop");
+        assert_eq!(stop["result"]["stop"]["output"], "i");
+        let regs = call(&mut server, json!({ "id": 5, "method": "registers" }));
+        assert_eq!(regs["result"]["d"], 105);
+        let unknown = call(&mut server, json!({ "id": 6, "method": "frobnicate" }));
+        assert_eq!(unknown["error"]["code"], METHOD_NOT_FOUND);
+        let bad = call(
+            &mut server,
+            json!({ "id": 7, "method": "memory", "params": { "address": 0x9000 } }),
+        );
+        assert_eq!(bad["error"]["code"], INVALID_PARAMS);
+        let huge = call(
+            &mut server,
+            json!({ "id": 8, "method": "memory", "params": { "address": 1, "count": u64::MAX } }),
+        );
+        assert_eq!(huge["error"]["code"], INVALID_PARAMS);
+        // params go to the shell as words, so quotes in them are just text
+        let trace = call(
+            &mut server,
+            json!({ "id": 9, "method": "trace", "params": ["2", "say \"hi\" --if 1"] }),
+        );
+        assert!(trace["result"]["output"].is_string());
+        let list = call(
+            &mut server,
+            json!({ "id": 10, "method": "list_breakpoints" }),
+        );
+        assert!(list["result"]["output"]
+            .as_str()
+            .unwrap()
+            .contains("log \"say \"hi\" --if 1\""));
+        assert_eq!(server.request("{").unwrap()["error"]["code"], PARSE_ERROR);
+    }
+}
@@ -1,23 +1,26 @@
-use crate::{
-    debugger::{debug_em::HackSystem, shell::Shell},
-    emulator::engine::StopReason,
-};
+use crate::debugger::shell::Shell;
+use hackem_core::{debugger::debug_em::HackSystem, emulator::engine::StopReason};
 
 use egui_console::{ConsoleBuilder, ConsoleEvent, ConsoleWindow};
 use egui_dock::{DockArea, DockState, NodeIndex, Style, TabViewer};
-use thiserror::Error;
 use web_time::Duration;
 
-use super::widgets::{code::CodeWindow, cpu::CpuWindow, data::DataWindow, screen::ScreenWindow};
+use super::widgets::{
+    callstack::CallStackWindow, code::CodeWindow, cpu::CpuWindow, data::DataWindow,
+    profile::ProfileWindow, screen::ScreenWindow, segments::SegmentsWindow,
+};
 
 #[derive(Debug, Clone, PartialEq)]
 pub enum AppTab {
     Console,
     Code,
     Cpu,
+    Segments,
     Data1,
     Data2,
     Screen,
+    CallStack,
+    Profile,
 }
 
 impl std::fmt::Display for AppTab {
@@ -26,9 +29,12 @@ impl std::fmt::Display for AppTab {
             AppTab::Console => write!(f, "Console"),
             AppTab::Code => write!(f, "Code"),
             AppTab::Cpu => write!(f, "CPU"),
+            AppTab::Segments => write!(f, "VM Segments"),
             AppTab::Data1 => write!(f, "Data 1"),
             AppTab::Data2 => write!(f, "Data 2"),
             AppTab::Screen => write!(f, "Screen"),
+            AppTab::CallStack => write!(f, "Call Stack"),
+            AppTab::Profile => write!(f, "Profile"),
         }
     }
 }
@@ -39,9 +45,12 @@ struct AppTabViewer<'a> {
     console_response: &'a mut ConsoleEvent,
     code_window: &'a mut CodeWindow,
     cpu_window: &'a mut CpuWindow,
+    segments_window: &'a mut SegmentsWindow,
     data_window1: &'a mut DataWindow,
     data_window2: &'a mut DataWindow,
     screen_window: &'a mut ScreenWindow,
+    call_stack_window: &'a mut CallStackWindow,
+    profile_window: &'a mut ProfileWindow,
     running: bool,
 }
 
@@ -61,7 +70,14 @@ impl<'a> TabViewer for AppTabViewer<'a> {
                 self.screen_window.ui(ui, self.hacksys);
             }
             // While running, freeze heavyweight views to avoid wasting CPU.
-            AppTab::Code | AppTab::Cpu | AppTab::Data1 | AppTab::Data2 if self.running => {
+            AppTab::Code
+            | AppTab::Cpu
+            | AppTab::Data1
+            | AppTab::Data2
+            | AppTab::CallStack
+            | AppTab::Profile
+                if self.running =>
+            {
                 ui.centered_and_justified(|ui| {
                     ui.label("⏸ paused while running");
                 });
@@ -72,12 +88,21 @@ impl<'a> TabViewer for AppTabViewer<'a> {
             AppTab::Cpu => {
                 self.cpu_window.ui(ui, self.hacksys);
             }
+            AppTab::Segments => {
+                self.segments_window.ui(ui, self.hacksys);
+            }
             AppTab::Data1 => {
                 self.data_window1.ui(ui, self.hacksys);
             }
             AppTab::Data2 => {
                 self.data_window2.ui(ui, self.hacksys);
             }
+            AppTab::CallStack => {
+                self.call_stack_window.ui(ui, self.hacksys);
+            }
+            AppTab::Profile => {
+                self.profile_window.ui(ui, self.hacksys);
+            }
         }
     }
 }
@@ -87,7 +112,10 @@ pub struct HackEgui {
     running: bool,
     console_window: ConsoleWindow,
     screen_window: ScreenWindow,
+    call_stack_window: CallStackWindow,
+    profile_window: ProfileWindow,
     cpu_window: CpuWindow,
+    segments_window: SegmentsWindow,
     code_window: CodeWindow,
     data_window1: DataWindow,
     data_window2: DataWindow,
@@ -97,18 +125,6 @@ pub struct HackEgui {
     output_line_buf: String,
 }
 
-#[derive(Debug, Error, PartialEq)]
-pub enum RuntimeError {
-    #[error("Invalid instruction")]
-    InvalidInstruction,
-    #[error("Invalid RAM read address {0}")]
-    InvalidReadAddress(u16),
-    #[error("Invalid RAM write address {0}")]
-    InvalidWriteAddress(u16),
-    #[error("Invalid instruction address {0}")]
-    InvalidPC(u16),
-}
-
 impl HackEgui {
     /// Called once before the first frame.
     pub fn new(cc: &eframe::CreationContext<'_>) -> Self {
@@ -116,14 +132,17 @@ impl HackEgui {
 
         // Build initial dock layout:
         //   Left 60% = Code
-        //   Right 40% top = [CPU, Data1, Data2, Screen] tabs
+        //   Right 40% top = [CPU, VM Segments, Data1, Data2, Screen, Call Stack, Profile] tabs
         //   Right 40% bottom = Console
         let mut dock_state = DockState::new(vec![AppTab::Code]);
         let surface = dock_state.main_surface_mut();
         let [_left, right] = surface.split_right(NodeIndex::root(), 0.6, vec![AppTab::Cpu]);
+        surface.push_to_focused_leaf(AppTab::Segments);
         surface.push_to_focused_leaf(AppTab::Data1);
         surface.push_to_focused_leaf(AppTab::Data2);
         surface.push_to_focused_leaf(AppTab::Screen);
+        surface.push_to_focused_leaf(AppTab::CallStack);
+        surface.push_to_focused_leaf(AppTab::Profile);
         let [_top, _bottom] = surface.split_below(right, 0.5, vec![AppTab::Console]);
 
         let mut console_window = ConsoleBuilder::new()
@@ -142,18 +161,41 @@ impl HackEgui {
             }
         }
 
-        Self {
+        let mut app = Self {
             hacksys: HackSystem::new(),
             running: false,
             console_window,
             screen_window: ScreenWindow::new(),
+            call_stack_window: CallStackWindow::new(),
+            profile_window: ProfileWindow::new(),
             cpu_window: CpuWindow::new(),
+            segments_window: SegmentsWindow::new(),
             code_window: CodeWindow::new(),
             data_window1: DataWindow::new("Data 1"),
             data_window2: DataWindow::new("Data 2"),
             shell: Shell::new(),
             dock_state,
             output_line_buf: String::new(),
+        };
+        #[cfg(not(target_arch = "wasm32"))]
+        app.run_rc_files(&cc.egui_ctx);
+        app
+    }
+
+    /// Source `~/.hackemrc`, then `.hackemrc` in the current directory
+    /// unless that is the same file.
+    #[cfg(not(target_arch = "wasm32"))]
+    fn run_rc_files(&mut self, ctx: &egui::Context) {
+        let mut rc_files: Vec<std::path::PathBuf> = dirs::home_dir()
+            .map(|home| home.join(".hackemrc"))
+            .into_iter()
+            .chain([std::path::PathBuf::from(".hackemrc")])
+            .filter_map(|rc| rc.canonicalize().ok())
+            .filter(|rc| rc.is_file())
+            .collect();
+        rc_files.dedup();
+        for rc in rc_files {
+            self.run_command(ctx, &format!("source \"{}\"", rc.display()));
         }
     }
 
@@ -176,6 +218,9 @@ impl HackEgui {
     /// not intersperse prompts between lines). Any partial line is held in
     /// `output_line_buf` until the next call or until `flush` is true.
     fn drain_output(&mut self, flush: bool) {
+        for message in self.hacksys.take_messages() {
+            self.console_window.write(&message);
+        }
         let raw = self.hacksys.engine.take_output();
         if raw.is_empty() && !flush {
             return;
@@ -191,8 +236,47 @@ impl HackEgui {
             self.console_window.write(line.trim_end_matches(['\n', '\r']));
         }
     }
+    /// Run a shell command and act on its response.
+    fn run_command(&mut self, ctx: &egui::Context, cmd: &str) {
+        if let Ok(response) = self.shell.execute_message(cmd, &mut self.hacksys) {
+            let (output, action) = Shell::split_action(&response);
+            if action.is_some() && !output.is_empty() {
+                self.console_window.write(output);
+            }
+            match action.unwrap_or_default() {
+                "__go__" => {
+                    self.running = true;
+                    // Switch to Screen tab so the display is visible while running.
+                    if let Some((surface, node, tab)) = self.dock_state.find_tab(&AppTab::Screen) {
+                        if let Some(leaf) = self.dock_state[surface][node].get_leaf_mut() {
+                            leaf.set_active_tab(tab);
+                        }
+                    }
+                }
+                "__run__" => {
+                    // run without switching tabs: step over, finish, until
+                    self.running = true;
+                }
+                "__stop__" => {
+                    self.do_break();
+                }
+                "__quit__" => {
+                    self.save_history();
+                    ctx.send_viewport_cmd(egui::ViewportCommand::Close);
+                }
+                _ => {
+                    if !output.is_empty() {
+                        self.console_write(output);
+                    } else {
+                        self.console_window.prompt();
+                    }
+                }
+            }
+        }
+    }
     fn do_break(&mut self) {
         self.running = false;
+        self.hacksys.clear_goal();
         self.drain_output(true);
         self.console_write(&format!("Break at 0x{:04X}", self.hacksys.engine.pc));
     }
@@ -209,7 +293,7 @@ impl eframe::App for HackEgui {
                 ui.menu_button("File", |ui| {
                     if ui.button("Load Binary").clicked() {
                         if let Some(path) = rfd::FileDialog::new()
-                            .add_filter("Hack binary", &["hackem", "hack", "hx"])
+                            .add_filter("Hack program", &["hackem", "hack", "hx", "asm", "vm"])
                             .pick_file()
                         {
                             let file_name = path
@@ -219,19 +303,33 @@ impl eframe::App for HackEgui {
                             match std::fs::read_to_string(&path) {
                                 Err(e) => self
                                     .console_write(&format!("Error reading {}: {}", file_name, e)),
-                                Ok(bin) => match self.hacksys.engine.load_file(&bin) {
+                                Ok(bin) => match self.hacksys.load_code(&path, &bin) {
                                     Err(e) => self.console_write(&format!("Load error: {}", e)),
                                     Ok(()) => self.console_write(&format!(
-                                        "Loaded {}  ROM: {} words  RAM: {} words",
+                                        "Loaded {}  {}",
                                         file_name,
-                                        self.hacksys.engine.rom_words_loaded,
-                                        self.hacksys.engine.ram_words_loaded
+                                        self.hacksys.program_summary()
                                     )),
                                 },
                             }
                         }
                         ui.close();
                     }
+                    if ui.button("Load VM Folder").clicked() {
+                        if let Some(dir) = rfd::FileDialog::new().pick_folder() {
+                            let loaded = hackem_core::debugger::vm::read_vm_dir(&dir)
+                                .and_then(|files| self.hacksys.load_vm(&files));
+                            match loaded {
+                                Err(e) => self.console_write(&format!("Load error: {}", e)),
+                                Ok(()) => self.console_write(&format!(
+                                    "Loaded {}  {}",
+                                    dir.display(),
+                                    self.hacksys.program_summary()
+                                )),
+                            }
+                        }
+                        ui.close();
+                    }
 
                     if ui.button("Quit").clicked() {
                         self.save_history();
@@ -263,9 +361,12 @@ impl eframe::App for HackEgui {
                 console_response: &mut console_response,
                 code_window: &mut self.code_window,
                 cpu_window: &mut self.cpu_window,
+                segments_window: &mut self.segments_window,
                 data_window1: &mut self.data_window1,
                 data_window2: &mut self.data_window2,
                 screen_window: &mut self.screen_window,
+                call_stack_window: &mut self.call_stack_window,
+                profile_window: &mut self.profile_window,
                 running: self.running,
             };
             DockArea::new(&mut self.dock_state)
@@ -274,42 +375,17 @@ impl eframe::App for HackEgui {
         });
 
         if let ConsoleEvent::Command(cmd) = console_response {
-            if let Ok(response) = self.shell.execute_message(&cmd, &mut self.hacksys) {
-                match response.as_str() {
-                    "__go__" => {
-                        self.running = true;
-                        // Switch to Screen tab so the display is visible while running.
-                        if let Some((surface, node, tab)) =
-                            self.dock_state.find_tab(&AppTab::Screen)
-                        {
-                            if let Some(leaf) = self.dock_state[surface][node].get_leaf_mut() {
-                                leaf.set_active_tab(tab);
-                            }
-                        }
-                    }
-                    "__stop__" => {
-                        self.do_break();
-                    }
-                    "__quit__" => {
-                        self.save_history();
-                        ctx.send_viewport_cmd(egui::ViewportCommand::Close);
-                    }
-                    _ => {
-                        if !response.is_empty() {
-                            self.console_write(&response);
-                        } else {
-                            self.console_window.prompt();
-                        }
-                    }
-                }
-            }
+            self.run_command(ctx, &cmd);
+        }
+        if let Some(cmd) = self.code_window.take_command() {
+            self.run_command(ctx, &cmd);
+        }
+        if let Some(cmd) = self.profile_window.take_command() {
+            self.run_command(ctx, &cmd);
         }
 
         if self.running {
-            let stop = self
-                .hacksys
-                .engine
-                .execute_instructions(Duration::from_millis(50));
+            let stop = self.hacksys.execute_instructions(Duration::from_millis(50));
             match stop {
                 Ok(reason) => match reason {
                     StopReason::SysHalt => {
@@ -336,11 +412,33 @@ impl eframe::App for HackEgui {
                     StopReason::WatchPoint => {
                         self.running = false;
                         self.drain_output(true);
-                        let addr = self.hacksys.engine.triggered_watchpoint.unwrap_or(0);
-                        self.console_write(&format!("Watchpoint hit at 0x{:04X}", addr));
+                        if let Some(hit) = &self.hacksys.engine.triggered_watchpoint {
+                            let msg = hit.to_string();
+                            self.console_write(&msg);
+                        }
+                        ctx.request_repaint();
+                    }
+                    StopReason::RunTo => {
+                        self.running = false;
+                        self.drain_output(true);
+                        let pc = self.hacksys.engine.pc;
+                        let msg = match self.hacksys.source_location(pc) {
+                            Some(loc) => format!("Stopped at 0x{:04X}  {}", pc, loc),
+                            None => format!("Stopped at 0x{:04X}", pc),
+                        };
+                        self.console_write(&msg);
+                        ctx.request_repaint();
+                    }
+                    StopReason::InstructionLimit => {
+                        self.running = false;
+                        self.drain_output(true);
+                        self.console_write(&format!(
+                            "Instruction limit reached at 0x{:04X}",
+                            self.hacksys.engine.pc
+                        ));
                         ctx.request_repaint();
                     }
-                    StopReason::RefreshUI => {
+                    StopReason::RefreshUI | StopReason::Intercept => {
                         self.drain_output(false);
                         ctx.request_repaint();
                     }
@@ -0,0 +1,44 @@
+use egui::{RichText, ScrollArea};
+
+use hackem_core::debugger::debug_em::HackSystem;
+
+pub struct CallStackWindow {}
+
+impl CallStackWindow {
+    pub fn new() -> Self {
+        Self {}
+    }
+
+    pub fn ui(&self, ui: &mut egui::Ui, hacksys: &mut HackSystem) {
+        let frames = hacksys.backtrace();
+        let selected = hacksys.selected_frame_index().min(frames.len() - 1);
+        let addr_color = ui.visuals().weak_text_color();
+
+        ScrollArea::vertical().id_salt("call_stack").show(ui, |ui| {
+            egui::Grid::new("call_stack_grid")
+                .num_columns(4)
+                .spacing([8.0, 2.0])
+                .striped(true)
+                .show(ui, |ui| {
+                    for (i, frame) in frames.iter().enumerate() {
+                        ui.label(RichText::new(format!("#{}", i)).color(addr_color));
+                        let name = frame.function.as_deref().unwrap_or("??");
+                        if ui.selectable_label(i == selected, name).clicked() {
+                            // the frame exists: it came from this backtrace
+                            let _ = hacksys.select_frame(i);
+                        }
+                        ui.label(
+                            RichText::new(format!("{:04X}", frame.pc))
+                                .monospace()
+                                .color(addr_color),
+                        );
+                        ui.label(
+                            RichText::new(format!("LCL={:04X} ARG={:04X}", frame.lcl, frame.arg))
+                                .monospace(),
+                        );
+                        ui.end_row();
+                    }
+                });
+        });
+    }
+}
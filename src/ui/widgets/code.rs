@@ -1,11 +1,17 @@
 use egui::{Color32, Id, RichText, ScrollArea};
 
-use crate::debugger::debug_em::HackSystem;
+use hackem_core::debugger::debug_em::HackSystem;
 
 pub struct CodeWindow {
     id: Id,
     /// How many instructions to show
     display_count: u16,
+    /// Shell command requested by a toolbar button, run by the app.
+    command: Option<String>,
+    /// Instruction being edited in place: address and text.
+    editing: Option<(u16, String)>,
+    /// Why the last edit could not be assembled.
+    edit_error: Option<String>,
 }
 
 impl CodeWindow {
@@ -13,9 +19,17 @@ impl CodeWindow {
         Self {
             id: Id::new("code_window"),
             display_count: 32,
+            command: None,
+            editing: None,
+            edit_error: None,
         }
     }
 
+    /// Take the shell command requested since the last frame, if any.
+    pub fn take_command(&mut self) -> Option<String> {
+        self.command.take()
+    }
+
     pub fn ui(&mut self, ui: &mut egui::Ui, hacksys: &mut HackSystem) {
         let pc = hacksys.engine.pc;
         let dark = ui.visuals().dark_mode;
@@ -33,7 +47,21 @@ impl CodeWindow {
         let half = self.display_count / 2;
         let start = pc.saturating_sub(half);
 
-        let rows = hacksys.engine.disassemble_range(start, self.display_count);
+        // VM mode lists VM commands, which have no machine word and can't be patched
+        let rows: Vec<(u16, Option<u16>, String)> = match &hacksys.vm {
+            Some(vm) => vm
+                .listing(start, self.display_count)
+                .into_iter()
+                .map(|(addr, text)| (addr, None, text))
+                .collect(),
+            None => hacksys
+                .engine
+                .disassemble_range(start, self.display_count)
+                .into_iter()
+                .map(|(addr, raw, mnemonic)| (addr, Some(raw), mnemonic))
+                .collect(),
+        };
+        let editable = !hacksys.vm_mode();
 
         ui.horizontal(|ui| {
             ui.label("Lines:");
@@ -42,7 +70,40 @@ impl CodeWindow {
                     .range(8..=128)
                     .speed(1.0),
             );
+            ui.separator();
+            if ui
+                .button("⤼ Step over")
+                .on_hover_text("Step one instruction, running a VM call until it returns")
+                .clicked()
+            {
+                self.command = Some("step_over".to_string());
+            }
+            if ui
+                .button("⤴ Step out")
+                .on_hover_text("Run until the current VM function returns")
+                .clicked()
+            {
+                self.command = Some("finish".to_string());
+            }
+            let can_reverse = !hacksys.engine.history.is_empty();
+            if ui
+                .add_enabled(can_reverse, egui::Button::new("◀ Step back"))
+                .on_hover_text("Undo the last instruction")
+                .clicked()
+            {
+                self.command = Some("reverse_step".to_string());
+            }
+            if ui
+                .add_enabled(can_reverse, egui::Button::new("⏪ Reverse"))
+                .on_hover_text("Run backwards to the previous breakpoint or watchpoint")
+                .clicked()
+            {
+                self.command = Some("reverse_continue".to_string());
+            }
         });
+        if let Some(err) = &self.edit_error {
+            ui.colored_label(Color32::RED, err);
+        }
         ui.separator();
 
         ScrollArea::vertical().id_salt(self.id).show(ui, |ui| {
@@ -53,17 +114,18 @@ impl CodeWindow {
                 .show(ui, |ui| {
                     for (addr, raw, mnemonic) in &rows {
                         let at_pc = *addr == pc;
-                        let has_bp = hacksys.engine.break_points.contains_key(addr);
+                        let bp_enabled = hacksys.engine.break_points.get(addr).map(|bp| bp.enabled);
+                        let has_bp = bp_enabled.is_some();
 
                         // PC indicator
                         let pc_label = if at_pc { "▶" } else { " " };
                         ui.label(RichText::new(pc_label).color(pc_accent));
 
                         // Breakpoint indicator — click to toggle
-                        let bp_text = if has_bp {
-                            RichText::new("●").color(Color32::RED)
-                        } else {
-                            RichText::new("○").color(ui.visuals().weak_text_color())
+                        let bp_text = match bp_enabled {
+                            Some(true) => RichText::new("●").color(Color32::RED),
+                            Some(false) => RichText::new("●").color(ui.visuals().weak_text_color()),
+                            None => RichText::new("○").color(ui.visuals().weak_text_color()),
                         };
                         if ui.small_button(bp_text).clicked() {
                             if has_bp {
@@ -77,18 +139,76 @@ impl CodeWindow {
                         let addr_text = RichText::new(format!("{:04X}", addr))
                             .monospace()
                             .color(if at_pc { pc_accent } else { addr_color });
-                        ui.label(addr_text);
+                        let addr_resp =
+                            ui.add(egui::Label::new(addr_text).sense(egui::Sense::click()));
 
                         // Raw hex
-                        ui.label(
-                            RichText::new(format!("{:04X}", raw))
-                                .monospace()
-                                .color(raw_color),
-                        );
-
-                        // Mnemonic
-                        let mn_color = if at_pc { pc_accent } else { normal_color };
-                        ui.label(RichText::new(mnemonic).monospace().color(mn_color));
+                        let raw_text = raw.map_or(String::new(), |raw| format!("{:04X}", raw));
+                        ui.label(RichText::new(raw_text).monospace().color(raw_color));
+
+                        // Mnemonic — double-click to assemble a replacement
+                        // instructions that have not run yet are greyed out (coverage);
+                        // the engine records VM commands at their address & 0x7fff
+                        let mn_color = if at_pc {
+                            pc_accent
+                        } else if hacksys.engine.executed[*addr as usize & 0x7fff] {
+                            normal_color
+                        } else {
+                            raw_color
+                        };
+                        let mn_resp = match &mut self.editing {
+                            Some((edit_addr, text)) if edit_addr == addr => {
+                                let resp = ui.add(
+                                    egui::TextEdit::singleline(text)
+                                        .font(egui::TextStyle::Monospace)
+                                        .desired_width(120.0),
+                                );
+                                resp.request_focus();
+                                if resp.lost_focus() {
+                                    // assemble directly: the text may hold quotes
+                                    // that would not survive a shell command line
+                                    let result = ui
+                                        .input(|i| i.key_pressed(egui::Key::Enter))
+                                        .then(|| hacksys.assemble_at(*addr, text.trim()));
+                                    match result {
+                                        Some(Err(err)) => self.edit_error = Some(err.to_string()),
+                                        _ => {
+                                            self.editing = None;
+                                            self.edit_error = None;
+                                        }
+                                    }
+                                }
+                                resp
+                            }
+                            _ => {
+                                let resp = ui.add(
+                                    egui::Label::new(
+                                        RichText::new(mnemonic).monospace().color(mn_color),
+                                    )
+                                    .sense(egui::Sense::click()),
+                                );
+                                if editable {
+                                    let resp = resp.on_hover_text("Double-click to edit");
+                                    if resp.double_clicked() {
+                                        self.editing = Some((*addr, mnemonic.clone()));
+                                    }
+                                    resp
+                                } else {
+                                    resp
+                                }
+                            }
+                        };
+
+                        addr_resp.union(mn_resp).context_menu(|ui| {
+                            if ui.button("Run to cursor").clicked() {
+                                self.command = Some(format!("until 0x{:04X}", addr));
+                                ui.close();
+                            }
+                            if editable && ui.button("Edit instruction").clicked() {
+                                self.editing = Some((*addr, mnemonic.clone()));
+                                ui.close();
+                            }
+                        });
 
                         ui.end_row();
                     }
@@ -4,7 +4,7 @@ use egui::{
     text::CCursorRange, Align, Context, Event, EventFilter, Id, Key, Modifiers, TextEdit, Ui,
 };
 
-use crate::debugger::debug_em::HackSystem;
+use hackem_core::debugger::debug_em::HackSystem;
 pub enum ConsoleEvent {
     Command(String),
     CtrlC,
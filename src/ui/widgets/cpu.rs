@@ -1,4 +1,4 @@
-use crate::debugger::debug_em::HackSystem;
+use hackem_core::debugger::debug_em::HackSystem;
 
 pub struct CpuWindow {}
 
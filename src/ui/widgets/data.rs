@@ -1,11 +1,13 @@
 use egui::{RichText, ScrollArea};
 
-use crate::debugger::debug_em::HackSystem;
+use hackem_core::debugger::debug_em::HackSystem;
 
 pub struct DataWindow {
     title: String,
     start_addr_text: String,
     start_addr: u16,
+    /// `=expr` typed as the address, re-evaluated every frame.
+    start_expr: Option<String>,
     row_count: u16,
 }
 
@@ -15,6 +17,7 @@ impl DataWindow {
             title: title.to_string(),
             start_addr_text: "0".to_string(),
             start_addr: 0,
+            start_expr: None,
             row_count: 16,
         }
     }
@@ -25,20 +28,26 @@ impl DataWindow {
 
         ui.horizontal(|ui| {
             ui.label("Address:");
-            let resp = ui.add(
-                egui::TextEdit::singleline(&mut self.start_addr_text)
-                    .desired_width(60.0)
-                    .hint_text("0x0000"),
-            );
+            let resp = ui
+                .add(
+                    egui::TextEdit::singleline(&mut self.start_addr_text)
+                        .desired_width(60.0)
+                        .hint_text("0x0000"),
+                )
+                .on_hover_text("Address, symbol, or =expr such as =.lcl");
             if resp.lost_focus() {
                 let s = self.start_addr_text.trim();
-                let parsed = if let Some(hex) = s.strip_prefix("0x").or_else(|| s.strip_prefix("$"))
+                if let Some(expr) = s.strip_prefix('=') {
+                    self.start_expr = Some(expr.to_string());
+                } else if let Some(Ok((addr, _))) = (!s.is_empty()).then(|| hacksys.convert_addr(s))
                 {
-                    u16::from_str_radix(hex, 16).ok()
-                } else {
-                    s.parse::<u16>().ok()
-                };
-                if let Some(addr) = parsed {
+                    self.start_expr = None;
+                    self.start_addr = addr;
+                }
+            }
+            // an expression such as =.lcl follows the registers and selected frame
+            if let Some(expr) = &self.start_expr {
+                if let Ok(addr) = hacksys.evaluate(expr) {
                     self.start_addr = addr;
                 }
             }
@@ -0,0 +1,105 @@
+use egui::{RichText, ScrollArea};
+
+use hackem_core::debugger::debug_em::HackSystem;
+
+pub struct ProfileWindow {
+    /// Shell command requested by a toolbar button, run by the app.
+    command: Option<String>,
+}
+
+impl ProfileWindow {
+    pub fn new() -> Self {
+        Self { command: None }
+    }
+
+    /// Take the shell command requested since the last frame, if any.
+    pub fn take_command(&mut self) -> Option<String> {
+        self.command.take()
+    }
+
+    pub fn ui(&mut self, ui: &mut egui::Ui, hacksys: &HackSystem) {
+        let addr_color = ui.visuals().weak_text_color();
+        ui.horizontal(|ui| {
+            if hacksys.profiling() {
+                if ui.button("⏹ Stop").on_hover_text("Stop counting").clicked() {
+                    self.command = Some("profile off".to_string());
+                }
+            } else if ui
+                .button("⏺ Record")
+                .on_hover_text("Count executions while the program runs")
+                .clicked()
+            {
+                self.command = Some("profile on".to_string());
+            }
+            if ui.button("Clear").clicked() {
+                self.command = Some("profile clear".to_string());
+            }
+            #[cfg(not(target_arch = "wasm32"))]
+            if ui
+                .button("Save…")
+                .on_hover_text("Export folded stacks for flame graph tools")
+                .clicked()
+            {
+                if let Some(path) = rfd::FileDialog::new()
+                    .set_file_name("hackem.folded")
+                    .save_file()
+                {
+                    self.command = Some(format!("profile save \"{}\"", path.display()));
+                }
+            }
+        });
+        ui.separator();
+
+        let report = hacksys.profile(20);
+        let percent = |count: u64| count as f64 * 100.0 / report.total.max(1) as f64;
+        ui.label(format!("{} instructions", report.total));
+
+        ScrollArea::vertical().id_salt("profile").show(ui, |ui| {
+            egui::Grid::new("profile_functions")
+                .num_columns(4)
+                .spacing([12.0, 2.0])
+                .striped(true)
+                .show(ui, |ui| {
+                    ui.label(RichText::new("Function").strong());
+                    ui.label(RichText::new("Self").strong());
+                    ui.label(RichText::new("Self %").strong());
+                    ui.label(RichText::new("Total %").strong());
+                    ui.end_row();
+                    for func in &report.functions {
+                        ui.label(&func.name);
+                        ui.label(RichText::new(func.self_count.to_string()).monospace());
+                        ui.label(
+                            RichText::new(format!("{:.1}", percent(func.self_count))).monospace(),
+                        );
+                        ui.label(
+                            RichText::new(format!("{:.1}", percent(func.total_count)))
+                                .monospace()
+                                .color(addr_color),
+                        )
+                        .on_hover_text(format!("{} including calls", func.total_count));
+                        ui.end_row();
+                    }
+                });
+            if !report.hotspots.is_empty() {
+                ui.separator();
+                ui.label(RichText::new("Hottest addresses").strong());
+                egui::Grid::new("profile_hotspots")
+                    .num_columns(3)
+                    .spacing([12.0, 2.0])
+                    .striped(true)
+                    .show(ui, |ui| {
+                        for (addr, count, name) in &report.hotspots {
+                            ui.label(
+                                RichText::new(format!("{:04X}", addr))
+                                    .monospace()
+                                    .color(addr_color),
+                            );
+                            ui.label(RichText::new(count.to_string()).monospace());
+                            ui.label(name);
+                            ui.end_row();
+                        }
+                    });
+            }
+        });
+    }
+}
@@ -1,6 +1,7 @@
-use egui::{ColorImage, Id, Sense, TextureHandle, TextureOptions, Ui, Vec2};
+use egui::{Color32, ColorImage, Id, Sense, TextureHandle, TextureOptions, Ui, Vec2};
 
-use crate::{debugger::debug_em::HackSystem, ui::key_lookup::lookup_key};
+use crate::ui::key_lookup::lookup_key;
+use hackem_core::debugger::debug_em::HackSystem;
 const SCREEN_WIDTH: usize = 512;
 const SCREEN_HEIGHT: usize = 256;
 
@@ -8,12 +9,15 @@ pub(crate) static mut CURRENT_KEY: u8 = 0;
 pub struct ScreenWindow {
     paint_id: Id,
     texture: Option<TextureHandle>,
+    /// Pixels (512×256) rebuilt from the engine's screen buffer when it changes.
+    pixels: Vec<Color32>,
 }
 impl ScreenWindow {
     pub fn new() -> Self {
         Self {
             paint_id: Id::new("Screen"),
             texture: None,
+            pixels: vec![Color32::WHITE; SCREEN_WIDTH * SCREEN_HEIGHT],
         }
     }
 
@@ -36,13 +40,21 @@ impl ScreenWindow {
                 }
             });
         }
+        hacksys.engine.set_key(unsafe { CURRENT_KEY } as u16);
 
         // Upload texture to GPU only when the engine has written new screen pixels.
-        if hacksys.engine.screen_dirty || self.texture.is_none() {
-            let image = ColorImage::new(
-                [SCREEN_WIDTH, SCREEN_HEIGHT],
-                hacksys.engine.screen_pixels.clone(),
-            );
+        if hacksys.engine.screen_dirty() || self.texture.is_none() {
+            for (word_index, word) in hacksys.engine.screen().iter().enumerate() {
+                let pixel_base = word_index * 16;
+                for i in 0..16usize {
+                    self.pixels[pixel_base + i] = if word & (1u16 << i) != 0 {
+                        Color32::BLACK
+                    } else {
+                        Color32::WHITE
+                    };
+                }
+            }
+            let image = ColorImage::new([SCREEN_WIDTH, SCREEN_HEIGHT], self.pixels.clone());
             let opts = TextureOptions::NEAREST;
             match &mut self.texture {
                 Some(tex) => tex.set(image, opts),
@@ -50,7 +62,7 @@ impl ScreenWindow {
                     self.texture = Some(ctx.load_texture("hack_screen", image, opts));
                 }
             }
-            hacksys.engine.screen_dirty = false;
+            hacksys.engine.set_screen_dirty(false);
         }
 
         if let Some(tex) = &self.texture {
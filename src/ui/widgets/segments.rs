@@ -0,0 +1,66 @@
+use egui::{CollapsingHeader, RichText, ScrollArea};
+
+use hackem_core::debugger::{debug_em::HackSystem, segments::Segment};
+
+pub struct SegmentsWindow {}
+
+impl SegmentsWindow {
+    pub fn new() -> Self {
+        Self {}
+    }
+
+    pub fn ui(&self, ui: &mut egui::Ui, hacksys: &HackSystem) {
+        let addr_color = ui.visuals().weak_text_color();
+        let frame = hacksys.selected_frame();
+        ui.label(format!(
+            "Frame #{}  {}",
+            hacksys.selected_frame_index(),
+            frame.function.as_deref().unwrap_or("??")
+        ));
+        ui.separator();
+
+        ScrollArea::vertical()
+            .id_salt("vm_segments")
+            .show(ui, |ui| {
+                for view in hacksys.vm_segments() {
+                    let open = matches!(view.segment, Segment::Local | Segment::Argument);
+                    CollapsingHeader::new(format!(
+                        "{} @ {:04X} ({})",
+                        view.segment,
+                        view.base,
+                        view.slots.len()
+                    ))
+                    .id_salt(view.segment.to_string())
+                    .default_open(open)
+                    .show(ui, |ui| {
+                        egui::Grid::new(format!("{}_grid", view.segment))
+                            .num_columns(4)
+                            .spacing([8.0, 2.0])
+                            .striped(true)
+                            .show(ui, |ui| {
+                                for slot in &view.slots {
+                                    ui.label(
+                                        RichText::new(format!("{} {}", view.segment, slot.index))
+                                            .color(addr_color),
+                                    );
+                                    ui.label(
+                                        RichText::new(format!("{:04X}", slot.address))
+                                            .monospace()
+                                            .color(addr_color),
+                                    );
+                                    ui.label(
+                                        RichText::new(format!("{:04X}", slot.value)).monospace(),
+                                    )
+                                    .on_hover_text(format!(
+                                        "{} ({})",
+                                        slot.value, slot.value as i16
+                                    ));
+                                    ui.label(slot.name.as_deref().unwrap_or(""));
+                                    ui.end_row();
+                                }
+                            });
+                    });
+                }
+            });
+    }
+}